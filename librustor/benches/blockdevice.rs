@@ -0,0 +1,100 @@
+use std::path::PathBuf;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+use librustor::blockstore::{BasicBlockDevice, UringBlockDevice, UringOptions};
+use librustor::{BlockDevice, BS4K};
+
+const BLOCKS: u64 = 1 << 12;
+
+fn path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("rustor-bench-{}-{}.bin", name, std::process::id()))
+}
+
+/// (lba, span) extents of `span` blocks spread across the device
+fn extents(span: u64) -> Vec<(u64, u64)> {
+    (0..8).map(|i| (i * BLOCKS / 8, span)).collect()
+}
+
+fn bench_write(c: &mut Criterion) {
+    let mut group = c.benchmark_group("write_extents");
+    for span in [1u64, 16, 256].iter() {
+        let extents = extents(*span);
+        let data = vec![0xa5u8; *span as usize * BS4K];
+        group.throughput(Throughput::Bytes(8 * data.len() as u64));
+
+        let mut basic = BasicBlockDevice::new(BLOCKS * BS4K as u64, path("basic")).unwrap();
+        group.bench_with_input(BenchmarkId::new("BasicBlockDevice", span), &extents, |b, extents| {
+            b.iter(|| {
+                for (lba, span) in extents.iter() {
+                    for (n, block) in data.chunks(BS4K).enumerate().take(*span as usize) {
+                        basic.write_block(lba + n as u64, block).unwrap();
+                    }
+                }
+            })
+        });
+
+        group.bench_with_input(BenchmarkId::new("BasicBlockDevice/pwrite", span), &extents, |b, extents| {
+            b.iter(|| {
+                for (lba, _) in extents.iter() {
+                    basic.write_blocks(*lba, &data).unwrap();
+                }
+            })
+        });
+
+        for depth in [1u32, 32].iter() {
+            let options = UringOptions { queue_depth: *depth, direct: false };
+            let mut uring = UringBlockDevice::with_options(BLOCKS * BS4K as u64, path("uring"), options).unwrap();
+            let writes: Vec<(u64, &[u8])> = extents.iter().map(|(lba, _)| (*lba, &data[..])).collect();
+            group.bench_with_input(BenchmarkId::new(format!("UringBlockDevice/qd{}", depth), span), &writes, |b, writes| {
+                b.iter(|| uring.write_extents(writes).unwrap())
+            });
+        }
+    }
+    group.finish();
+}
+
+fn bench_read(c: &mut Criterion) {
+    let mut group = c.benchmark_group("read_extents");
+    for span in [1u64, 16, 256].iter() {
+        let extents = extents(*span);
+        group.throughput(Throughput::Bytes(8 * span * BS4K as u64));
+
+        let mut basic = BasicBlockDevice::new(BLOCKS * BS4K as u64, path("basic")).unwrap();
+        group.bench_with_input(BenchmarkId::new("BasicBlockDevice", span), &extents, |b, extents| {
+            let mut block = [0; BS4K];
+            b.iter(|| {
+                for (lba, span) in extents.iter() {
+                    for n in 0..*span {
+                        basic.read_block(lba + n, &mut block).unwrap();
+                    }
+                }
+            })
+        });
+
+        group.bench_with_input(BenchmarkId::new("BasicBlockDevice/pread", span), &extents, |b, extents| {
+            let mut data = vec![0; *span as usize * BS4K];
+            b.iter(|| {
+                for (lba, _) in extents.iter() {
+                    basic.read_blocks(*lba, &mut data).unwrap();
+                }
+            })
+        });
+
+        let mut uring = UringBlockDevice::new(BLOCKS * BS4K as u64, path("uring")).unwrap();
+        group.bench_with_input(BenchmarkId::new("UringBlockDevice/qd32", span), &extents, |b, extents| {
+            let mut data = Vec::with_capacity(8 * *span as usize * BS4K);
+            b.iter(|| {
+                data.clear();
+                uring.read_extents(extents, &mut data).unwrap()
+            })
+        });
+    }
+    group.finish();
+
+    let _ = std::fs::remove_file(path("basic"));
+    let _ = std::fs::remove_file(path("uring"));
+}
+
+criterion_group!(benches, bench_write, bench_read);
+criterion_main!(benches);
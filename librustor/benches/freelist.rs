@@ -0,0 +1,51 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+
+use librustor::freelist::{FreeList, VecFreeList, BitmapFreelist, BTreeFreeList};
+use librustor::BS4K;
+
+const CAPACITY: u64 = 1 << 16;
+
+/// deterministic mix of allocation sizes in blocks
+fn sizes(n: usize) -> Vec<u64> {
+    let mut x: u64 = 0x2545_f491;
+    (0..n).map(|_| {
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        1 + x % 64
+    }).collect()
+}
+
+/// allocate every size, then release every other allocation to leave the list fragmented
+fn churn<F: FreeList>(list: &mut F, sizes: &[u64], bytes: bool) {
+    let mut manifests = Vec::with_capacity(sizes.len());
+    for s in sizes {
+        let request = if bytes { s * BS4K as u64 } else { *s };
+        manifests.push(list.allocate(request).unwrap());
+    }
+    for m in manifests.iter().step_by(2) {
+        list.release(m).unwrap();
+    }
+}
+
+fn bench_freelists(c: &mut Criterion) {
+    let mut group = c.benchmark_group("allocate_release");
+    for n in [100, 1000].iter() {
+        let sizes = sizes(*n);
+
+        group.bench_with_input(BenchmarkId::new("VecFreeList", n), &sizes, |b, sizes| {
+            b.iter(|| churn(&mut VecFreeList::new(CAPACITY), sizes, true))
+        });
+        group.bench_with_input(BenchmarkId::new("BitmapFreelist", n), &sizes, |b, sizes| {
+            // BitmapFreelist::allocate takes a span in blocks rather than bytes
+            b.iter(|| churn(&mut BitmapFreelist::new(CAPACITY as usize), sizes, false))
+        });
+        group.bench_with_input(BenchmarkId::new("BTreeFreeList", n), &sizes, |b, sizes| {
+            b.iter(|| churn(&mut BTreeFreeList::new(CAPACITY), sizes, true))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_freelists);
+criterion_main!(benches);
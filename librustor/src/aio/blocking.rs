@@ -0,0 +1,209 @@
+use std::sync::{Arc, Mutex};
+
+use uuid::Uuid;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use tokio::task;
+
+use crate::{BS4K, RResult, RustorError};
+use crate::blockstore::{BlockDevice, BlockStore};
+use crate::keystore::KeyStore;
+use crate::object::ObjKey;
+use crate::objstore::{ObjectStore, ObjectID, SharedObjectStore};
+use super::*;
+
+#[allow(unused_imports)]
+use log::{trace, debug, info, warn, error};
+
+/// Adapts a synchronous BlockDevice, BlockStore, KeyStore or ObjectStore to its async trait.
+///
+/// Every call runs on tokio's blocking thread pool with exclusive access to the wrapped value,
+/// so calls through one adapter are serialized just as they would be through `&mut`. Must be
+/// used from within a tokio runtime.
+pub struct Blocking<T> {
+    inner: Arc<Mutex<T>>,
+}
+
+impl<T> Clone for Blocking<T> {
+    fn clone(&self) -> Self {
+        Self { inner: Arc::clone(&self.inner) }
+    }
+}
+
+impl<T: Send + 'static> Blocking<T> {
+    pub fn new(inner: T) -> Self {
+        Self { inner: Arc::new(Mutex::new(inner)) }
+    }
+
+    fn run<R, F>(&self, f: F) -> BoxFuture<'static, R>
+    where
+        R: Send + 'static,
+        F: FnOnce(&mut T) -> RResult<R> + Send + 'static,
+    {
+        let inner = Arc::clone(&self.inner);
+        Box::pin(async move {
+            task::spawn_blocking(move || {
+                let mut inner = inner.lock().map_err(|_| RustorError::Poisoned("blocking adapter"))?;
+                f(&mut inner)
+            }).await?
+        })
+    }
+}
+
+impl<D: BlockDevice + Send + 'static> AsyncBlockDevice for Blocking<D> {
+    fn write_block(&self, lba: u64, data: Vec<u8>) -> BoxFuture<'_, ()> {
+        self.run(move |dev| dev.write_block(lba, &data))
+    }
+
+    fn read_block(&self, lba: u64) -> BoxFuture<'_, Box<[u8; BS4K]>> {
+        self.run(move |dev| {
+            let mut block = Box::new([0; BS4K]);
+            dev.read_block(lba, &mut block)?;
+            Ok(block)
+        })
+    }
+}
+
+impl<S: BlockStore + Send + 'static> AsyncBlockStore for Blocking<S> {
+    fn write(&self, data: Vec<u8>, key: ObjKey) -> BoxFuture<'_, ()> {
+        self.run(move |store| store.write(&data, &key))
+    }
+
+    fn read(&self, key: ObjKey) -> BoxFuture<'_, Vec<u8>> {
+        self.run(move |store| {
+            let mut data = Vec::with_capacity(key.size as usize);
+            store.read(&mut data, &key)?;
+            Ok(data)
+        })
+    }
+}
+
+impl<K, T> AsyncKeyStore<T> for Blocking<K>
+where
+    K: KeyStore<T> + Send + 'static,
+    T: Serialize + DeserializeOwned + Clone + Send + 'static,
+{
+    fn set(&self, uuid: Uuid, object: T) -> BoxFuture<'_, Option<T>> {
+        self.run(move |keys| keys.set(uuid, object))
+    }
+
+    fn get(&self, uuid: Uuid) -> BoxFuture<'_, Option<T>> {
+        self.run(move |keys| Ok(keys.get(&uuid)?.cloned()))
+    }
+
+    fn delete(&self, uuid: Uuid) -> BoxFuture<'_, Option<T>> {
+        self.run(move |keys| keys.delete(&uuid))
+    }
+}
+
+impl<S: ObjectStore + Send + 'static> AsyncObjectStore for Blocking<S> {
+    fn put(&self, data: Vec<u8>) -> BoxFuture<'_, ObjectID> {
+        self.run(move |store| store.put(&data))
+    }
+
+    fn get(&self, uuid: ObjectID) -> BoxFuture<'_, Option<Vec<u8>>> {
+        self.run(move |store| store.get(uuid))
+    }
+
+    fn delete(&self, uuid: ObjectID) -> BoxFuture<'_, Option<ObjectID>> {
+        self.run(move |store| store.delete(uuid))
+    }
+}
+
+/// A SharedObjectStore does its own locking, so calls through an `Arc` of one run on the blocking
+/// pool in parallel instead of being serialized by a `Blocking` adapter
+fn shared<R, F>(store: &Arc<SharedObjectStore>, f: F) -> BoxFuture<'static, R>
+where
+    R: Send + 'static,
+    F: FnOnce(&SharedObjectStore) -> RResult<R> + Send + 'static,
+{
+    let store = Arc::clone(store);
+    Box::pin(async move {
+        task::spawn_blocking(move || f(&store)).await?
+    })
+}
+
+impl AsyncObjectStore for Arc<SharedObjectStore> {
+    fn put(&self, data: Vec<u8>) -> BoxFuture<'_, ObjectID> {
+        shared(self, move |store| store.put(&data))
+    }
+
+    fn get(&self, uuid: ObjectID) -> BoxFuture<'_, Option<Vec<u8>>> {
+        shared(self, move |store| store.get(uuid))
+    }
+
+    fn delete(&self, uuid: ObjectID) -> BoxFuture<'_, Option<ObjectID>> {
+        shared(self, move |store| store.delete(uuid))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use tokio::runtime::{Builder, Runtime};
+
+    use crate::blockstore::{BasicBlockDevice, SingleDeviceBlockStore};
+    use crate::freelist::VecFreeList;
+    use crate::keygen::KeyGen;
+    use crate::keystore::JsonKeystore;
+
+    fn runtime() -> Runtime {
+        Builder::new_current_thread().build().unwrap()
+    }
+
+    fn path(test: &str, ext: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("rustor-aio-{}-{}.{}", test, std::process::id(), ext))
+    }
+
+    fn cleanup(test: &str) {
+        let _ = std::fs::remove_file(path(test, "bin"));
+        let _ = std::fs::remove_file(path(test, "json"));
+    }
+
+    #[test]
+    fn test_block_device() {
+        let dev = Blocking::new(BasicBlockDevice::new(4 * BS4K as u64, path("dev", "bin")).unwrap());
+        let dyn_dev: &dyn AsyncBlockDevice = &dev;
+
+        runtime().block_on(async {
+            dyn_dev.write_block(2, vec![7; BS4K]).await.unwrap();
+            assert_eq!(dyn_dev.read_block(2).await.unwrap()[..], [7; BS4K][..]);
+        });
+        cleanup("dev");
+    }
+
+    #[test]
+    fn test_key_store() {
+        let _ = std::fs::remove_file(path("keys", "json"));
+        let keys = Blocking::new(JsonKeystore::<ObjKey>::new(path("keys", "json")));
+        let key = ObjKey { uuid: Uuid::new_v4(), size: 3, ..Default::default() };
+
+        runtime().block_on(async {
+            assert!(keys.set(key.uuid, key.clone()).await.unwrap().is_none());
+            assert_eq!(keys.get(key.uuid).await.unwrap().unwrap().size, 3);
+            assert!(keys.delete(key.uuid).await.unwrap().is_some());
+            assert!(keys.get(key.uuid).await.unwrap().is_none());
+        });
+        cleanup("keys");
+    }
+
+    #[test]
+    fn test_shared_object_store() {
+        let _ = std::fs::remove_file(path("objs", "json"));
+        let store: Arc<SharedObjectStore> = Arc::new(SharedObjectStore::new(
+            SingleDeviceBlockStore::new(path("objs", "bin"), 16 * BS4K as u64).unwrap(),
+            VecFreeList::new(16),
+            KeyGen {},
+            JsonKeystore::<ObjKey>::new(path("objs", "json")),
+        ));
+
+        runtime().block_on(async {
+            let uuid = store.put(b"async".to_vec()).await.unwrap();
+            assert_eq!(&AsyncObjectStore::get(&store, uuid).await.unwrap().unwrap()[..5], b"async");
+            assert_eq!(AsyncObjectStore::delete(&store, uuid).await.unwrap(), Some(uuid));
+            assert!(AsyncObjectStore::get(&store, uuid).await.unwrap().is_none());
+        });
+        cleanup("objs");
+    }
+}
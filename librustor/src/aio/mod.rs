@@ -0,0 +1,46 @@
+//! Asynchronous counterparts of the storage traits, for use on a tokio runtime.
+//!
+//! The traits return boxed futures so they stay object safe, and take owned buffers so that an
+//! implementation is free to hand the I/O to another thread. `Blocking` adapts any of the
+//! existing synchronous implementations by running each call on tokio's blocking thread pool.
+
+use std::future::Future;
+use std::pin::Pin;
+
+use uuid::Uuid;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use crate::{BS4K, RResult};
+use crate::object::ObjKey;
+use crate::objstore::ObjectID;
+
+pub mod blocking;
+pub use blocking::*;
+
+/// RustorError is Send + Sync, so results can cross threads and await points
+pub type AResult<T> = RResult<T>;
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = AResult<T>> + Send + 'a>>;
+
+pub trait AsyncBlockDevice: Send + Sync {
+    fn write_block(&self, lba: u64, data: Vec<u8>) -> BoxFuture<'_, ()>;
+    fn read_block(&self, lba: u64) -> BoxFuture<'_, Box<[u8; BS4K]>>;
+}
+
+pub trait AsyncBlockStore: Send + Sync {
+    fn write(&self, data: Vec<u8>, key: ObjKey) -> BoxFuture<'_, ()>;
+    fn read(&self, key: ObjKey) -> BoxFuture<'_, Vec<u8>>;
+}
+
+pub trait AsyncKeyStore<T: Serialize + DeserializeOwned>: Send + Sync {
+    fn set(&self, uuid: Uuid, object: T) -> BoxFuture<'_, Option<T>>;
+    fn get(&self, uuid: Uuid) -> BoxFuture<'_, Option<T>>;
+    fn delete(&self, uuid: Uuid) -> BoxFuture<'_, Option<T>>;
+}
+
+pub trait AsyncObjectStore: Send + Sync {
+    fn put(&self, data: Vec<u8>) -> BoxFuture<'_, ObjectID>;
+    fn get(&self, uuid: ObjectID) -> BoxFuture<'_, Option<Vec<u8>>>;
+    fn delete(&self, uuid: ObjectID) -> BoxFuture<'_, Option<ObjectID>>;
+}
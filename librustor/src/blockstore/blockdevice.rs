@@ -1,12 +1,12 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
 use std::path::PathBuf;
-use std::io::{Seek, SeekFrom, Read, Write, Error};
 use std::fs::{File, OpenOptions};
+use std::time::{Duration, Instant};
 
-use crate::GeneralError;
 use crate::RResult;
 
-use crate::object::ObjKey;
-
 use super::blockstore::*;
 
 #[allow(unused_imports)]
@@ -15,9 +15,106 @@ use log::{trace, debug, info, warn, error};
 pub trait BlockDevice {
     fn write_block(&mut self, lba: u64, data: &[u8]) -> RResult<()>;
     fn read_block(&mut self, lba: u64, data: &mut[u8; BS4K]) -> RResult<()>;
+
+    /// write `data` to consecutive blocks starting at `lba`. a partial last block is written as is
+    fn write_blocks(&mut self, lba: u64, data: &[u8]) -> RResult<()> {
+        for (n, block) in data.chunks(BS4K).enumerate() {
+            self.write_block(lba + n as u64, block)?;
+        }
+        Ok(())
+    }
+
+    /// fill `data` from consecutive blocks starting at `lba`
+    fn read_blocks(&mut self, lba: u64, data: &mut [u8]) -> RResult<()> {
+        let mut block = [0; BS4K];
+        for (n, chunk) in data.chunks_mut(BS4K).enumerate() {
+            block.fill(0);
+            self.read_block(lba + n as u64, &mut block)?;
+            chunk.copy_from_slice(&block[..chunk.len()]);
+        }
+        Ok(())
+    }
+
+    /// make every write completed so far durable, whatever the durability policy
+    fn sync(&mut self) -> RResult<()> {
+        Ok(())
+    }
+
+    /// called once all of an object's blocks have been written. makes them durable if the
+    /// device's durability policy asks for it
+    fn barrier(&mut self) -> RResult<()> {
+        self.sync()
+    }
+}
+
+/// When a device makes written blocks durable
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DurabilityPolicy {
+    /// leave it to the operating system. fastest, but a crash can lose acknowledged objects
+    None,
+    /// sync at every object barrier, so an object is durable before its key is stored
+    #[default]
+    PerObject,
+    /// sync after every write
+    PerWrite,
+    /// sync at a write or barrier once at least this long has passed since the last sync
+    Periodic(Duration),
 }
 
 
+#[derive(Debug)]
+pub enum BlockDeviceError {
+    /// `span` blocks at `lba` extend past the last block of the device
+    OutOfRange { lba: u64, span: u64, max_lba: u64 },
+    /// the device stopped accepting data part way through a write
+    ShortWrite { lba: u64, expected: usize, written: usize },
+    /// the device file ended part way through a read, e.g. because it was truncated
+    ShortRead { lba: u64, expected: usize, read: usize },
+    /// the device has no backing file
+    DeviceNotOpen,
+    /// the device has failed or was taken out of service
+    Offline,
+    /// the device's submission queue had no room for another request
+    QueueFull,
+    Io(io::Error),
+}
+
+impl fmt::Display for BlockDeviceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BlockDeviceError::OutOfRange { lba, span, max_lba } =>
+                write!(f, "{} blocks at lba {} are out of range (max {})", span, lba, max_lba),
+            BlockDeviceError::ShortWrite { lba, expected, written } =>
+                write!(f, "Short write at lba {}: wrote {} of {} bytes", lba, written, expected),
+            BlockDeviceError::ShortRead { lba, expected, read } =>
+                write!(f, "Short read at lba {}: read {} of {} bytes", lba, read, expected),
+            BlockDeviceError::DeviceNotOpen =>
+                write!(f, "Block device is not open"),
+            BlockDeviceError::Offline =>
+                write!(f, "Block device is offline"),
+            BlockDeviceError::QueueFull =>
+                write!(f, "Block device submission queue is full"),
+            BlockDeviceError::Io(e) =>
+                write!(f, "Block device I/O error: {}", e),
+        }
+    }
+}
+
+impl Error for BlockDeviceError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            BlockDeviceError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for BlockDeviceError {
+    fn from(e: io::Error) -> Self {
+        BlockDeviceError::Io(e)
+    }
+}
+
 #[derive(Debug)]
 pub struct BasicBlockDevice {
     //bs: u32,
@@ -25,6 +122,9 @@ pub struct BasicBlockDevice {
     max_lba: u64,
     path: PathBuf,
     file: Option<File>,
+    policy: DurabilityPolicy,
+    last_sync: Instant,
+    syncs: u64,
 }
 
 impl Default for BasicBlockDevice {
@@ -34,65 +134,325 @@ impl Default for BasicBlockDevice {
             capacity: 0,
             max_lba: 0,
             path: PathBuf::default(),
-            file: None
+            file: None,
+            policy: DurabilityPolicy::default(),
+            last_sync: Instant::now(),
+            syncs: 0,
         }
     }
 }
 
 impl BasicBlockDevice {
-    pub fn new(/*bs: u32, */capacity: u64, path: PathBuf) -> Self {
-        // TODO: validate block size
-        // TODO: validate capacity is a multiple of blocksize
-        // TODO: check path exists
+    /// open the device file at `path`, creating it if necessary, and extend it to `capacity` bytes
+    /// so that every block in range can be read back
+    pub fn new(/*bs: u32, */capacity: u64, path: PathBuf) -> RResult<Self> {
+        Self::with_policy(capacity, path, DurabilityPolicy::default())
+    }
 
-        let mut file = Some(OpenOptions::new().write(true).read(true)
+    pub fn with_policy(capacity: u64, path: PathBuf, policy: DurabilityPolicy) -> RResult<Self> {
+        let file = OpenOptions::new().write(true).read(true)
             .create(true)
-            .open(path.as_path()).unwrap());
-        BasicBlockDevice { /*bs,*/ capacity, max_lba: capacity/BS4K as u64/*(bs as u64)*/, path, file }
+            .truncate(false)
+            .open(path.as_path())
+            .map_err(BlockDeviceError::Io)?;
+        if file.metadata().map_err(BlockDeviceError::Io)?.len() < capacity {
+            file.set_len(capacity).map_err(BlockDeviceError::Io)?;
+        }
+        Ok(BasicBlockDevice {
+            /*bs,*/ capacity, max_lba: capacity/BS4K as u64/*(bs as u64)*/, path, file: Some(file),
+            policy, last_sync: Instant::now(), syncs: 0,
+        })
+    }
+
+    pub fn policy(&self) -> DurabilityPolicy {
+        self.policy
+    }
+
+    pub fn set_policy(&mut self, policy: DurabilityPolicy) {
+        self.policy = policy;
+    }
+
+    /// number of times the device file has been synced
+    pub fn syncs(&self) -> u64 {
+        self.syncs
+    }
+
+    fn sync_if_due(&mut self, interval: Duration) -> RResult<()> {
+        if self.last_sync.elapsed() >= interval {
+            self.sync()?;
+        }
+        Ok(())
+    }
+
+    pub fn capacity(&self) -> u64 {
+        self.capacity
+    }
+
+    pub fn path(&self) -> &PathBuf {
+        &self.path
+    }
+
+    /// the open file, provided `len` bytes at `lba` are within the device
+    fn file_for(&self, lba: u64, len: usize) -> Result<&File, BlockDeviceError> {
+        let file = self.file.as_ref().ok_or(BlockDeviceError::DeviceNotOpen)?;
+        let span = len.div_ceil(BS4K) as u64;
+        if lba + span > self.max_lba {
+            return Err(BlockDeviceError::OutOfRange { lba, span, max_lba: self.max_lba });
+        }
+        Ok(file)
+    }
+
+    /// fill `data` from consecutive blocks starting at `lba` with one positioned read. it needs no
+    /// `&mut self`, so reads can run in parallel
+    pub fn read_at(&self, lba: u64, data: &mut [u8]) -> RResult<()> {
+        trace!("read blocks: lba {:?}, data: {:?}", &lba, data.len());
+        let file = self.file_for(lba, data.len())?;
+        let read = read_full_at(file, data, lba * BS4K as u64).map_err(BlockDeviceError::Io)?;
+        if read < data.len() {
+            return Err(BlockDeviceError::ShortRead { lba, expected: data.len(), read })?;
+        }
+        Ok(())
     }
 }
 
 impl BlockDevice for BasicBlockDevice {
     fn write_block(&mut self, lba: u64, data: &[u8]) -> RResult<()> {
-        // TODO: check that lba is within length of device
-        
-        trace!("write block: lba {:?}, data: {:?}", &lba, data.len());
-        if let Some(file) = &mut self.file {
-            if let Err(error) = file.seek(SeekFrom::Start(lba*BS4K as u64/*self.bs*/)) {
-                return GeneralError::from(error);
-            }
+        self.write_blocks(lba, &data[..data.len().min(BS4K)])
+    }
 
-            #[allow(unused_variables)]
-            let bytes_written = file.write(&data);
-            // TODO: check that bytes written is the expected length
+    fn read_block(&mut self, lba: u64, data: &mut[u8; BS4K]) -> RResult<()> {
+        self.read_blocks(lba, data)
+    }
 
-            if let Err(error) = file.flush() {
-                return GeneralError::from(error);
-            }
-            return Ok(());
-        } else {
-            return GeneralError::new("Blockdevice::file is uninitialized");
+    /// one positioned write for the whole extent
+    fn write_blocks(&mut self, lba: u64, data: &[u8]) -> RResult<()> {
+        trace!("write blocks: lba {:?}, data: {:?}", &lba, data.len());
+        let file = self.file_for(lba, data.len())?;
+        let written = write_full_at(file, data, lba * BS4K as u64).map_err(BlockDeviceError::Io)?;
+        if written < data.len() {
+            return Err(BlockDeviceError::ShortWrite { lba, expected: data.len(), written })?;
         }
+        match self.policy {
+            DurabilityPolicy::PerWrite => self.sync(),
+            DurabilityPolicy::Periodic(interval) => self.sync_if_due(interval),
+            DurabilityPolicy::None | DurabilityPolicy::PerObject => Ok(()),
+        }
+    }
 
+    fn read_blocks(&mut self, lba: u64, data: &mut [u8]) -> RResult<()> {
+        self.read_at(lba, data)
     }
-    fn read_block(&mut self, lba: u64, data: &mut[u8; BS4K]) -> RResult<()> {
-        // TODO: check that lba is in range
 
-        if let Some(file) = &mut self.file {
-            if let Err(error) = file.seek(SeekFrom::Start(lba*BS4K as u64/*self.bs*/)) {
-                return GeneralError::from(error);
-            }
-            if let Err(error) = file.read(data) {
-                return GeneralError::from(error);
-            }
+    /// File::flush is a no-op; sync_data actually reaches the disk
+    fn sync(&mut self) -> RResult<()> {
+        let file = self.file.as_ref().ok_or(BlockDeviceError::DeviceNotOpen)?;
+        file.sync_data().map_err(BlockDeviceError::Io)?;
+        self.syncs += 1;
+        self.last_sync = Instant::now();
+        Ok(())
+    }
 
-            // TODO: check that bytes read is the expected length
+    fn barrier(&mut self) -> RResult<()> {
+        match self.policy {
+            DurabilityPolicy::PerObject => self.sync(),
+            DurabilityPolicy::Periodic(interval) => self.sync_if_due(interval),
+            // every write has already been synced
+            DurabilityPolicy::PerWrite => Ok(()),
+            DurabilityPolicy::None => Ok(()),
+        }
+    }
+}
 
-            return Ok(());
-        } else {
-            return GeneralError::new("Blockdevice::file is uninitialized");
+/// write until all of `data` is written or the file stops accepting it, returning the number of
+/// bytes written
+fn write_full_at(file: &File, data: &[u8], offset: u64) -> io::Result<usize> {
+    #[cfg(unix)]
+    use std::os::unix::fs::FileExt;
+    #[cfg(windows)]
+    use std::os::windows::fs::FileExt;
+
+    let mut total = 0;
+    while total < data.len() {
+        #[cfg(unix)]
+        let n = file.write_at(&data[total..], offset + total as u64);
+        #[cfg(windows)]
+        let n = file.seek_write(&data[total..], offset + total as u64);
+        match n {
+            Ok(0) => break,
+            Ok(n) => total += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
         }
+    }
+    Ok(total)
+}
 
+/// read until `data` is full or the end of the file, returning the number of bytes read
+fn read_full_at(file: &File, data: &mut [u8], offset: u64) -> io::Result<usize> {
+    #[cfg(unix)]
+    use std::os::unix::fs::FileExt;
+    #[cfg(windows)]
+    use std::os::windows::fs::FileExt;
+
+    let mut total = 0;
+    while total < data.len() {
+        #[cfg(unix)]
+        let n = file.read_at(&mut data[total..], offset + total as u64);
+        #[cfg(windows)]
+        let n = file.seek_read(&mut data[total..], offset + total as u64);
+        match n {
+            Ok(0) => break,
+            Ok(n) => total += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
     }
+    Ok(total)
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RustorError;
+
+    /// only has the single-block methods, so it exercises the trait's default extent methods
+    struct SingleBlocks(BasicBlockDevice);
+
+    impl BlockDevice for SingleBlocks {
+        fn write_block(&mut self, lba: u64, data: &[u8]) -> RResult<()> {
+            self.0.write_block(lba, data)
+        }
+        fn read_block(&mut self, lba: u64, data: &mut [u8; BS4K]) -> RResult<()> {
+            self.0.read_block(lba, data)
+        }
+    }
+
+    fn path(test: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("rustor-blockdevice-{}-{}.bin", test, std::process::id()))
+    }
+
+    fn pattern(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    fn round_trip(dev: &mut dyn BlockDevice) {
+        let data = pattern(3 * BS4K + 100);
+        dev.write_blocks(5, &data).unwrap();
+
+        let mut read = vec![0xff; 4 * BS4K];
+        dev.read_blocks(5, &mut read).unwrap();
+        assert_eq!(&read[..data.len()], &data[..]);
+        // the unwritten tail of the last block, and the never-written block after it, read as zeros
+        assert!(read[data.len()..].iter().all(|b| *b == 0));
+
+        // extents and single blocks address the same data
+        let mut block = [0; BS4K];
+        dev.read_block(7, &mut block).unwrap();
+        assert_eq!(&block[..], &data[2 * BS4K..3 * BS4K]);
+
+        let mut partial = vec![0; 10];
+        dev.read_blocks(6, &mut partial).unwrap();
+        assert_eq!(&partial[..], &data[BS4K..BS4K + 10]);
+    }
+
+    #[test]
+    fn test_default_extent_methods() {
+        round_trip(&mut SingleBlocks(BasicBlockDevice::new(16 * BS4K as u64, path("default")).unwrap()));
+        let _ = std::fs::remove_file(path("default"));
+    }
+
+    #[test]
+    fn test_positioned_extent_methods() {
+        round_trip(&mut BasicBlockDevice::new(16 * BS4K as u64, path("positioned")).unwrap());
+        let _ = std::fs::remove_file(path("positioned"));
+    }
+
+    fn device_error(result: RResult<()>) -> BlockDeviceError {
+        match result.unwrap_err() {
+            RustorError::BlockDevice { error, .. } => error,
+            e => panic!("not a BlockDeviceError: {}", e),
+        }
+    }
+
+    #[test]
+    fn test_out_of_range() {
+        let mut dev = BasicBlockDevice::new(16 * BS4K as u64, path("range")).unwrap();
+        let mut block = [0; BS4K];
+        assert!(matches!(device_error(dev.read_block(16, &mut block)),
+            BlockDeviceError::OutOfRange { lba: 16, span: 1, max_lba: 16 }));
+        assert!(matches!(device_error(dev.write_blocks(15, &[1; BS4K + 1])),
+            BlockDeviceError::OutOfRange { lba: 15, span: 2, .. }));
+        assert!(dev.write_block(15, &[1; BS4K]).is_ok());
+        let _ = std::fs::remove_file(path("range"));
+    }
+
+    #[test]
+    fn test_truncated_device_file() {
+        let mut dev = BasicBlockDevice::new(16 * BS4K as u64, path("truncated")).unwrap();
+        dev.write_blocks(8, &[3; 2 * BS4K]).unwrap();
+
+        // something else cuts the device file short in the middle of block 9
+        let file = OpenOptions::new().write(true).open(path("truncated")).unwrap();
+        file.set_len(9 * BS4K as u64 + 100).unwrap();
+
+        let mut block = [0; BS4K];
+        dev.read_block(8, &mut block).unwrap();
+        assert!(matches!(device_error(dev.read_block(9, &mut block)),
+            BlockDeviceError::ShortRead { lba: 9, expected: BS4K, read: 100 }));
+        assert!(matches!(device_error(dev.read_block(12, &mut block)),
+            BlockDeviceError::ShortRead { lba: 12, read: 0, .. }));
+
+        // reopening restores the full capacity
+        let mut dev = BasicBlockDevice::new(16 * BS4K as u64, path("truncated")).unwrap();
+        dev.read_block(12, &mut block).unwrap();
+        assert_eq!(block[..], [0; BS4K][..]);
+        let _ = std::fs::remove_file(path("truncated"));
+    }
+
+    #[test]
+    fn test_not_open() {
+        let mut dev = BasicBlockDevice::default();
+        assert!(matches!(device_error(dev.write_block(0, &[0; BS4K])), BlockDeviceError::DeviceNotOpen));
+
+        // a directory can't be opened as a device file
+        match BasicBlockDevice::new(BS4K as u64, std::env::temp_dir()) {
+            Err(e) => assert!(matches!(e, RustorError::BlockDevice { error: BlockDeviceError::Io(_), .. })),
+            Ok(_) => panic!("opened a directory as a block device"),
+        }
+    }
+
+    #[test]
+    fn test_durability_policies() {
+        let mut dev = BasicBlockDevice::with_policy(16 * BS4K as u64, path("durability"), DurabilityPolicy::None).unwrap();
+        dev.write_blocks(0, &[1; 2 * BS4K]).unwrap();
+        dev.barrier().unwrap();
+        assert_eq!(dev.syncs(), 0);
+        // an explicit sync always happens
+        dev.sync().unwrap();
+        assert_eq!(dev.syncs(), 1);
+
+        dev.set_policy(DurabilityPolicy::PerObject);
+        dev.write_blocks(0, &[2; BS4K]).unwrap();
+        dev.write_blocks(1, &[2; BS4K]).unwrap();
+        assert_eq!(dev.syncs(), 1);
+        dev.barrier().unwrap();
+        assert_eq!(dev.syncs(), 2);
+
+        dev.set_policy(DurabilityPolicy::PerWrite);
+        dev.write_blocks(0, &[3; BS4K]).unwrap();
+        dev.write_block(1, &[3; BS4K]).unwrap();
+        dev.barrier().unwrap();
+        assert_eq!(dev.syncs(), 4);
+
+        dev.set_policy(DurabilityPolicy::Periodic(Duration::from_secs(3600)));
+        dev.write_blocks(0, &[4; BS4K]).unwrap();
+        dev.barrier().unwrap();
+        assert_eq!(dev.syncs(), 4);
+        dev.set_policy(DurabilityPolicy::Periodic(Duration::from_secs(0)));
+        dev.barrier().unwrap();
+        assert_eq!(dev.syncs(), 5);
+
+        assert!(matches!(device_error(BasicBlockDevice::default().sync()), BlockDeviceError::DeviceNotOpen));
+        let _ = std::fs::remove_file(path("durability"));
+    }
+}
@@ -12,57 +12,89 @@ use crate::RResult;
 pub trait BlockStore {
     fn write(&mut self, data: &[u8], key: &ObjKey) -> RResult<()>;
     fn read(&mut self, data: &mut Vec<u8>, key: &ObjKey) -> RResult<()>;
+
+    /// as `read`, through a shared reference so that reads can run in parallel. None if the
+    /// store can only read through `read`
+    fn read_shared(&self, _data: &mut Vec<u8>, _key: &ObjKey) -> Option<RResult<()>> {
+        None
+    }
+
+    /// make everything written so far durable on every device
+    fn sync(&mut self) -> RResult<()> {
+        Ok(())
+    }
+
+    /// called after an object is written and before its key is stored. passes the barrier on to
+    /// the devices, which sync according to their durability policy
+    fn barrier(&mut self) -> RResult<()> {
+        Ok(())
+    }
 }
 
 
 use super::BasicBlockDevice;
 use super::BlockDevice;
+use super::DurabilityPolicy;
 pub struct SingleDeviceBlockStore {
     pub device: BasicBlockDevice,
 }
 
 impl SingleDeviceBlockStore {
-    pub fn new(path: PathBuf, capacity: u64) -> Self {
-        Self {
-            device : BasicBlockDevice::new(capacity, path)
+    pub fn new(path: PathBuf, capacity: u64) -> RResult<Self> {
+        Ok(Self {
+            device : BasicBlockDevice::new(capacity, path)?
+        })
+    }
+
+    pub fn with_policy(path: PathBuf, capacity: u64, policy: DurabilityPolicy) -> RResult<Self> {
+        Ok(Self {
+            device : BasicBlockDevice::with_policy(capacity, path, policy)?
+        })
+    }
+
+    fn read_extents(&self, data: &mut Vec<u8>, key: &ObjKey) -> RResult<()> {
+        debug!("read data: {:?}", &key);
+        for entry in key.manifest.shards.iter() {
+            let start = data.len();
+            data.resize(start + entry.span as usize * BS4K, 0);
+            self.device.read_at(entry.lba, &mut data[start..])?;
         }
+        Ok(())
     }
 }
 
 impl BlockStore for SingleDeviceBlockStore {
-    #[allow(unused_variables)]
     fn write(&mut self, data: &[u8], key: &ObjKey) -> RResult<()> {
         debug!("write object {:?}, size {:?}", &key.uuid, &key.size);
+        // shards are filled in manifest order, so a fragmented object's data continues from one
+        // shard into the next
+        let mut rest = data;
         for entry in key.manifest.shards.iter() {
             debug!("entry: {:?}", &entry);
-            let span = entry.span;
-            let mut start = 0;
-            for n in 0..span {
-                let lba:u64 = entry.lba + n;
-                let end = if data.len() - n as usize * BS4K < BS4K { data.len() - n as usize * BS4K } else { BS4K };
-                let slice = &data[ start .. end ];
-                self.device.write_block(lba as u64, slice)?;
-                start += BS4K;
+            if rest.is_empty() {
+                break;
             }
+            let n = rest.len().min(entry.span as usize * BS4K);
+            self.device.write_blocks(entry.lba, &rest[..n])?;
+            rest = &rest[n..];
         }
         
         Ok(())
     }
 
-    #[allow(unused_variables)]
     fn read(&mut self, data: &mut Vec<u8>, key: &ObjKey) -> RResult<()> {
-        debug!("read data: {:?}", &key);
-        for entry in key.manifest.shards.iter() {
-            let span = entry.span;
-            for n in 0..span as usize {
-                let lba = entry.lba as usize + n;
-
-                // TODO: optimize this so that read_block copies directly into data
-                let mut readblk = [0; BS4K]; //&data[ n*BS4K as usize .. (n+1)*BS4K as usize];
-                self.device.read_block(lba as u64, &mut readblk)?;
-                data.extend_from_slice(&readblk);
-            }
-        }
-        Ok(())
+        self.read_extents(data, key)
+    }
+
+    fn read_shared(&self, data: &mut Vec<u8>, key: &ObjKey) -> Option<RResult<()>> {
+        Some(self.read_extents(data, key))
+    }
+
+    fn sync(&mut self) -> RResult<()> {
+        self.device.sync()
+    }
+
+    fn barrier(&mut self) -> RResult<()> {
+        self.device.barrier()
     }
 }
@@ -1,27 +1,14 @@
 #![allow(unused_imports)]
 pub mod blockstore;
 pub mod blockdevice;
+pub mod registry;
+pub mod raid;
+#[cfg(all(target_os = "linux", feature = "uring"))]
+pub mod uring;
 
 pub use blockstore::*;
 pub use blockdevice::*;
-
-/*
-#[derive(Debug, Default)]
-struct BlockDevice {
-    bs: u32,
-    capacity: u64,
-    max_lba: u64,
-}
-
-impl BlockDevice {
-    fn new(bs: u32, capacity: u64) -> BlockDevice {
-        BlockDevice { bs, capacity, max_lba: capacity/bs }
-    }
-}
-
-trait BlockStore {
-    fn write_block(&self, lba: u64, data: [u8]) -> Result<(), String>;
-    fn read_block(&self, lba: u64, data: &[u8]) -> Result<(), String>;
-}
-
-*/
+pub use registry::*;
+pub use raid::*;
+#[cfg(all(target_os = "linux", feature = "uring"))]
+pub use uring::*;
@@ -1,47 +1,473 @@
-use std::path::PathBuf;
-
-use std::fs::OpenOptions;
-
-enum RAIDLevel {
-    RAID0, // block-level striping
-    RAID1, // mirroring
-    //RAID2, // bit-level striping with dedicated hamming-code parity -- not going to implement
-    //RAID3, // byte-level striping with dedicated parity drive. uncommon -- not going to implement
-    RAID4, // block-level striping with dedicated parity drive
-    RAID5, // block-level striping with distributed parity
-    RAID6, // block-level striping with double distributed parity
-    DCRAID, // declustered raid n+m
-}
+use std::collections::BTreeSet;
+
+use crate::object::{ObjKey, BlkDevID};
+use crate::keystore::{KeyStore, ListQuery};
+use crate::{RResult, RustorError};
+
+use super::{BlockStore, BlockDevice, BlockDeviceError, BS4K};
+use super::registry::{DeviceRegistry, DeviceState};
 
-trait RAID {
+#[allow(unused_imports)]
+use log::{trace, debug, info, warn, error};
 
+fn xor_into(acc: &mut [u8; BS4K], other: &[u8; BS4K]) {
+    for (a, b) in acc.iter_mut().zip(other.iter()) {
+        *a ^= *b;
+    }
 }
 
-struct RAID0 {
-    disks: Vec<PathBuf>,
+/// Result of rebuilding a failed member onto a spare
+#[derive(Debug)]
+pub struct RebuildReport {
+    pub failed: BlkDevID,
+    pub spare: BlkDevID,
+    /// number of blocks regenerated onto the spare
+    pub blocks: u64,
+    /// number of keys whose manifest referenced the failed device, rewritten in the keystore to
+    /// point at the spare
+    pub rewritten: usize,
 }
 
-impl RAID0 {
-    pub fn new() -> RAID0 {
-        RAID0 { vec![] }
-    }
+/// RAID4: LBAs are striped across `n` data devices with a dedicated XOR parity device.
+///
+/// LBA `l` lives on data member `l % n` at row `l / n`; the parity device holds the XOR of every
+/// data member at each row. Any single member can be lost without failing reads: the missing
+/// block is regenerated from the survivors and the member is marked failed in the registry.
+pub struct ParityBlockStore {
+    pub registry: DeviceRegistry,
+    /// data members followed by the parity member
+    members: Vec<BlkDevID>,
 }
 
-impl BlockStore for RAID0 {
-    fn write_block(&self, lba: u64, data[u8]) -> Result<(), String> {
-        let index = lba % self.disks.len();
-        let disk = self.disks[index];
+impl ParityBlockStore {
+    pub fn new(data: Vec<Box<dyn BlockDevice>>, parity: Box<dyn BlockDevice>) -> RResult<Self> {
+        if data.is_empty() {
+            return Err(RustorError::Config("ParityBlockStore needs at least one data device".to_string()));
+        }
+        let mut registry = DeviceRegistry::new();
+        let mut members: Vec<BlkDevID> = data.into_iter().map(|d| registry.add(d)).collect();
+        members.push(registry.add(parity));
+        Ok(Self { registry, members })
+    }
+
+    pub fn add_spare(&mut self, device: Box<dyn BlockDevice>) -> BlkDevID {
+        self.registry.add_spare(device)
+    }
+
+    /// ids of the data members, in stripe order
+    pub fn data_devices(&self) -> &[BlkDevID] {
+        &self.members[..self.members.len() - 1]
+    }
+
+    pub fn parity_device(&self) -> BlkDevID {
+        self.members[self.members.len() - 1]
+    }
 
-        let disk_lba = lba / self.disks.len();
-        
+    pub fn is_degraded(&self) -> bool {
+        self.members.iter().any(|id| self.registry.state(id) != Some(DeviceState::Online))
     }
 
-    fn read_block(&self, lba: u64, data: &[u8]) -> Result<(), String> {
+    fn n_data(&self) -> u64 {
+        self.members.len() as u64 - 1
+    }
+
+    fn parity_slot(&self) -> usize {
+        self.members.len() - 1
+    }
+
+    /// (member slot, row) holding `lba`
+    fn locate(&self, lba: u64) -> (usize, u64) {
+        ((lba % self.n_data()) as usize, lba / self.n_data())
+    }
+
+    fn online(&self, slot: usize) -> bool {
+        self.registry.state(&self.members[slot]) == Some(DeviceState::Online)
+    }
+
+    /// read one member block, marking the member failed if the device errors
+    fn read_member(&mut self, slot: usize, row: u64, buf: &mut [u8; BS4K]) -> RResult<()> {
+        let id = self.members[slot];
+        if !self.online(slot) {
+            return Err(RustorError::from(BlockDeviceError::Offline).with_device(id));
+        }
+        // devices leave the buffer untouched past the end of their backing file
+        *buf = [0; BS4K];
+        if let Err(e) = self.registry.device(&id)?.read_block(row, buf) {
+            warn!("read of row {} from {:?} failed: {}", row, &id, e);
+            self.registry.mark_failed(&id)?;
+            return Err(e.with_device(id));
+        }
+        Ok(())
+    }
+
+    fn write_member(&mut self, slot: usize, row: u64, buf: &[u8; BS4K]) -> RResult<()> {
+        let id = self.members[slot];
+        if !self.online(slot) {
+            return Err(RustorError::from(BlockDeviceError::Offline).with_device(id));
+        }
+        if let Err(e) = self.registry.device(&id)?.write_block(row, buf) {
+            warn!("write of row {} to {:?} failed: {}", row, &id, e);
+            self.registry.mark_failed(&id)?;
+            return Err(e.with_device(id));
+        }
+        Ok(())
+    }
 
+    /// regenerate the block of member `slot` at `row` from every other member
+    fn reconstruct(&mut self, slot: usize, row: u64, buf: &mut [u8; BS4K]) -> RResult<()> {
+        debug!("reconstructing row {} of member {}", row, slot);
+        *buf = [0; BS4K];
+        let mut other = [0; BS4K];
+        for s in (0..self.members.len()).filter(|s| *s != slot) {
+            if self.read_member(s, row, &mut other).is_err() {
+                return Err(RustorError::Integrity {
+                    uuid: None, lba: Some(row), reason: "more than one device has failed".to_string() });
+            }
+            xor_into(buf, &other);
+        }
+        Ok(())
+    }
+
+    fn read_lba(&mut self, lba: u64, buf: &mut [u8; BS4K]) -> RResult<()> {
+        let (slot, row) = self.locate(lba);
+        if self.read_member(slot, row, buf).is_ok() {
+            return Ok(());
+        }
+        self.reconstruct(slot, row, buf)
+    }
+
+    fn write_lba(&mut self, lba: u64, data: &[u8]) -> RResult<()> {
+        let (slot, row) = self.locate(lba);
+        let parity = self.parity_slot();
+
+        let mut new = [0; BS4K];
+        new[..data.len()].copy_from_slice(data);
+
+        let data_ok = self.online(slot);
+        if self.online(parity) {
+            // new parity = old parity ^ old data ^ new data
+            let mut p = [0; BS4K];
+            let mut old = [0; BS4K];
+            let updated = self.read_member(parity, row, &mut p)
+                .and_then(|_| self.read_lba(lba, &mut old))
+                .and_then(|_| {
+                    xor_into(&mut p, &old);
+                    xor_into(&mut p, &new);
+                    self.write_member(parity, row, &p)
+                });
+            // parity that doesn't cover the new data would regenerate the wrong block, so the
+            // array runs degraded without it
+            if let Err(e) = updated {
+                warn!("could not update parity of row {}: {}", row, e);
+                let id = self.members[parity];
+                if self.online(parity) {
+                    self.registry.mark_failed(&id)?;
+                }
+            }
+        }
+
+        let wrote_data = data_ok && self.write_member(slot, row, &new).is_ok();
+        if !wrote_data && !self.online(parity) {
+            return Err(RustorError::Integrity {
+                uuid: None, lba: Some(lba), reason: "data and parity devices unavailable".to_string() });
+        }
+        Ok(())
+    }
+
+    /// sync or pass a barrier to every online member, marking any that fail
+    fn sync_members(&mut self, barrier: bool) -> RResult<()> {
+        let online: Vec<usize> = (0..self.members.len()).filter(|s| self.online(*s)).collect();
+        for slot in online {
+            let id = self.members[slot];
+            let dev = self.registry.device(&id)?;
+            let result = if barrier { dev.barrier() } else { dev.sync() };
+            if let Err(e) = result {
+                warn!("sync of {:?} failed: {}", &id, e);
+                self.registry.mark_failed(&id)?;
+                return Err(e.with_device(id));
+            }
+        }
+        Ok(())
+    }
+
+    /// Regenerate the first failed member onto a spare device.
+    ///
+    /// Only rows referenced by the manifests in `keystore` are rebuilt, and the keys that referred
+    /// to the failed device are stored again referring to the spare. Once every row and key is
+    /// written the spare takes over the failed member's slot and the failed device is dropped from
+    /// the registry, so a rebuild that fails part way can be run again. Returns `None` if no
+    /// member has failed.
+    pub fn rebuild(&mut self, keystore: &mut dyn KeyStore<ObjKey>) -> RResult<Option<RebuildReport>> {
+        let slot = match (0..self.members.len()).find(|s| !self.online(*s)) {
+            Some(s) => s,
+            None => return Ok(None),
+        };
+        let failed = self.members[slot];
+        // the lowest spare id, so the same spare is chosen every time
+        let spare = match self.registry.spares().first() {
+            Some(id) => *id,
+            None => return Err(RustorError::Config(format!("No spare available to rebuild {:?}", &failed))),
+        };
+        info!("rebuilding {:?} onto {:?}", &failed, &spare);
+
+        let keys = keystore.list(&ListQuery::default())?.cloned().keys;
+        let mut rows = BTreeSet::new();
+        let mut rewritten = Vec::new();
+        for key in keys.iter() {
+            for shard in key.manifest.shards.iter() {
+                for lba in shard.lba .. shard.lba + shard.span {
+                    let (s, row) = self.locate(lba);
+                    if s == slot || slot == self.parity_slot() {
+                        rows.insert(row);
+                    }
+                }
+            }
+            let mut moved = key.clone();
+            if moved.manifest.replace_device(failed, spare) {
+                rewritten.push(moved);
+            }
+        }
+
+        let mut buf = [0; BS4K];
+        for row in rows.iter() {
+            self.reconstruct(slot, *row, &mut buf)?;
+            self.registry.device(&spare)?.write_block(*row, &buf)?;
+        }
+        // the spare must hold everything before it replaces the failed member
+        self.registry.device(&spare)?.sync()?;
+        for key in rewritten.iter() {
+            keystore.set(key.uuid, key.clone())?;
+        }
+
+        self.members[slot] = spare;
+        self.registry.set_state(&spare, DeviceState::Online)?;
+        self.registry.remove(&failed);
+
+        Ok(Some(RebuildReport { failed, spare, blocks: rows.len() as u64, rewritten: rewritten.len() }))
     }
 }
 
-impl RAID5 {
+impl BlockStore for ParityBlockStore {
+    fn write(&mut self, data: &[u8], key: &ObjKey) -> RResult<()> {
+        debug!("write object {:?}, size {:?}", &key.uuid, &key.size);
+        let mut chunks = data.chunks(BS4K);
+        for entry in key.manifest.shards.iter() {
+            for lba in entry.lba .. entry.lba + entry.span {
+                match chunks.next() {
+                    Some(chunk) => self.write_lba(lba, chunk)?,
+                    None => return Ok(()),
+                }
+            }
+        }
+        Ok(())
+    }
 
+    fn read(&mut self, data: &mut Vec<u8>, key: &ObjKey) -> RResult<()> {
+        debug!("read data: {:?}", &key);
+        let mut readblk = [0; BS4K];
+        for entry in key.manifest.shards.iter() {
+            for lba in entry.lba .. entry.lba + entry.span {
+                self.read_lba(lba, &mut readblk)?;
+                data.extend_from_slice(&readblk);
+            }
+        }
+        data.truncate(key.size as usize);
+        Ok(())
+    }
+
+    fn sync(&mut self) -> RResult<()> {
+        self.sync_members(false)
+    }
+
+    fn barrier(&mut self) -> RResult<()> {
+        self.sync_members(true)
+    }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::path::PathBuf;
+    use std::rc::Rc;
+    use uuid::Uuid;
+
+    use crate::blockstore::BasicBlockDevice;
+    use crate::keystore::JsonKeystore;
+    use crate::object::{Manifest, ManifestLocation};
+
+    /// a device that starts returning errors once its switch is flipped
+    struct FlakyDevice {
+        inner: BasicBlockDevice,
+        failed: Rc<Cell<bool>>,
+    }
+
+    impl BlockDevice for FlakyDevice {
+        fn write_block(&mut self, lba: u64, data: &[u8]) -> RResult<()> {
+            if self.failed.get() { return Err(BlockDeviceError::Offline)?; }
+            self.inner.write_block(lba, data)
+        }
+        fn read_block(&mut self, lba: u64, data: &mut [u8; BS4K]) -> RResult<()> {
+            if self.failed.get() { return Err(BlockDeviceError::Offline)?; }
+            self.inner.read_block(lba, data)
+        }
+    }
+
+    fn device_path(test: &str, n: usize) -> PathBuf {
+        std::env::temp_dir().join(format!("rustor-raid-{}-{}-{}.bin", test, std::process::id(), n))
+    }
+
+    fn flaky(test: &str, n: usize) -> (Box<dyn BlockDevice>, Rc<Cell<bool>>) {
+        let failed = Rc::new(Cell::new(false));
+        let dev = FlakyDevice {
+            inner: BasicBlockDevice::new(64 * BS4K as u64, device_path(test, n)).unwrap(),
+            failed: Rc::clone(&failed),
+        };
+        (Box::new(dev), failed)
+    }
+
+    /// a device whose writes fail, while reads still succeed
+    struct ReadOnlyDevice(BasicBlockDevice);
+
+    impl BlockDevice for ReadOnlyDevice {
+        fn write_block(&mut self, _lba: u64, _data: &[u8]) -> RResult<()> {
+            Err(BlockDeviceError::Offline)?
+        }
+        fn read_block(&mut self, lba: u64, data: &mut [u8; BS4K]) -> RResult<()> {
+            self.0.read_block(lba, data)
+        }
+    }
+
+    fn cleanup(test: &str, n: usize) {
+        for i in 0..n {
+            let _ = std::fs::remove_file(device_path(test, i));
+        }
+        let _ = std::fs::remove_file(keystore_path(test));
+    }
+
+    fn keystore_path(test: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("rustor-raid-{}-{}.json", test, std::process::id()))
+    }
+
+    fn keystore(test: &str) -> JsonKeystore<ObjKey> {
+        let _ = std::fs::remove_file(keystore_path(test));
+        JsonKeystore::new(keystore_path(test))
+    }
+
+    fn object(lba: u64, size: usize) -> (Vec<u8>, ObjKey) {
+        let data: Vec<u8> = (0..size).map(|i| (i % 251) as u8).collect();
+        let span = size.div_ceil(BS4K) as u64;
+        let key = ObjKey {
+            uuid: Uuid::new_v4(),
+            hash: 0,
+            size: size as u64,
+            manifest: Manifest { shards: vec![ManifestLocation { blkdevid: None, lba, span }] },
+            ..Default::default()
+        };
+        (data, key)
+    }
+
+    #[test]
+    fn test_degraded_read() {
+        let (d0, _) = flaky("degraded", 0);
+        let (d1, fail1) = flaky("degraded", 1);
+        let (d2, _) = flaky("degraded", 2);
+        let (p, _) = flaky("degraded", 3);
+        let mut store = ParityBlockStore::new(vec![d0, d1, d2], p).unwrap();
+
+        let (data, key) = object(0, 5 * BS4K + 100);
+        store.write(&data, &key).unwrap();
+
+        fail1.set(true);
+        let mut out = Vec::new();
+        store.read(&mut out, &key).unwrap();
+        assert_eq!(out, data);
+
+        let failed = store.data_devices()[1];
+        assert_eq!(store.registry.state(&failed), Some(DeviceState::Failed));
+        assert!(store.is_degraded());
+
+        cleanup("degraded", 4);
+    }
+
+    #[test]
+    fn test_rebuild_onto_spare() {
+        let (d0, fail0) = flaky("rebuild", 0);
+        let (d1, _) = flaky("rebuild", 1);
+        let (p, _) = flaky("rebuild", 2);
+        let (spare, _) = flaky("rebuild", 3);
+        let (other, _) = flaky("rebuild", 4);
+        let mut store = ParityBlockStore::new(vec![d0, d1], p).unwrap();
+        // of several spares, the lowest id is used
+        let spare_id = store.add_spare(spare).min(store.add_spare(other));
+
+        let failed = store.data_devices()[0];
+        let (data, mut key) = object(4, 3 * BS4K);
+        key.manifest.shards[0].blkdevid = Some(failed);
+        store.write(&data, &key).unwrap();
+
+        fail0.set(true);
+        let mut out = Vec::new();
+        store.read(&mut out, &key).unwrap();
+        assert_eq!(out, data);
+
+        let mut keys = keystore("rebuild");
+        keys.set(key.uuid, key.clone()).unwrap();
+        let report = store.rebuild(&mut keys).unwrap().unwrap();
+        assert_eq!(report.failed, failed);
+        assert_eq!(report.spare, spare_id);
+        assert_eq!(report.blocks, 2);
+        assert_eq!(report.rewritten, 1);
+        assert_eq!(keys.get(&key.uuid).unwrap().unwrap().manifest.shards[0].blkdevid, Some(spare_id));
+
+        assert!(!store.is_degraded());
+        assert_eq!(store.data_devices()[0], spare_id);
+        assert!(store.registry.state(&failed).is_none());
+
+        // the spare now serves reads directly
+        let mut out = Vec::new();
+        store.read(&mut out, &key).unwrap();
+        assert_eq!(out, data);
+        assert!(store.rebuild(&mut keys).unwrap().is_none());
+
+        cleanup("rebuild", 5);
+    }
+
+    #[test]
+    fn test_failed_parity_write_degrades() {
+        let (d0, _) = flaky("parity", 0);
+        let (d1, _) = flaky("parity", 1);
+        let parity = ReadOnlyDevice(BasicBlockDevice::new(64 * BS4K as u64, device_path("parity", 2)).unwrap());
+        let mut store = ParityBlockStore::new(vec![d0, d1], Box::new(parity)).unwrap();
+
+        // the data is written, but the stale parity is taken out of the array
+        let (data, key) = object(0, 3 * BS4K);
+        store.write(&data, &key).unwrap();
+        assert_eq!(store.registry.state(&store.parity_device()), Some(DeviceState::Failed));
+        assert!(store.is_degraded());
+
+        let mut out = Vec::new();
+        store.read(&mut out, &key).unwrap();
+        assert_eq!(out, data);
+
+        cleanup("parity", 3);
+    }
+
+    #[test]
+    fn test_double_failure() {
+        let (d0, fail0) = flaky("double", 0);
+        let (d1, fail1) = flaky("double", 1);
+        let (p, _) = flaky("double", 2);
+        let mut store = ParityBlockStore::new(vec![d0, d1], p).unwrap();
+
+        let (data, key) = object(0, 2 * BS4K);
+        store.write(&data, &key).unwrap();
+
+        fail0.set(true);
+        fail1.set(true);
+        let mut out = Vec::new();
+        assert!(store.read(&mut out, &key).is_err());
+
+        cleanup("double", 3);
+    }
+}
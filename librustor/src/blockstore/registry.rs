@@ -0,0 +1,115 @@
+use std::collections::HashMap;
+
+use uuid::Uuid;
+
+use crate::object::BlkDevID;
+use crate::{RResult, RustorError};
+
+use super::BlockDevice;
+
+#[allow(unused_imports)]
+use log::{trace, debug, info, warn, error};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceState {
+    /// in service and expected to return good data
+    Online,
+    /// returned an I/O error; its contents must be reconstructed from the other members
+    Failed,
+    /// idle, available to replace a failed device
+    Spare,
+}
+
+struct DeviceEntry {
+    state: DeviceState,
+    device: Box<dyn BlockDevice>,
+}
+
+/// Tracks every block device known to a `BlockStore` by `BlkDevID`, along with its health
+#[derive(Default)]
+pub struct DeviceRegistry {
+    devices: HashMap<BlkDevID, DeviceEntry>,
+}
+
+impl DeviceRegistry {
+    pub fn new() -> Self {
+        Self { devices: HashMap::new() }
+    }
+
+    /// register an online device and return the id assigned to it
+    pub fn add(&mut self, device: Box<dyn BlockDevice>) -> BlkDevID {
+        self.insert(device, DeviceState::Online)
+    }
+
+    /// register a device held in reserve for rebuilds
+    pub fn add_spare(&mut self, device: Box<dyn BlockDevice>) -> BlkDevID {
+        self.insert(device, DeviceState::Spare)
+    }
+
+    fn insert(&mut self, device: Box<dyn BlockDevice>, state: DeviceState) -> BlkDevID {
+        let id = Uuid::new_v4();
+        debug!("registered device {:?} as {:?}", &id, &state);
+        self.devices.insert(id, DeviceEntry { state, device });
+        id
+    }
+
+    pub fn state(&self, id: &BlkDevID) -> Option<DeviceState> {
+        self.devices.get(id).map(|entry| entry.state)
+    }
+
+    pub fn set_state(&mut self, id: &BlkDevID, state: DeviceState) -> RResult<()> {
+        if let Some(entry) = self.devices.get_mut(id) {
+            debug!("device {:?}: {:?} -> {:?}", id, &entry.state, &state);
+            entry.state = state;
+            Ok(())
+        } else {
+            Err(RustorError::Config(format!("Unknown block device {:?}", id)))
+        }
+    }
+
+    pub fn mark_failed(&mut self, id: &BlkDevID) -> RResult<()> {
+        if self.state(id) != Some(DeviceState::Failed) {
+            warn!("marking device {:?} failed", id);
+        }
+        self.set_state(id, DeviceState::Failed)
+    }
+
+    /// ids of all devices currently in `state`, lowest first so the order doesn't depend on the map's
+    pub fn with_state(&self, state: DeviceState) -> Vec<BlkDevID> {
+        let mut ids: Vec<BlkDevID> = self.devices.iter()
+            .filter(|(_, entry)| entry.state == state)
+            .map(|(id, _)| *id)
+            .collect();
+        ids.sort_unstable();
+        ids
+    }
+
+    pub fn failed(&self) -> Vec<BlkDevID> {
+        self.with_state(DeviceState::Failed)
+    }
+
+    pub fn spares(&self) -> Vec<BlkDevID> {
+        self.with_state(DeviceState::Spare)
+    }
+
+    /// access a device regardless of its state
+    pub fn device(&mut self, id: &BlkDevID) -> RResult<&mut dyn BlockDevice> {
+        match self.devices.get_mut(id) {
+            Some(entry) => Ok(entry.device.as_mut()),
+            None => Err(RustorError::Config(format!("Unknown block device {:?}", id))),
+        }
+    }
+
+    /// drop a device from the registry, handing it back to the caller
+    pub fn remove(&mut self, id: &BlkDevID) -> Option<Box<dyn BlockDevice>> {
+        self.devices.remove(id).map(|entry| entry.device)
+    }
+
+    pub fn len(&self) -> usize {
+        self.devices.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.devices.is_empty()
+    }
+}
@@ -0,0 +1,450 @@
+use std::alloc::{alloc_zeroed, dealloc, handle_alloc_error, Layout};
+use std::fs::{File, OpenOptions};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
+use std::ptr::NonNull;
+use std::{io, slice};
+
+use io_uring::{opcode, types, IoUring};
+
+use crate::{RResult, RustorError};
+use crate::object::ObjKey;
+use super::{BlockDevice, BlockDeviceError, BlockStore, BS4K};
+
+#[allow(unused_imports)]
+use log::{trace, debug, info, warn, error};
+
+/// largest single read or write submitted, in blocks. longer extents are split so that a deep
+/// queue keeps several requests in flight
+const MAX_IO_BLOCKS: usize = 256;
+
+#[derive(Debug, Clone, Copy)]
+pub struct UringOptions {
+    /// number of requests submitted to the ring at once
+    pub queue_depth: u32,
+    /// open the file with O_DIRECT, bypassing the page cache
+    pub direct: bool,
+}
+
+impl Default for UringOptions {
+    fn default() -> Self {
+        Self { queue_depth: 32, direct: false }
+    }
+}
+
+/// a heap buffer aligned to the block size, as O_DIRECT requires
+struct AlignedBuf {
+    ptr: NonNull<u8>,
+    layout: Layout,
+}
+
+impl AlignedBuf {
+    fn zeroed(blocks: usize) -> Self {
+        let layout = Layout::from_size_align(blocks.max(1) * BS4K, BS4K).unwrap();
+        let ptr = unsafe { alloc_zeroed(layout) };
+        let ptr = NonNull::new(ptr).unwrap_or_else(|| handle_alloc_error(layout));
+        Self { ptr, layout }
+    }
+
+    fn len(&self) -> usize {
+        self.layout.size()
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.ptr.as_ptr(), self.len()) }
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len()) }
+    }
+}
+
+impl Drop for AlignedBuf {
+    fn drop(&mut self) {
+        unsafe { dealloc(self.ptr.as_ptr(), self.layout) }
+    }
+}
+
+// the buffer is uniquely owned, like a Box<[u8]>
+unsafe impl Send for AlignedBuf {}
+
+/// one block-aligned request: `buf` holds the whole span of blocks starting at `lba`
+struct Request {
+    lba: u64,
+    buf: AlignedBuf,
+}
+
+/// A BlockDevice on a file, with I/O submitted through io_uring.
+///
+/// Whole extents are read and written at once: each is split into requests of at most
+/// `MAX_IO_BLOCKS` blocks and up to `queue_depth` requests are submitted to the kernel together,
+/// so a multi-shard object costs a handful of system calls rather than a seek and write per block.
+pub struct UringBlockDevice {
+    capacity: u64,
+    max_lba: u64,
+    path: PathBuf,
+    file: File,
+    ring: IoUring,
+    options: UringOptions,
+    /// the ring failed with requests in flight
+    broken: bool,
+}
+
+impl UringBlockDevice {
+    pub fn new(capacity: u64, path: PathBuf) -> RResult<Self> {
+        Self::with_options(capacity, path, UringOptions::default())
+    }
+
+    pub fn with_options(capacity: u64, path: PathBuf, options: UringOptions) -> RResult<Self> {
+        if options.queue_depth == 0 {
+            return Err(RustorError::Config("io_uring queue depth must be at least 1".to_string()));
+        }
+        let mut open = OpenOptions::new();
+        open.read(true).write(true).create(true);
+        if options.direct {
+            open.custom_flags(libc::O_DIRECT);
+        }
+        let file = open.open(&path)?;
+        // as BasicBlockDevice, so that every block in range can be read back
+        if file.metadata()?.len() < capacity {
+            file.set_len(capacity)?;
+        }
+        let ring = IoUring::new(options.queue_depth)?;
+        debug!("opened {:?} with io_uring, {:?}", &path, &options);
+
+        Ok(Self { capacity, max_lba: capacity / BS4K as u64, path, file, ring, options, broken: false })
+    }
+
+    pub fn capacity(&self) -> u64 {
+        self.capacity
+    }
+
+    pub fn path(&self) -> &PathBuf {
+        &self.path
+    }
+
+    pub fn options(&self) -> UringOptions {
+        self.options
+    }
+
+    fn check_range(&self, lba: u64, span: u64) -> RResult<()> {
+        if lba + span > self.max_lba {
+            return Err(BlockDeviceError::OutOfRange { lba, span, max_lba: self.max_lba })?;
+        }
+        Ok(())
+    }
+
+    /// split `span` blocks at `lba` into requests no larger than MAX_IO_BLOCKS
+    fn requests(lba: u64, span: u64, requests: &mut Vec<Request>) {
+        let mut lba = lba;
+        let end = lba + span;
+        while lba < end {
+            let n = (end - lba).min(MAX_IO_BLOCKS as u64);
+            requests.push(Request { lba, buf: AlignedBuf::zeroed(n as usize) });
+            lba += n;
+        }
+    }
+
+    /// submit the requests `queue_depth` at a time and wait for each batch to complete. the
+    /// requests are handed back once the kernel is done with all of their buffers
+    fn submit(&mut self, mut requests: Vec<Request>, write: bool) -> RResult<Vec<Request>> {
+        if self.broken {
+            return Err(BlockDeviceError::Offline)?;
+        }
+        let fd = types::Fd(self.file.as_raw_fd());
+        let depth = self.options.queue_depth as usize;
+        for first in (0..requests.len()).step_by(depth) {
+            let end = (first + depth).min(requests.len());
+            let batch = &mut requests[first..end];
+            let mut failure: Option<BlockDeviceError> = None;
+            let mut pushed = 0;
+            for (i, req) in batch.iter_mut().enumerate() {
+                let offset = req.lba * BS4K as u64;
+                let len = req.buf.len() as u32;
+                let entry = if write {
+                    opcode::Write::new(fd, req.buf.as_slice().as_ptr(), len).offset(offset).build()
+                } else {
+                    opcode::Read::new(fd, req.buf.as_mut_slice().as_mut_ptr(), len).offset(offset).build()
+                };
+                // the kernel uses the buffer until the entry completes, and every entry pushed is
+                // waited for below before the buffers can be dropped
+                if unsafe { self.ring.submission().push(&entry.user_data(i as u64)) }.is_err() {
+                    failure = Some(BlockDeviceError::QueueFull);
+                    break;
+                }
+                pushed += 1;
+            }
+
+            if !self.wait(batch, pushed, write, &mut failure) {
+                // the ring can't tell us when the kernel is done with the buffers, so they can
+                // never be freed, and the device can't be trusted with any more requests
+                error!("io_uring failed with requests in flight, taking {:?} offline", &self.path);
+                self.broken = true;
+                std::mem::forget(requests);
+                return Err(failure.unwrap_or(BlockDeviceError::Offline))?;
+            }
+            if let Some(failure) = failure {
+                return Err(failure)?;
+            }
+        }
+        Ok(requests)
+    }
+
+    /// submit whatever is queued and reap completions until all `pushed` entries of `batch` have
+    /// completed, keeping the first failure. false if the ring itself failed so that they can't
+    /// be waited for
+    fn wait(&mut self, batch: &[Request], pushed: usize, write: bool, failure: &mut Option<BlockDeviceError>) -> bool {
+        let mut completed = 0;
+        while completed < pushed {
+            if let Err(e) = self.ring.submit_and_wait(pushed - completed) {
+                match e.raw_os_error() {
+                    // interrupted, or short of resources until completions are reaped: entries
+                    // may already be in flight, so go round again
+                    Some(libc::EINTR) | Some(libc::EAGAIN) | Some(libc::EBUSY) => (),
+                    _ => {
+                        failure.get_or_insert(e.into());
+                        return false;
+                    }
+                }
+            }
+            for cqe in self.ring.completion() {
+                completed += 1;
+                let req = &batch[cqe.user_data() as usize];
+                let result = cqe.result();
+                if result < 0 {
+                    failure.get_or_insert(io::Error::from_raw_os_error(-result).into());
+                } else if result as usize != req.buf.len() {
+                    let (lba, expected, done) = (req.lba, req.buf.len(), result as usize);
+                    failure.get_or_insert(if write {
+                        BlockDeviceError::ShortWrite { lba, expected, written: done }
+                    } else {
+                        // e.g. the device file was truncated after it was opened
+                        BlockDeviceError::ShortRead { lba, expected, read: done }
+                    });
+                }
+            }
+        }
+        true
+    }
+
+    /// write each (lba, data) extent, padding the last block of each with zeros
+    pub fn write_extents(&mut self, extents: &[(u64, &[u8])]) -> RResult<()> {
+        let mut requests = Vec::new();
+        for (lba, data) in extents.iter() {
+            let span = data.len().div_ceil(BS4K) as u64;
+            self.check_range(*lba, span)?;
+            let first = requests.len();
+            Self::requests(*lba, span, &mut requests);
+            for (req, chunk) in requests[first..].iter_mut().zip(data.chunks(MAX_IO_BLOCKS * BS4K)) {
+                req.buf.as_mut_slice()[..chunk.len()].copy_from_slice(chunk);
+            }
+        }
+        trace!("write {} extents as {} requests", extents.len(), requests.len());
+        self.submit(requests, true)?;
+        Ok(())
+    }
+
+    /// read each (lba, span) extent, appending the blocks to `data` in order
+    pub fn read_extents(&mut self, extents: &[(u64, u64)], data: &mut Vec<u8>) -> RResult<()> {
+        let mut requests = Vec::new();
+        for (lba, span) in extents.iter() {
+            self.check_range(*lba, *span)?;
+            Self::requests(*lba, *span, &mut requests);
+        }
+        trace!("read {} extents as {} requests", extents.len(), requests.len());
+        let requests = self.submit(requests, false)?;
+
+        for req in requests.iter() {
+            data.extend_from_slice(req.buf.as_slice());
+        }
+        Ok(())
+    }
+}
+
+impl BlockDevice for UringBlockDevice {
+    fn write_block(&mut self, lba: u64, data: &[u8]) -> RResult<()> {
+        self.write_extents(&[(lba, &data[..data.len().min(BS4K)])])
+    }
+
+    fn read_block(&mut self, lba: u64, data: &mut [u8; BS4K]) -> RResult<()> {
+        let mut block = Vec::with_capacity(BS4K);
+        self.read_extents(&[(lba, 1)], &mut block)?;
+        data.copy_from_slice(&block);
+        Ok(())
+    }
+
+    fn write_blocks(&mut self, lba: u64, data: &[u8]) -> RResult<()> {
+        self.write_extents(&[(lba, data)])
+    }
+
+    fn sync(&mut self) -> RResult<()> {
+        self.file.sync_data()?;
+        Ok(())
+    }
+
+    fn read_blocks(&mut self, lba: u64, data: &mut [u8]) -> RResult<()> {
+        let mut blocks = Vec::with_capacity(data.len().div_ceil(BS4K) * BS4K);
+        self.read_extents(&[(lba, data.len().div_ceil(BS4K) as u64)], &mut blocks)?;
+        data.copy_from_slice(&blocks[..data.len()]);
+        Ok(())
+    }
+}
+
+/// A BlockStore that hands every shard of an object to the io_uring device in one batch
+pub struct UringBlockStore {
+    pub device: UringBlockDevice,
+}
+
+impl UringBlockStore {
+    pub fn new(path: PathBuf, capacity: u64) -> RResult<Self> {
+        Ok(Self { device: UringBlockDevice::new(capacity, path)? })
+    }
+}
+
+impl BlockStore for UringBlockStore {
+    fn write(&mut self, data: &[u8], key: &ObjKey) -> RResult<()> {
+        debug!("write object {:?}, size {:?}", &key.uuid, &key.size);
+        // shards are filled in manifest order, as in SingleDeviceBlockStore
+        let mut extents = Vec::with_capacity(key.manifest.shards.len());
+        let mut rest = data;
+        for shard in key.manifest.shards.iter() {
+            if rest.is_empty() {
+                break;
+            }
+            let n = rest.len().min(shard.span as usize * BS4K);
+            extents.push((shard.lba, &rest[..n]));
+            rest = &rest[n..];
+        }
+        self.device.write_extents(&extents)
+    }
+
+    fn read(&mut self, data: &mut Vec<u8>, key: &ObjKey) -> RResult<()> {
+        debug!("read data: {:?}", &key);
+        let extents: Vec<(u64, u64)> = key.manifest.shards.iter().map(|s| (s.lba, s.span)).collect();
+        self.device.read_extents(&extents, data)
+    }
+
+    fn sync(&mut self) -> RResult<()> {
+        self.device.sync()
+    }
+
+    fn barrier(&mut self) -> RResult<()> {
+        self.device.barrier()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::object::{Manifest, ManifestLocation};
+    use crate::blockstore::BasicBlockDevice;
+
+    fn path(test: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("rustor-uring-{}-{}.bin", test, std::process::id()))
+    }
+
+    /// io_uring may be unavailable, e.g. disabled by a container's seccomp profile
+    fn device(test: &str, blocks: u64, options: UringOptions) -> Option<UringBlockDevice> {
+        match UringBlockDevice::with_options(blocks * BS4K as u64, path(test), options) {
+            Ok(dev) => Some(dev),
+            Err(e) => {
+                eprintln!("skipping io_uring test {}: {}", test, e);
+                let _ = std::fs::remove_file(path(test));
+                None
+            }
+        }
+    }
+
+    fn pattern(blocks: usize, seed: u8) -> Vec<u8> {
+        (0..blocks * BS4K).map(|i| (i / BS4K) as u8 ^ seed).collect()
+    }
+
+    #[test]
+    fn test_extents_round_trip() {
+        let options = UringOptions { queue_depth: 2, direct: false };
+        let mut dev = match device("extents", 1024, options) { Some(d) => d, None => return };
+
+        // longer than MAX_IO_BLOCKS, and more requests than the queue is deep
+        let a = pattern(600, 1);
+        let b = pattern(3, 2);
+        dev.write_extents(&[(10, &a), (700, &b[..2 * BS4K + 5])]).unwrap();
+
+        let mut data = Vec::new();
+        dev.read_extents(&[(10, 600), (700, 3)], &mut data).unwrap();
+        assert_eq!(&data[..a.len()], &a[..]);
+        assert_eq!(&data[a.len()..a.len() + 2 * BS4K + 5], &b[..2 * BS4K + 5]);
+        assert!(data[a.len() + 2 * BS4K + 5..].iter().all(|b| *b == 0));
+
+        assert!(dev.write_extents(&[(1020, &a[..5 * BS4K])]).is_err());
+        let _ = std::fs::remove_file(path("extents"));
+    }
+
+    #[test]
+    fn test_interoperates_with_basic_device() {
+        let mut dev = match device("basic", 16, UringOptions::default()) { Some(d) => d, None => return };
+        dev.write_block(3, &[9; BS4K]).unwrap();
+
+        let mut basic = BasicBlockDevice::new(16 * BS4K as u64, path("basic")).unwrap();
+        let mut block = [0; BS4K];
+        basic.read_block(3, &mut block).unwrap();
+        assert_eq!(block[..], [9; BS4K][..]);
+
+        basic.write_block(4, &[8; BS4K]).unwrap();
+        dev.read_block(4, &mut block).unwrap();
+        assert_eq!(block[..], [8; BS4K][..]);
+        let _ = std::fs::remove_file(path("basic"));
+    }
+
+    #[test]
+    fn test_direct() {
+        let options = UringOptions { queue_depth: 4, direct: true };
+        let mut dev = match device("direct", 16, options) { Some(d) => d, None => return };
+        let data = pattern(3, 4);
+        dev.write_extents(&[(5, &data[..2 * BS4K + 1])]).unwrap();
+
+        let mut read = Vec::new();
+        dev.read_extents(&[(5, 3)], &mut read).unwrap();
+        assert_eq!(&read[..2 * BS4K + 1], &data[..2 * BS4K + 1]);
+        let _ = std::fs::remove_file(path("direct"));
+    }
+
+    #[test]
+    fn test_block_store() {
+        let dev = match device("store", 64, UringOptions::default()) { Some(d) => d, None => return };
+        let mut store = UringBlockStore { device: dev };
+
+        let data = pattern(5, 3);
+        let key = ObjKey {
+            size: data.len() as u64,
+            manifest: Manifest { shards: vec![
+                ManifestLocation { blkdevid: None, lba: 40, span: 2 },
+                ManifestLocation { blkdevid: None, lba: 7, span: 3 },
+            ] },
+            ..Default::default()
+        };
+        store.write(&data, &key).unwrap();
+
+        let mut read = Vec::new();
+        store.read(&mut read, &key).unwrap();
+        assert_eq!(read, data);
+        let _ = std::fs::remove_file(path("store"));
+    }
+
+    #[test]
+    fn test_truncated_device_file() {
+        let mut dev = match device("truncated", 16, UringOptions::default()) { Some(d) => d, None => return };
+        // a new device file is extended to the full capacity
+        let mut block = [1; BS4K];
+        dev.read_block(15, &mut block).unwrap();
+        assert_eq!(block[..], [0; BS4K][..]);
+
+        let file = OpenOptions::new().write(true).open(path("truncated")).unwrap();
+        file.set_len(9 * BS4K as u64 + 100).unwrap();
+        match dev.read_block(9, &mut block).unwrap_err() {
+            RustorError::BlockDevice { error: BlockDeviceError::ShortRead { lba: 9, expected: BS4K, read: 100 }, .. } => (),
+            e => panic!("expected a short read: {}", e),
+        }
+        let _ = std::fs::remove_file(path("truncated"));
+    }
+}
@@ -0,0 +1,210 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+use uuid::Uuid;
+
+use crate::blockstore::BlockDeviceError;
+use crate::freelist::FreeListError;
+use crate::object::BlkDevID;
+
+/// Everything that can go wrong in rustor, by subsystem
+#[derive(Debug)]
+pub enum RustorError {
+    /// no object is stored under `uuid`
+    NotFound { uuid: Uuid },
+    SnapshotNotFound { id: Uuid },
+    /// no bucket of that name, or no object under `name` in it
+    NameNotFound { bucket: String, name: Option<String> },
+    /// allocating or releasing blocks failed, e.g. because the store is out of space
+    FreeList(FreeListError),
+    /// storing the object would take the bucket to `needed` bytes, over its quota
+    QuotaExceeded { bucket: String, quota: u64, needed: u64 },
+    /// keys could not be stored or loaded
+    KeyStore { uuid: Option<Uuid>, reason: String },
+    /// a block device failed. `blkdevid` is known when the device belongs to a multi-device store
+    BlockDevice { blkdevid: Option<BlkDevID>, error: BlockDeviceError },
+    /// stored data is missing or doesn't match what was recorded for it
+    Integrity { uuid: Option<Uuid>, lba: Option<u64>, reason: String },
+    /// the store was set up with an invalid configuration
+    Config(String),
+    /// a request was malformed, e.g. an unparseable uuid
+    InvalidRequest(String),
+    /// a byte range holds none of the object, e.g. it starts past the end
+    RangeNotSatisfiable { uuid: Uuid, offset: u64, size: u64 },
+    Io(io::Error),
+    /// a thread panicked while holding the named lock
+    Poisoned(&'static str),
+    /// anything without a variant of its own
+    Other(String),
+}
+
+impl RustorError {
+    /// attribute a block device error to the device `id`
+    pub fn with_device(self, id: BlkDevID) -> Self {
+        match self {
+            RustorError::BlockDevice { blkdevid: None, error } =>
+                RustorError::BlockDevice { blkdevid: Some(id), error },
+            other => other,
+        }
+    }
+
+    /// true if the request failed for lack of free space or quota rather than a fault
+    pub fn is_out_of_space(&self) -> bool {
+        matches!(self, RustorError::FreeList(FreeListError::AllocationError { .. })
+            | RustorError::FreeList(FreeListError::TooFragmented { .. })
+            | RustorError::QuotaExceeded { .. })
+    }
+
+    /// process exit status for command line tools, following sysexits.h
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            RustorError::InvalidRequest(_)
+                | RustorError::RangeNotSatisfiable { .. } => 64, // EX_USAGE
+            RustorError::Integrity { .. } => 65,            // EX_DATAERR
+            RustorError::NotFound { .. }
+                | RustorError::NameNotFound { .. }
+                | RustorError::SnapshotNotFound { .. } => 66, // EX_NOINPUT
+            _ if self.is_out_of_space() => 73,              // EX_CANTCREAT
+            RustorError::BlockDevice { .. }
+                | RustorError::KeyStore { .. }
+                | RustorError::Io(_) => 74,                 // EX_IOERR
+            RustorError::Config(_) => 78,                   // EX_CONFIG
+            _ => 70,                                        // EX_SOFTWARE
+        }
+    }
+
+    /// HTTP status code for a request that failed with this error
+    pub fn http_status(&self) -> u16 {
+        match self {
+            RustorError::InvalidRequest(_) => 400,
+            RustorError::NotFound { .. }
+                | RustorError::NameNotFound { .. }
+                | RustorError::SnapshotNotFound { .. } => 404,
+            RustorError::RangeNotSatisfiable { .. } => 416,
+            _ if self.is_out_of_space() => 507,
+            RustorError::BlockDevice { .. } | RustorError::Io(_) => 503,
+            _ => 500,
+        }
+    }
+}
+
+impl fmt::Display for RustorError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RustorError::NotFound { uuid } => write!(f, "No object with uuid {}", uuid),
+            RustorError::SnapshotNotFound { id } => write!(f, "No snapshot with id {}", id),
+            RustorError::NameNotFound { bucket, name: Some(name) } =>
+                write!(f, "No object named {} in bucket {}", name, bucket),
+            RustorError::NameNotFound { bucket, name: None } => write!(f, "No bucket named {}", bucket),
+            RustorError::FreeList(e) => write!(f, "{}", e),
+            RustorError::QuotaExceeded { bucket, quota, needed } =>
+                write!(f, "Bucket {} would hold {} bytes, over its quota of {}", bucket, needed, quota),
+            RustorError::KeyStore { uuid: Some(uuid), reason } =>
+                write!(f, "Keystore error for {}: {}", uuid, reason),
+            RustorError::KeyStore { uuid: None, reason } => write!(f, "Keystore error: {}", reason),
+            RustorError::BlockDevice { blkdevid: Some(id), error } =>
+                write!(f, "Block device {}: {}", id, error),
+            RustorError::BlockDevice { blkdevid: None, error } => write!(f, "{}", error),
+            RustorError::Integrity { uuid, lba, reason } => {
+                write!(f, "Integrity error")?;
+                if let Some(uuid) = uuid {
+                    write!(f, " in {}", uuid)?;
+                }
+                if let Some(lba) = lba {
+                    write!(f, " at lba {}", lba)?;
+                }
+                write!(f, ": {}", reason)
+            }
+            RustorError::Config(reason) => write!(f, "Configuration error: {}", reason),
+            RustorError::InvalidRequest(reason) => write!(f, "Invalid request: {}", reason),
+            RustorError::RangeNotSatisfiable { uuid, offset, size } =>
+                write!(f, "Range at offset {} is outside {} ({} bytes)", offset, uuid, size),
+            RustorError::Io(e) => write!(f, "I/O error: {}", e),
+            RustorError::Poisoned(lock) => write!(f, "{} lock poisoned by a panicked thread", lock),
+            RustorError::Other(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+impl Error for RustorError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            RustorError::FreeList(e) => Some(e),
+            RustorError::BlockDevice { error, .. } => Some(error),
+            RustorError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// for reporting through std::io traits such as Read
+impl From<RustorError> for io::Error {
+    fn from(e: RustorError) -> Self {
+        match e {
+            RustorError::Io(e) => e,
+            e => io::Error::other(e),
+        }
+    }
+}
+
+impl From<FreeListError> for RustorError {
+    fn from(e: FreeListError) -> Self {
+        RustorError::FreeList(e)
+    }
+}
+
+impl From<BlockDeviceError> for RustorError {
+    fn from(error: BlockDeviceError) -> Self {
+        RustorError::BlockDevice { blkdevid: None, error }
+    }
+}
+
+impl From<io::Error> for RustorError {
+    fn from(e: io::Error) -> Self {
+        RustorError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for RustorError {
+    fn from(e: serde_json::Error) -> Self {
+        RustorError::KeyStore { uuid: None, reason: e.to_string() }
+    }
+}
+
+impl From<uuid::Error> for RustorError {
+    fn from(e: uuid::Error) -> Self {
+        RustorError::InvalidRequest(e.to_string())
+    }
+}
+
+impl From<tokio::task::JoinError> for RustorError {
+    fn from(e: tokio::task::JoinError) -> Self {
+        RustorError::Other(e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_codes() {
+        let full: RustorError = FreeListError::AllocationError { span: 3 }.into();
+        assert_eq!((full.exit_code(), full.http_status()), (73, 507));
+
+        let missing = RustorError::NotFound { uuid: Uuid::nil() };
+        assert_eq!((missing.exit_code(), missing.http_status()), (66, 404));
+
+        let id = Uuid::new_v4();
+        let device = RustorError::from(BlockDeviceError::DeviceNotOpen).with_device(id);
+        assert!(matches!(device, RustorError::BlockDevice { blkdevid: Some(d), .. } if d == id));
+        assert_eq!((device.exit_code(), device.http_status()), (74, 503));
+
+        let bad = Uuid::parse_str("not a uuid").map_err(RustorError::from).unwrap_err();
+        assert_eq!((bad.exit_code(), bad.http_status()), (64, 400));
+
+        let poisoned = RustorError::Poisoned("keystore");
+        assert_eq!((poisoned.exit_code(), poisoned.http_status()), (70, 500));
+    }
+}
@@ -1,16 +1,17 @@
-use std::cmp;
-use std::mem;
+use std::cmp::{self, Ordering};
+use std::ops::{Bound, RangeBounds};
 
 // TODO: use configuration options to handle duplicates
 //      (a) put with duplicate key replaces old data
 //      (b) put with duplicate key appends data to list in node
 //      (c) put with duplicate key keeps data versions (?)
 //      (d) ???
+// for now, (a): put with a duplicate key replaces the old data
 
 type OptBoxNode<K,D> = Option<Box<Node<K,D>>>;
 
 #[derive(Default)]
-struct Node<K, D> {
+pub struct Node<K, D> {
     key: K,
     data: D,
 
@@ -21,7 +22,7 @@ struct Node<K, D> {
 }
 
 use std::fmt;
-impl<K,D> fmt::Debug for Node<K,D> 
+impl<K,D> fmt::Debug for Node<K,D>
 where K: fmt::Debug, D: fmt::Debug {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let left = match &self.left {
@@ -36,254 +37,183 @@ where K: fmt::Debug, D: fmt::Debug {
     }
 }
 
-impl<K,D> Node<K,D> 
-where K: PartialEq + PartialOrd,
-      D: PartialEq + PartialOrd
-{
+fn height<K,D>(node: &OptBoxNode<K,D>) -> isize {
+    node.as_ref().map_or(0, |n| n.height)
+}
+
+impl<K: Ord, D> Node<K,D> {
     pub fn new(key: K, data: D) -> Self {
         Self { key, data, height: 1, left: None, right: None }
     }
 
-    fn height(&mut self) -> isize {
-        // cache result from potentially expensive drill-down
-        // TODO: when does this need to be invalidated?
-        if self.height != 0 {
-            return self.height;
-        }
+    pub fn key(&self) -> &K { &self.key }
+    pub fn data(&self) -> &D { &self.data }
 
-        self.update_height()
-    }
-
-    fn update_height(&mut self) -> isize {
-        let mut left_height = 0;
-        let mut right_height = 0;
-        if let Some(node) = &mut self.left {
-            left_height = node.height() + 1;
-        } 
-        if let Some(node) = &mut self.right {
-            right_height = node.height() + 1;
-        } 
-
-        self.height = cmp::max(left_height, right_height);
-        self.height
+    /// recompute this node's height from its children. children must already be up to date
+    fn update_height(&mut self) {
+        self.height = cmp::max(height(&self.left), height(&self.right)) + 1;
     }
 
     /// return the difference in height between the right tree and the left tree
     /// a positive value indicates that the right tree is deeper
     /// a negative value indicates that the left tree is deeper
-    fn balance_factor(&mut self) -> isize {
-        let mut left_height = 0;
-        let mut right_height = 0;
-        if let Some(node) = &mut self.left {
-            left_height = node.height();
-        }
-        if let Some(node) = &mut self.right {
-            right_height = node.height();
-        }
-
-        right_height - left_height
+    fn balance_factor(&self) -> isize {
+        height(&self.right) - height(&self.left)
     }
 
     /// recursively search for the given key
-    pub fn find(&self, key: K) -> Option<&D> {
-        if key == self.key {
-            return Some(&self.data);
-        } else if key < self.key {
-            if let Some(node) = &self.left {
-                return node.find(key);
-            } else { 
-                return None; 
-            }
-        } else { // key > self.key
-            if let Some(node) = &self.right {
-                return node.find(key);
-            } else { 
-                return None; 
-            }
+    pub fn find(&self, key: &K) -> Option<&D> {
+        match key.cmp(&self.key) {
+            Ordering::Equal => Some(&self.data),
+            Ordering::Less => self.left.as_ref().and_then(|n| n.find(key)),
+            Ordering::Greater => self.right.as_ref().and_then(|n| n.find(key)),
         }
     }
 
-    /// insert a new key/data pair
-    pub fn put(&mut self, key: K, data: D) -> Result<(), String> {
-        self.height = 0;
-
-        if key == self.key {
-            self.data = data;
-        } else if key < self.key {
-            // key is less than self.key
-            if let Some(node) = &mut self.left {
-                // if we have a left node, recurse to it
-                node.put(key, data)?;
-                self.height = node.height() + 1;
-            } else {
-                // otherwise, create it
-                self.left = Some(Box::new(Node::new(key, data,)));
-            }
-        } else {
-            // key is greater than self.key
-            if let Some(node) = &mut self.right {
-                // if we have a right node, recurse to it
-                node.put(key, data)?;
-                self.height = node.height() + 1;
-            } else {
-                // otherwise, create it
-                self.right = Some(Box::new(Node::new(key, data,)));
-            }
+    fn find_mut(&mut self, key: &K) -> Option<&mut D> {
+        match key.cmp(&self.key) {
+            Ordering::Equal => Some(&mut self.data),
+            Ordering::Less => self.left.as_mut().and_then(|n| n.find_mut(key)),
+            Ordering::Greater => self.right.as_mut().and_then(|n| n.find_mut(key)),
         }
-
-        self.rebalance();
-
-        Ok(())
-    }
-
-    fn left_heavy(&mut self) -> bool {
-        if self.balance_factor() < -1 { return true; }
-        else { return false; }
-    }
-    fn right_heavy(&mut self) -> bool {
-        if self.balance_factor() > 1 { return true; }
-        else { return false; }
     }
 
     /*             self                  left
-     *            /                     /    \
-     *           left    =>     left_left    self
-     *          /
-     * left_left
-     *
-     *
+     *            /    \                /    \
+     *         left     c     =>       a      self
+     *        /    \                         /    \
+     *       a      b                       b      c
      */
-    fn rotate_right(&mut self) -> bool {
-        let mut left: Box<Node<K,D>> = self.left.take().expect("no left child");
-        let left_left: Box<Node<K,D>> = left.left.take().expect("no left-left child");
-
-        // now self.left is None and left.left is None, but we have the data
-        // need to:
-        //   swap the keys and data between self and left
-        //   make self.left = &left_left
-        //   make self.right = &left
-        mem::swap(&mut self.key, &mut left.key);
-        mem::swap(&mut self.data, &mut left.data);
-        self.left = Some(left_left);
-        self.right = Some(left);
-
-        true
-
-        /*
-        let mut new_root: Box<Node<K,D>> = self.left.unwrap();
-        new_root.right = Some(rotate_root);
-        self.left = None;
-        self.right = None;
-        */
-    }
-
-    /* self                            right
-     *     \                          /     \
-     *      right    =>           self      right_right
-     *          \ 
-     *           right_right
-     *  move self to self.right.left and return self.right
-     */
-    fn rotate_left(&mut self) {
-        if self.right.is_none() { return; };
-
-        let right_right: Box<Node<K,D>> = self.right.as_mut().unwrap().right.take().unwrap();
-        let mut right: Box<Node<K,D>> = self.right.take().unwrap();
-
-        // now self.right is None and right.right is None, but we have the data
-        // need to:
-        //   swap the keys and data between self and right
-        //   make self.right = &right_right
-        //   make self.right = &right
-        mem::swap(&mut self.key, &mut right.key);
-        mem::swap(&mut self.data, &mut right.data);
-
-        self.right = Some(right_right);
-        self.right = Some(right);
-        /*
-        let new_root = self.right.unwrap();
-        new_root.left = Some(Box::new(*self));
-        self.left = None;
-        self.right = None;
-
-        &new_root
-        */
-    }
-
-    /*     c             b
-     *    /             / \
-     *   a    =>       a   c 
-     *    \ 
-     *     b
-     *
-     */
-    /*
-    fn rotate_left_right(&mut self) -> &Self {
-        let mut new_root = self.left.unwrap().right.unwrap();
-        new_root.left = self.left; 
-        new_root.right = Some(Box::new(*self));
-
-        self.left = None;
-        self.right = None;
-        new_root.left.unwrap().left = None;
-        new_root.left.unwrap().right = None;
-         
-        &new_root
-    }
-    */
-
-    /*  a             b \           / \
-     *    c    =>   a   c 
-     *   /  
-     *  b   
-     *
-     */
-    /*
-    fn rotate_right_left(&mut self) -> &Self {
-        let mut new_root = self.right.unwrap().left.unwrap();
-        new_root.left = Some(Box::new(*self));
-        new_root.right = self.right;
-
-        self.left = None;
-        self.right = None;
-        new_root.right.unwrap().left = None;
-        new_root.right.unwrap().right = None;
+    fn rotate_right(mut self: Box<Self>) -> Box<Self> {
+        let mut left = self.left.take().expect("no left child");
+        self.left = left.right.take();
+        self.update_height();
+        left.right = Some(self);
+        left.update_height();
+        left
+    }
 
-        &new_root
+    /*     self                            right
+     *    /    \                          /     \
+     *   a      right      =>         self       c
+     *         /     \               /    \
+     *        b       c             a      b
+     */
+    fn rotate_left(mut self: Box<Self>) -> Box<Self> {
+        let mut right = self.right.take().expect("no right child");
+        self.right = right.left.take();
+        self.update_height();
+        right.left = Some(self);
+        right.update_height();
+        right
     }
-    */
 
     /* right rotation after a node is inserted in the left subtree of a left subtree
      * left rotation after a node is inserted in the right subtree of a right subtree
      * left-right rotation after a node is inserted as the right subtree of a left subtree
      * right-left rotation after a node is inserted as the left subtree of a right subtree
      */
-
-    fn rebalance(&mut self) {
+    fn rebalance(mut self: Box<Self>) -> Box<Self> {
+        self.update_height();
         match self.balance_factor() {
             -2 => {
                 // the sub-tree rooted at this node is left-heavy
-                let left = self.left.as_mut().unwrap();
-                if left.balance_factor() > 0 {
-                    left.rotate_right();
-                }
+                let left = self.left.take().unwrap();
+                self.left = Some(if left.balance_factor() > 0 { left.rotate_left() } else { left });
+                self.rotate_right()
             }
             2 => {
                 // the sub-tree rooted at this node is right-heavy
-                let right = self.right.as_mut().unwrap();
-                if right.balance_factor() < 0 {
-                    right.rotate_left();
-                }
+                let right = self.right.take().unwrap();
+                self.right = Some(if right.balance_factor() < 0 { right.rotate_right() } else { right });
+                self.rotate_left()
+            }
+            _ => self
+        }
+    }
+
+    /// insert a new key/data pair, returning the data it replaced
+    fn put(mut self: Box<Self>, key: K, data: D) -> (Box<Self>, Option<D>) {
+        let old = match key.cmp(&self.key) {
+            Ordering::Equal => {
+                let old = std::mem::replace(&mut self.data, data);
+                return (self, Some(old));
+            }
+            Ordering::Less => {
+                let (node, old) = Node::put_opt(self.left.take(), key, data);
+                self.left = Some(node);
+                old
+            }
+            Ordering::Greater => {
+                let (node, old) = Node::put_opt(self.right.take(), key, data);
+                self.right = Some(node);
+                old
             }
-            _ => {}
         };
+        (self.rebalance(), old)
     }
 
+    fn put_opt(node: OptBoxNode<K,D>, key: K, data: D) -> (Box<Self>, Option<D>) {
+        match node {
+            Some(n) => n.put(key, data),
+            None => (Box::new(Node::new(key, data)), None),
+        }
+    }
+
+    /// detach the smallest node of this sub-tree. returns (remaining sub-tree, smallest node)
+    fn take_min(mut self: Box<Self>) -> (OptBoxNode<K,D>, Box<Self>) {
+        match self.left.take() {
+            None => (self.right.take(), self),
+            Some(left) => {
+                let (rest, min) = left.take_min();
+                self.left = rest;
+                (Some(self.rebalance()), min)
+            }
+        }
+    }
+
+    /// remove `key` from this sub-tree. returns (remaining sub-tree, removed data)
+    fn remove(mut self: Box<Self>, key: &K) -> (OptBoxNode<K,D>, Option<D>) {
+        match key.cmp(&self.key) {
+            Ordering::Less => {
+                let (rest, old) = match self.left.take() {
+                    Some(n) => n.remove(key),
+                    None => (None, None),
+                };
+                self.left = rest;
+                (Some(self.rebalance()), old)
+            }
+            Ordering::Greater => {
+                let (rest, old) = match self.right.take() {
+                    Some(n) => n.remove(key),
+                    None => (None, None),
+                };
+                self.right = rest;
+                (Some(self.rebalance()), old)
+            }
+            Ordering::Equal => {
+                let node = *self;
+                let replacement = match (node.left, node.right) {
+                    (None, None) => None,
+                    (Some(l), None) => Some(l),
+                    (None, Some(r)) => Some(r),
+                    (Some(l), Some(r)) => {
+                        // replace this node with its in-order successor
+                        let (rest, mut succ) = r.take_min();
+                        succ.left = Some(l);
+                        succ.right = rest;
+                        Some(succ.rebalance())
+                    }
+                };
+                (replacement, Some(node.data))
+            }
+        }
+    }
 }
 
-impl<K,D> PartialEq for Node<K,D> 
-where K: PartialEq + PartialOrd,
-      D: PartialEq + PartialOrd
+impl<K,D> PartialEq for Node<K,D>
+where K: PartialEq,
+      D: PartialEq
 {
     fn eq(&self, other: &Self) -> bool {
         (self.key == other.key) && (self.data == other.data)
@@ -291,173 +221,230 @@ where K: PartialEq + PartialOrd,
 }
 
 
-
-struct NodeIter<'a, K, D> {
+/// in-order iterator over the nodes of a tree, optionally stopping at an upper bound
+pub struct NodeIter<'a, K, D> {
     stack: Vec<&'a Node<K,D>>,
-    curr: &'a OptBoxNode<K,D>,
+    end: Bound<K>,
 }
 
-impl<'a, K,D> Iterator for NodeIter<'a, K,D> 
-where K: PartialEq + PartialOrd,
-      D: PartialEq + PartialOrd
-{
+impl<'a, K: Ord, D> NodeIter<'a, K,D> {
+    /// push `node` and its chain of left children
+    fn push_left(&mut self, mut node: &'a OptBoxNode<K,D>) {
+        while let Some(n) = node {
+            self.stack.push(n);
+            node = &n.left;
+        }
+    }
+
+    /// seed the stack so that the first node returned is the smallest key satisfying `start`
+    fn seek(&mut self, mut node: &'a OptBoxNode<K,D>, start: Bound<&K>) {
+        while let Some(n) = node {
+            let go_left = match start {
+                Bound::Included(k) => n.key >= *k,
+                Bound::Excluded(k) => n.key > *k,
+                Bound::Unbounded => true,
+            };
+            if go_left {
+                self.stack.push(n);
+                node = &n.left;
+            } else {
+                node = &n.right;
+            }
+        }
+    }
+}
+
+impl<'a, K: Ord, D> Iterator for NodeIter<'a, K,D> {
     type Item = &'a Node<K,D>;
 
     /// iterate over the elements in sorted order
     fn next(&mut self) -> Option<Self::Item> {
-        loop {
-            match *self.curr {
-                // if we're at a node 
-                Some (ref node) => {
-                    // if this node has a left child, save this node on the stack and drill down
-                    if node.left.is_some() {
-                        self.stack.push(&node);
-                        self.curr = &node.left;
-                        continue;
-                    } 
-                    // if this node has a right child, put it on the stack and return this node
-                    if node.right.is_some() {
-                        self.curr = &node.right;
-                        return Some(node);
-                    }
-                    // return this node and on the next iteration return the one from the top of
-                    // the stack
-                    // this is kind of like .take() ourself
-                    self.curr = &None;
-                    return Some(node);
-                }
-
-                // we're at a leaf. pop the top node off the stack.
-                // if it has a right child, put that on the stack
-                // return the popped top node
-                None =>  {
-                    match self.stack.pop() {
-                        Some(node) => {
-                            self.curr = &node.right;
-                            return Some(node);
-                        }
-                        // end of iteration
-                        None => return None
-                    }
-                }
-            }
+        let node = self.stack.pop()?;
+        let in_range = match &self.end {
+            Bound::Included(k) => node.key <= *k,
+            Bound::Excluded(k) => node.key < *k,
+            Bound::Unbounded => true,
+        };
+        if !in_range {
+            self.stack.clear();
+            return None;
         }
+        self.push_left(&node.right);
+        Some(node)
     }
 }
 
 
+/// A self-balancing binary search tree
+pub struct AVLTree<K,D> {
+    root: OptBoxNode<K,D>,
+    len: usize,
+}
 
-struct AVLTree<K,D> {
-    root: OptBoxNode<K,D>
+impl<K: Ord, D> Default for AVLTree<K,D> {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
-impl <'a, K,D> AVLTree<K,D> 
-where K: PartialEq + PartialOrd + Copy + Clone,
-      D: PartialEq + PartialOrd + Copy + Clone
-{
+impl<K: Ord + fmt::Debug, D: fmt::Debug> fmt::Debug for AVLTree<K,D> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_map().entries(self.iter().map(|n| (&n.key, &n.data))).finish()
+    }
+}
+
+impl<K: Ord, D> AVLTree<K,D> {
     pub fn new() -> Self {
         Self {
-            root: None
+            root: None,
+            len: 0,
         }
     }
-    pub fn iter(&'a self) -> NodeIter<'a, K, D> {
-        NodeIter {
-            stack: Vec::new(),
-            curr: &self.root
-        }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn height(&self) -> isize {
+        height(&self.root)
+    }
+
+    pub fn iter(&self) -> NodeIter<'_, K, D> {
+        let mut iter = NodeIter { stack: Vec::new(), end: Bound::Unbounded };
+        iter.push_left(&self.root);
+        iter
+    }
+
+    /// iterate in order over the nodes whose keys fall within `range`
+    pub fn range<R: RangeBounds<K>>(&self, range: R) -> NodeIter<'_, K, D>
+    where K: Clone {
+        let end = match range.end_bound() {
+            Bound::Included(k) => Bound::Included(k.clone()),
+            Bound::Excluded(k) => Bound::Excluded(k.clone()),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        let mut iter = NodeIter { stack: Vec::new(), end };
+        iter.seek(&self.root, range.start_bound());
+        iter
     }
+
+    /// insert `key`, replacing any existing data. returns true if the key was not already present
     pub fn put(&mut self, key: K, data: D) -> bool {
-        if let Some(ref mut boxnode) = &mut self.root {
-            boxnode.put(key, data);
-        } else {
-            self.root = Some(Box::new(Node::new(key, data)));
+        let (root, old) = Node::put_opt(self.root.take(), key, data);
+        self.root = Some(root);
+        if old.is_none() { self.len += 1; }
+        old.is_none()
+    }
+
+    pub fn get(&self, key: &K) -> Option<&D> {
+        self.root.as_ref().and_then(|n| n.find(key))
+    }
+
+    pub fn get_mut(&mut self, key: &K) -> Option<&mut D> {
+        self.root.as_mut().and_then(|n| n.find_mut(key))
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// remove `key` from the tree, returning its data if it was present
+    pub fn remove(&mut self, key: &K) -> Option<D> {
+        let (root, old) = match self.root.take() {
+            Some(n) => n.remove(key),
+            None => (None, None),
+        };
+        self.root = root;
+        if old.is_some() { self.len -= 1; }
+        old
+    }
+
+    /// return the smallest node with a key >= `key`
+    pub fn lower_bound(&self, key: &K) -> Option<&Node<K,D>> {
+        let mut cursor = &self.root;
+        let mut best = None;
+        while let Some(node) = cursor {
+            if node.key >= *key {
+                best = Some(node.as_ref());
+                cursor = &node.left;
+            } else {
+                cursor = &node.right;
+            }
         }
-        return true;
+        best
     }
 
-    /// return a vector of key/value tuples
-    pub fn items(&self) -> Vec<(K,D)> {
-        let mut iter = self.iter();
-        let mut v = Vec::new();
-        loop {
-            if let Some(node) = iter.next() {
-                v.push((node.key, node.data))
+    /// return the largest node with a key <= `key`
+    pub fn floor(&self, key: &K) -> Option<&Node<K,D>> {
+        let mut cursor = &self.root;
+        let mut best = None;
+        while let Some(node) = cursor {
+            if node.key <= *key {
+                best = Some(node.as_ref());
+                cursor = &node.right;
             } else {
-                return v;
+                cursor = &node.left;
             }
         }
+        best
+    }
+
+    /// return a vector of key/value tuples
+    pub fn items(&self) -> Vec<(K,D)>
+    where K: Clone, D: Clone {
+        self.iter().map(|node| (node.key.clone(), node.data.clone())).collect()
     }
 }
 
-impl<K,D> From <&Vec<(K,D)>> for AVLTree<K,D> 
-where K: PartialEq + PartialOrd + Copy + Clone,
-      D: PartialEq + PartialOrd + Copy + Clone,
+impl<K,D> From <&Vec<(K,D)>> for AVLTree<K,D>
+where K: Ord + Clone,
+      D: Clone,
 {
     fn from(nodes: &Vec<(K,D)>) -> AVLTree<K,D>{
         let mut tree = AVLTree::new();
         for node in nodes {
-            tree.put(node.0, node.1);
+            tree.put(node.0.clone(), node.1.clone());
         }
         tree
     }
 }
 
 use std::iter::{Iterator, FromIterator, IntoIterator};
-impl <K,D> FromIterator <Node<K,D>> for AVLTree<K,D> 
-where K: PartialEq + PartialOrd + Copy + Clone,
-      D: PartialEq + PartialOrd + Copy + Clone, 
-{
-    fn from_iter<I: IntoIterator<Item = Node<K,D>>>(iter: I) -> Self {
+impl <K: Ord, D> FromIterator <(K,D)> for AVLTree<K,D> {
+    fn from_iter<I: IntoIterator<Item = (K,D)>>(iter: I) -> Self {
         let mut tree = Self::new();
-        for i in iter {
-            tree.put(i.key, i.data);
+        for (key, data) in iter {
+            tree.put(key, data);
         }
         tree
     }
 }
 
-/*
-impl<K,D> IntoIterator for AVLTree<K,D> 
-where K: PartialEq + PartialOrd,
-      D: PartialEq + PartialOrd
-{
-    type Item = (K,D);
-    type IntoIter = std::vec::IntoIter<Self::Item>;
-
-    fn into_iter(self) -> Self::IntoIter {
-    }
-}
-*/
-
-/*
-use proptest::prelude::*;
-
-fn arb_node(max_qty: usize) -> impl Strategy<Value = Node<isize, String>> {
-    (any::<isize>(), "[a-z]*")
-        .prop_map(|(key, data)| Node::new(key, data))
-        .boxed()
-}
-
-prop_compose! {
-    fn arb_node2(_d: isize) 
-        (key in 0..100isize, data in "[a-z]*")
-            -> Node<isize, String> {
-                Node::new(key, data) 
-        }
-}
-
-
-proptest! {
-    #[test]
-    fn test_put_inorder_set(nodes in arb_node2(0)) {
-        println!("{:?}", nodes);
-    }
-}
-*/
-
 #[cfg(test)]
 mod tests {
     use super::*;
+    use proptest::prelude::*;
+    use std::collections::BTreeMap;
+
+    /// check ordering, cached heights and the AVL balance invariant. returns the sub-tree height
+    fn check<K: Ord, D>(node: &OptBoxNode<K,D>) -> isize {
+        match node {
+            None => 0,
+            Some(n) => {
+                if let Some(l) = &n.left { assert!(l.key < n.key); }
+                if let Some(r) = &n.right { assert!(r.key > n.key); }
+                let lh = check(&n.left);
+                let rh = check(&n.right);
+                assert!((rh - lh).abs() <= 1, "unbalanced node");
+                assert_eq!(n.height, cmp::max(lh, rh) + 1);
+                n.height
+            }
+        }
+    }
 
     #[test]
     fn test_put_inorder_set() {
@@ -469,6 +456,7 @@ mod tests {
         let tree = AVLTree::from(&data);
 
         assert!(tree.items().eq(&data));
+        assert_eq!(tree.height(), 2);
     }
 
     #[test]
@@ -479,17 +467,57 @@ mod tests {
             (0, "asdf"),
         ];
 
-        let mut a = Node::new(data[0].0, data[0].1);
-        let mut b = Node::new(data[1].0, data[1].1); 
-        let mut c = Node::new(data[2].0, data[2].1);
+        let tree = AVLTree::from(&data);
+        let root = tree.root.as_ref().unwrap();
+        assert_eq!(root.key, 1);
+        assert_eq!(root.left.as_ref().unwrap().key, 0);
+        assert_eq!(root.right.as_ref().unwrap().key, 2);
+        check(&tree.root);
+    }
 
-        let bref = &mut b;
-        let aref = &mut a;
+    #[test]
+    fn test_remove() {
+        let mut tree: AVLTree<i32, i32> = (0..100).map(|i| (i, i * 10)).collect();
+        for i in (0..100).step_by(3) {
+            assert_eq!(tree.remove(&i), Some(i * 10));
+            check(&tree.root);
+        }
+        assert_eq!(tree.remove(&0), None);
+        assert_eq!(tree.len(), 66);
+        assert!(tree.iter().all(|n| n.key % 3 != 0));
+    }
 
-        b.left = Some(Box::new(c));
-        a.left = Some(Box::new(b));
+    #[test]
+    fn test_bounds_and_range() {
+        let tree: AVLTree<u64, ()> = [10, 20, 30, 40].iter().map(|k| (*k, ())).collect();
+
+        assert_eq!(tree.lower_bound(&15).map(|n| n.key), Some(20));
+        assert_eq!(tree.lower_bound(&20).map(|n| n.key), Some(20));
+        assert!(tree.lower_bound(&41).is_none());
+        assert_eq!(tree.floor(&15).map(|n| n.key), Some(10));
+        assert!(tree.floor(&5).is_none());
+
+        let keys: Vec<u64> = tree.range(15..=30).map(|n| n.key).collect();
+        assert_eq!(keys, vec![20, 30]);
+        let keys: Vec<u64> = tree.range(..30).map(|n| n.key).collect();
+        assert_eq!(keys, vec![10, 20]);
+    }
 
-        assert_eq!(*bref.left.unwrap(), c);
-        assert_eq!(*aref.left.unwrap(), b)
+    proptest! {
+        #[test]
+        fn test_matches_btreemap(ops in prop::collection::vec((any::<bool>(), 0..64u32), 0..200)) {
+            let mut tree = AVLTree::new();
+            let mut model = BTreeMap::new();
+            for (insert, key) in ops {
+                if insert {
+                    prop_assert_eq!(tree.put(key, key), model.insert(key, key).is_none());
+                } else {
+                    prop_assert_eq!(tree.remove(&key), model.remove(&key));
+                }
+                check(&tree.root);
+            }
+            prop_assert_eq!(tree.len(), model.len());
+            prop_assert_eq!(tree.items(), model.into_iter().collect::<Vec<_>>());
+        }
     }
 }
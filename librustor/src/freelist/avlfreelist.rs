@@ -0,0 +1,114 @@
+use std::ops::Bound;
+
+use super::avl::AVLTree;
+use super::extents::{ExtentFreeList, ExtentIndex};
+
+/// Free extents in two AVL trees: by (span, address) for O(log n) best-fit allocation and by
+/// address for O(log n) coalescing of neighbors on release
+#[derive(Debug, Default)]
+pub struct AvlExtents {
+    /// (span, address) -> ()
+    by_size: AVLTree<(u64, u64), ()>,
+    /// address -> span
+    by_addr: AVLTree<u64, u64>,
+}
+
+impl ExtentIndex for AvlExtents {
+    fn insert(&mut self, address: u64, span: u64) {
+        self.by_size.put((span, address), ());
+        self.by_addr.put(address, span);
+    }
+
+    fn remove(&mut self, address: u64, span: u64) {
+        self.by_size.remove(&(span, address));
+        self.by_addr.remove(&address);
+    }
+
+    fn best_fit(&self, span: u64) -> Option<(u64, u64)> {
+        self.by_size.lower_bound(&(span, 0)).map(|node| (node.key().1, node.key().0))
+    }
+
+    fn before(&self, lba: u64) -> Option<(u64, u64)> {
+        self.by_addr.floor(&lba).map(|node| (*node.key(), *node.data()))
+    }
+
+    fn after(&self, lba: u64) -> Option<(u64, u64)> {
+        self.by_addr.range((Bound::Excluded(lba), Bound::Unbounded)).next().map(|node| (*node.key(), *node.data()))
+    }
+
+    fn extents(&self) -> Vec<(u64, u64)> {
+        self.by_addr.iter().map(|node| (*node.key(), *node.data())).collect()
+    }
+
+    fn len(&self) -> usize {
+        self.by_addr.len()
+    }
+}
+
+/// A best-fit free list of extents in AVL trees
+pub type AvlFreeList = ExtentFreeList<AvlExtents>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::BS4K;
+    use crate::freelist::FreeList;
+    use crate::freelist::tests::{check_free_list, shard};
+    use crate::object::ManifestLocation;
+
+    #[test]
+    fn test_best_fit() {
+        let mut list = AvlFreeList::new(100);
+        list.take(10, 0).unwrap();
+        list.take(5, 13).unwrap();
+        list.take(10, 20).unwrap();
+        // free: 3 at 10, 2 at 18, 70 at 30
+        assert_eq!(list.extents(), 3);
+
+        let m = list.allocate(2 * BS4K as u64).unwrap();
+        assert_eq!(m.shards[0], ManifestLocation { blkdevid: None, lba: 18, span: 2 });
+
+        let m = list.allocate(BS4K as u64 + 1).unwrap();
+        assert_eq!(m.shards[0], ManifestLocation { blkdevid: None, lba: 10, span: 2 });
+
+        assert_eq!(list.free_blocks(), 71);
+        assert!(list.allocate(72 * BS4K as u64).is_err());
+    }
+
+    #[test]
+    fn test_release_coalesces() {
+        let mut list = AvlFreeList::new(30);
+        let a = list.allocate(10 * BS4K as u64).unwrap();
+        let b = list.allocate(10 * BS4K as u64).unwrap();
+        let c = list.allocate(10 * BS4K as u64).unwrap();
+        assert_eq!(list.extents(), 0);
+
+        list.release(&a).unwrap();
+        list.release(&c).unwrap();
+        assert_eq!(list.extents(), 2);
+
+        list.release(&b).unwrap();
+        assert_eq!(list.extents(), 1);
+        assert_eq!(list.free_blocks(), 30);
+
+        // the whole device is contiguous again
+        assert!(list.allocate(30 * BS4K as u64).is_ok());
+    }
+
+    #[test]
+    fn test_release_errors() {
+        let mut list = AvlFreeList::new(30);
+        list.take(10, 10).unwrap();
+
+        assert!(list.release(&shard(5, 6)).is_err());
+        assert!(list.release(&shard(19, 2)).is_err());
+        assert!(list.release(&shard(25, 10)).is_err());
+        assert!(list.release(&shard(10, 10)).is_ok());
+        assert!(list.release(&shard(10, 1)).is_err());
+    }
+
+    #[test]
+    fn test_free_space_conserved() {
+        check_free_list(AvlFreeList::new);
+    }
+}
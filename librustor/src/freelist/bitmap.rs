@@ -1,4 +1,5 @@
 
+use std::cmp;
 use std::mem;
 
 const WORDLEN: usize = mem::size_of::<usize>();
@@ -11,8 +12,17 @@ fn index(bit: usize) -> usize {
     bit & (WORDLEN_BITS - 1)
 }
 
+/// mask selecting bits `lo..hi` of a word, where 0 <= lo < hi <= WORDLEN_BITS
+fn mask(lo: usize, hi: usize) -> usize {
+    let upper = if hi == WORDLEN_BITS { usize::MAX } else { (1 << hi) - 1 };
+    upper & !((1 << lo) - 1)
+}
+
+/// A fixed-size bitmap with a one-level summary: bit `w` of the summary is set when word `w` has
+/// any bit set, so searches for set bits skip whole empty words a summary word at a time
 pub struct Bitmap {
     words: Vec<usize>,
+    summary: Vec<usize>,
     size: usize,
 }
 
@@ -20,8 +30,6 @@ pub trait BitOps {
     fn get(&self, i: usize) -> bool;
     fn set(&mut self, i: usize);
     fn clear(&mut self, i: usize);
-    fn invert(&mut self);
-    fn toggle(&mut self, i: usize);
     fn set_value(&mut self, i: usize, val: bool);
 }
 
@@ -33,7 +41,8 @@ impl Bitmap {
         }
         Self { 
             words: vec![0; words],
-            size: size,
+            summary: vec![0; words.div_ceil(WORDLEN_BITS)],
+            size,
         }
     }
 
@@ -42,26 +51,141 @@ impl Bitmap {
     }
 
     pub fn set_all(&mut self) {
-        for w in self.words.iter_mut() {
-            *w = usize::MAX;
-        }
+        self.set_range(0, self.size);
+    }
+
+    pub fn clear_all(&mut self) {
+        self.clear_range(0, self.size);
     }
 
-    pub fn zeros(&self) -> BitmapIterVal {
+    pub fn zeros(&self) -> BitmapIterVal<'_> {
         BitmapIterVal::new(self, false)
     }
 
-    pub fn ones(&self) -> BitmapIterVal {
+    pub fn ones(&self) -> BitmapIterVal<'_> {
         BitmapIterVal::new(self, true)
     }
 
-    pub fn zeros_mut(&mut self) -> BitmapIterVal {
+    pub fn zeros_mut(&mut self) -> BitmapIterVal<'_> {
         BitmapIterVal::new(self, false)
     }
 
-    pub fn ones_mut(&mut self) -> BitmapIterVal {
+    pub fn ones_mut(&mut self) -> BitmapIterVal<'_> {
         BitmapIterVal::new(self, true)
     }
+
+    /// refresh the summary bit for word `w`
+    #[inline]
+    fn update_summary(&mut self, w: usize) {
+        self.summary[word(w)].set_value(index(w), self.words[w] != 0);
+    }
+
+    /// apply `f` to each word overlapping bits `start..start+len` with the mask of bits in range
+    fn for_range<F: FnMut(&mut usize, usize)>(&mut self, start: usize, len: usize, mut f: F) {
+        assert!(start + len <= self.size, "bit range {}..{} out of bounds (max {})", start, start+len, self.size);
+        let end = start + len;
+        let mut bit = start;
+        while bit < end {
+            let w = word(bit);
+            let hi = cmp::min(end - w * WORDLEN_BITS, WORDLEN_BITS);
+            f(&mut self.words[w], mask(index(bit), hi));
+            self.update_summary(w);
+            bit = (w + 1) * WORDLEN_BITS;
+        }
+    }
+
+    /// set bits `start..start+len`
+    pub fn set_range(&mut self, start: usize, len: usize) {
+        self.for_range(start, len, |w, m| *w |= m);
+    }
+
+    /// clear bits `start..start+len`
+    pub fn clear_range(&mut self, start: usize, len: usize) {
+        self.for_range(start, len, |w, m| *w &= !m);
+    }
+
+    /// number of set bits in the whole bitmap
+    pub fn count_ones(&self) -> usize {
+        self.words.iter().map(|w| w.count_ones() as usize).sum()
+    }
+
+    /// number of set bits in `start..start+len`
+    pub fn count_ones_range(&self, start: usize, len: usize) -> usize {
+        assert!(start + len <= self.size, "bit range {}..{} out of bounds (max {})", start, start+len, self.size);
+        let end = start + len;
+        let mut count = 0;
+        let mut bit = start;
+        while bit < end {
+            let w = word(bit);
+            let hi = cmp::min(end - w * WORDLEN_BITS, WORDLEN_BITS);
+            count += (self.words[w] & mask(index(bit), hi)).count_ones() as usize;
+            bit = (w + 1) * WORDLEN_BITS;
+        }
+        count
+    }
+
+    /// index of the first set bit at or after `from`
+    pub fn find_first_set(&self, from: usize) -> Option<usize> {
+        if from >= self.size { return None; }
+
+        // the rest of the word containing `from`
+        let w = word(from);
+        let bits = self.words[w] & mask(index(from), WORDLEN_BITS);
+        if bits != 0 {
+            return Some(w * WORDLEN_BITS + bits.trailing_zeros() as usize);
+        }
+
+        // then use the summary to jump to the next word with anything set
+        let next = w + 1;
+        if next >= self.words.len() { return None; }
+        let mut s = word(next);
+        let mut candidates = self.summary[s] & mask(index(next), WORDLEN_BITS);
+        loop {
+            if candidates != 0 {
+                let w = s * WORDLEN_BITS + candidates.trailing_zeros() as usize;
+                // bits past the end of the bitmap are never set
+                return Some(w * WORDLEN_BITS + self.words[w].trailing_zeros() as usize);
+            }
+            s += 1;
+            if s >= self.summary.len() { return None; }
+            candidates = self.summary[s];
+        }
+    }
+
+    /// index of the first clear bit in `from..limit`, or `limit` if they are all set
+    pub fn find_first_clear(&self, from: usize, limit: usize) -> usize {
+        let limit = cmp::min(limit, self.size);
+        let mut bit = from;
+        while bit < limit {
+            let w = word(bit);
+            let clear = !self.words[w] & mask(index(bit), WORDLEN_BITS);
+            if clear != 0 {
+                return cmp::min(w * WORDLEN_BITS + clear.trailing_zeros() as usize, limit);
+            }
+            bit = (w + 1) * WORDLEN_BITS;
+        }
+        limit
+    }
+
+    /// the first set bit at or after `from` and the length of the run of set bits starting
+    /// there, counting no further than `max_len`
+    pub fn next_run(&self, from: usize, max_len: usize) -> Option<(usize, usize)> {
+        let start = self.find_first_set(from)?;
+        let end = self.find_first_clear(start, start.saturating_add(max_len));
+        Some((start, end - start))
+    }
+
+    /// start of the first run of at least `n` consecutive set bits at or after `from`
+    pub fn find_run(&self, n: usize, from: usize) -> Option<usize> {
+        let mut from = from;
+        loop {
+            let (start, len) = self.next_run(from, n)?;
+            if len >= n {
+                return Some(start);
+            }
+            from = start + len;
+        }
+    }
 }
 
 impl BitOps for Bitmap {
@@ -74,21 +198,13 @@ impl BitOps for Bitmap {
     fn set(&mut self, i: usize) {
         //if i >= self.size { return Err(()); }
         self.words[word(i)] |= 1 << index(i);
+        self.update_summary(word(i));
     }
     #[inline]
     fn clear(&mut self, i: usize) {
         //if i >= self.size { return Err(()); }
-        self.words[word(i)] &= !(1 << index(i))
-    }
-    #[inline]
-    fn invert(&mut self) {
-        for w in self.words.iter_mut() {
-            *w = !*w;
-        }
-    }
-    #[inline]
-    fn toggle(&mut self, i: usize) {
-        self.words[word(i)] ^= 1 << i;
+        self.words[word(i)] &= !(1 << index(i));
+        self.update_summary(word(i));
     }
     #[inline]
     fn set_value(&mut self, i: usize, val: bool) {
@@ -102,7 +218,7 @@ impl<'a> IntoIterator for &'a Bitmap {
     type IntoIter = BitmapIter<'a>;
 
     fn into_iter(self) -> Self::IntoIter {
-        BitmapIter::new(&self)
+        BitmapIter::new(self)
     }
 }
 
@@ -148,16 +264,14 @@ impl<'a> Iterator for BitmapIterVal<'a> {
     type Item = usize;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.index == self.bitmap.size {
-            return None;
+        while self.index < self.bitmap.size {
+            let idx = self.index;
+            self.index += 1;
+            if self.bitmap.get(idx) == self.value {
+                return Some(idx);
+            }
         }
-        let bit = self.bitmap.get(self.index);
-
-        let idx = self.index;
-        self.index += 1;
-
-        return if bit == self.value { Some(idx) }
-            else { self.next() }
+        None
     }
 }
 
@@ -177,16 +291,6 @@ impl BitOps for usize {
         *self &= !(1 << i);
     }
 
-    #[inline]
-    fn invert(&mut self) {
-        *self = !*self;
-    }
-
-    #[inline]
-    fn toggle(&mut self, i: usize) {
-        *self ^= 1 << i;
-    }
-
     #[inline]
     fn set_value(&mut self, i: usize, val: bool) {
         if val { self.set(i); }
@@ -262,5 +366,49 @@ mod test {
 
         assert_eq!(ones, words)
     }
+
+    #[test]
+    fn test_ranges() {
+        let size = 1000;
+        let mut b = Bitmap::new(size);
+
+        b.set_range(10, 200);
+        assert_eq!(b.count_ones(), 200);
+        assert_eq!(b.count_ones_range(0, 100), 90);
+        assert!(!b.get(9) && b.get(10) && b.get(209) && !b.get(210));
+
+        b.clear_range(60, 70);
+        assert_eq!(b.count_ones(), 130);
+        assert!(b.get(59) && !b.get(60) && !b.get(129) && b.get(130));
+
+        b.set_all();
+        assert_eq!(b.count_ones(), size);
+        b.clear_all();
+        assert_eq!(b.count_ones(), 0);
+        assert!(b.summary.iter().all(|s| *s == 0));
+    }
+
+    #[test]
+    fn test_find() {
+        let size = 100_000;
+        let mut b = Bitmap::new(size);
+        assert_eq!(b.find_first_set(0), None);
+
+        b.set(70_000);
+        b.set_range(80_000, 5);
+        b.set_range(90_000, 500);
+        assert_eq!(b.find_first_set(0), Some(70_000));
+        assert_eq!(b.find_first_set(70_001), Some(80_000));
+        assert_eq!(b.find_first_set(90_500), None);
+
+        assert_eq!(b.find_first_clear(90_000, size), 90_500);
+        assert_eq!(b.find_first_clear(90_000, 90_100), 90_100);
+        assert_eq!(b.next_run(75_000, 100), Some((80_000, 5)));
+
+        assert_eq!(b.find_run(1, 0), Some(70_000));
+        assert_eq!(b.find_run(5, 0), Some(80_000));
+        assert_eq!(b.find_run(6, 0), Some(90_000));
+        assert_eq!(b.find_run(501, 0), None);
+    }
 }
 
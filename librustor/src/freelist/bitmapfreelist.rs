@@ -1,23 +1,40 @@
-use super::{FreeList, bitmap::*};
+use super::{FreeList, FreeListError, AllocationPolicy, FreeListStats, bitmap::*};
 use crate::object::{Manifest, ManifestLocation};
 use crate::RResult;
 
 
 pub struct BitmapFreelist {
     bitmap: Bitmap,
-    free: usize
+    free: usize,
+    policy: AllocationPolicy,
+    /// where the next next-fit search starts
+    cursor: usize,
 }
 
 impl BitmapFreelist {
     pub fn new(size: usize) -> Self {
+        Self::with_policy(size, AllocationPolicy::default())
+    }
+
+    pub fn with_policy(size: usize, policy: AllocationPolicy) -> Self {
         let mut b = BitmapFreelist {
             bitmap: Bitmap::new(size),
-            free: size
+            free: size,
+            policy,
+            cursor: 0,
         };
         b.bitmap.set_all();
         b
     }
 
+    pub fn policy(&self) -> AllocationPolicy {
+        self.policy
+    }
+
+    pub fn set_policy(&mut self, policy: AllocationPolicy) {
+        self.policy = policy;
+    }
+
     pub fn capacity(&self) -> usize {
         self.bitmap.capacity()
     }
@@ -25,59 +42,93 @@ impl BitmapFreelist {
     pub fn free(&self) -> usize {
         self.free
     }
+
+    /// iterate over the free runs as (start, length), in block order
+    fn runs(&self) -> impl Iterator<Item=(usize, usize)> + '_ {
+        let mut from = 0;
+        std::iter::from_fn(move || {
+            let run = self.bitmap.next_run(from, usize::MAX)?;
+            from = run.0 + run.1;
+            Some(run)
+        })
+    }
+
+    pub fn stats(&self) -> FreeListStats {
+        let mut stats = FreeListStats { capacity: self.capacity() as u64, ..Default::default() };
+        for (_, len) in self.runs() {
+            stats.add_extent(len as u64);
+        }
+        stats
+    }
+
+    /// gather free runs starting at `from` until `span` blocks are covered, wrapping around to
+    /// the start of the bitmap if needed
+    fn gather(&self, span: usize, from: usize) -> Option<Vec<ManifestLocation>> {
+        let mut shards = Vec::new();
+        let mut remaining = span;
+        let mut pos = from;
+        let mut wrapped = false;
+
+        while remaining > 0 {
+            // after wrapping, stop at `from`: everything past it has already been considered
+            let limit = if wrapped { from - pos } else { remaining };
+            match self.bitmap.next_run(pos, limit.min(remaining)) {
+                Some((start, len)) if !wrapped || start < from => {
+                    let len = if wrapped { len.min(from - start) } else { len };
+                    shards.push(ManifestLocation { blkdevid: None, lba: start as u64, span: len as u64 });
+                    remaining -= len;
+                    pos = start + len;
+                }
+                _ if !wrapped && from > 0 => {
+                    wrapped = true;
+                    pos = 0;
+                }
+                _ => return None,
+            }
+        }
+        Some(shards)
+    }
+
+    /// the smallest free run that holds `span` blocks
+    fn best_fit(&self, span: usize) -> Option<usize> {
+        self.runs()
+            .filter(|(_, len)| *len >= span)
+            .min_by_key(|(_, len)| *len)
+            .map(|(start, _)| start)
+    }
+
+    fn place(&self, span: usize) -> Option<Vec<ManifestLocation>> {
+        let whole = |start: usize| vec![ManifestLocation { blkdevid: None, lba: start as u64, span: span as u64 }];
+        match self.policy {
+            AllocationPolicy::FirstFit => self.gather(span, 0),
+            AllocationPolicy::NextFit => self.gather(span, self.cursor % self.capacity().max(1)),
+            AllocationPolicy::BestFit => self.best_fit(span).map(whole).or_else(|| self.gather(span, 0)),
+            AllocationPolicy::ContiguousOnly => self.bitmap.find_run(span, 0).map(whole),
+        }
+    }
 }
 
 impl FreeList for BitmapFreelist {
-    /// Naively allocates the first free blocks it finds, combining adjacent blocks
-    /// to a single ManifestLocation
+    /// Allocates `span` blocks according to the list's `AllocationPolicy`, combining adjacent
+    /// blocks to a single ManifestLocation
     fn allocate(&mut self, span:u64) -> RResult<Manifest> {
-        let mut m = Manifest::new();
-
-        let mut prev_block = 0;
-        let mut base_of_span = 0;
-        let mut found_span = 0;
-        let mut allocated = 0;
-
-        // iterate over the free blocks and decide which ones to use
-        for block in self.bitmap.ones().collect::<Vec<usize>>() {
-            if block == prev_block + 1 || block == prev_block {
-                // in a contiguous span of free blocks, or this is the first block
-                found_span += 1;
-
-                // claim it
-                self.bitmap.clear(block);
-
-                // return if we've found enough
-                if allocated + found_span >= span {
-                    m.shards.push(ManifestLocation { 
-                        blkdevid: None, lba: base_of_span, span: found_span
-                    });
-                    self.free -= found_span as usize;
-                    return Ok(m);
-                }
-            } else {
-                // this block is the beginning of a new contiguous block
-                
-                // allocate the space we found
-                m.shards.push(ManifestLocation {
-                    blkdevid: None, span: found_span, lba: base_of_span
-                });
-                self.free -= found_span as usize;
-                allocated += found_span;
-
-                if allocated >= span {
-                    return Ok(m);
-                }
+        if span as usize > self.free {
+            return Err(FreeListError::AllocationError { span })?;
+        }
 
-                // new span:
-                self.bitmap.clear(block);
-                base_of_span = block as u64;
-                found_span = 1;
+        let shards = match self.place(span as usize) {
+            Some(shards) => shards,
+            None => return Err(FreeListError::AllocationError { span })?,
+        };
 
-            }
-            prev_block = block;
+        for loc in shards.iter() {
+            self.bitmap.clear_range(loc.lba as usize, loc.span as usize);
         }
-        Err(format!("Could not allocate {} blocks", &span))?
+        if let Some(last) = shards.last() {
+            self.cursor = (last.lba + last.span) as usize;
+        }
+        self.free -= span as usize;
+        Ok(Manifest { shards })
     }
     fn release(&mut self, manifest: &Manifest) -> RResult<()> {
         for loc in manifest.shards.iter() {
@@ -88,27 +139,40 @@ impl FreeList for BitmapFreelist {
 
     fn take(&mut self, span:u64, lba: u64) -> RResult<()> {
         if lba + span > self.capacity() as u64 {
-            return Err(format!("Tried to take out of bounds: {} (max {})", lba+span, self.bitmap.capacity()))?;
+            return Err(FreeListError::OutOfRange { lba, span, capacity: self.capacity() as u64 })?;
         }
-        for i in lba .. lba + span {
-            self.bitmap.clear(i as usize);
+        if self.bitmap.count_ones_range(lba as usize, span as usize) != span as usize {
+            return Err(FreeListError::NotFree { lba, span })?;
         }
+        self.bitmap.clear_range(lba as usize, span as usize);
+        self.free -= span as usize;
         Ok(())
     }
     fn free(&mut self, span:u64, lba: u64) -> RResult<()> {
         if lba + span > self.capacity() as u64 {
-            return Err(format!("Tried to release out of bounds: {} at {} (max {})", lba, span, self.bitmap.capacity()))?;
+            return Err(FreeListError::OutOfRange { lba, span, capacity: self.capacity() as u64 })?;
         }
-        for i in lba .. lba + span {
-            self.bitmap.set(i as usize);
+        if self.bitmap.count_ones_range(lba as usize, span as usize) != 0 {
+            return Err(FreeListError::ReleaseOfFreeArea { lba, span })?;
         }
+        self.bitmap.set_range(lba as usize, span as usize);
+        self.free += span as usize;
         Ok(())
     }
-}
 
+    fn capacity(&self) -> u64 {
+        self.bitmap.capacity() as u64
+    }
+
+    fn free_areas(&self) -> Vec<(u64, u64)> {
+        self.runs().map(|(start, len)| (start as u64, len as u64)).collect()
+    }
+}
 
+#[cfg(test)]
 mod tests {
     use super::*;
+    use crate::freelist::tests::error;
 
     #[test]
     fn test_allocate() {
@@ -179,5 +243,113 @@ mod tests {
 
     }
 
+    #[test]
+    fn test_allocate_fragments() {
+        let size = 1024;
+        let mut list = BitmapFreelist::new(size);
+        list.take(10, 5).unwrap();
+        list.take(10, 20).unwrap();
+        assert_eq!(list.free(), size - 20);
+
+        let manifest = list.allocate(15).unwrap();
+        assert_eq!(manifest.shards, vec![
+            ManifestLocation { blkdevid: None, lba: 0, span: 5 },
+            ManifestLocation { blkdevid: None, lba: 15, span: 5 },
+            ManifestLocation { blkdevid: None, lba: 30, span: 5 },
+        ]);
+        assert_eq!(list.free(), size - 35);
+
+        assert!(list.allocate(size as u64).is_err());
+        assert_eq!(list.free(), size - 35);
+    }
+
+    /// 100 blocks with free runs of 10 at 0, 5 at 20, 30 at 40 and 8 at 90
+    fn fragmented(policy: AllocationPolicy) -> BitmapFreelist {
+        let mut list = BitmapFreelist::with_policy(100, policy);
+        for (lba, span) in [(10, 10), (25, 15), (70, 20), (98, 2)].iter() {
+            list.take(*span, *lba).unwrap();
+        }
+        list
+    }
+
+    fn loc(lba: u64, span: u64) -> ManifestLocation {
+        ManifestLocation { blkdevid: None, lba, span }
+    }
+
+    #[test]
+    fn test_policy_first_fit() {
+        let mut list = fragmented(AllocationPolicy::FirstFit);
+        assert_eq!(list.allocate(12).unwrap().shards, vec![loc(0, 10), loc(20, 2)]);
+    }
+
+    #[test]
+    fn test_policy_next_fit() {
+        let mut list = fragmented(AllocationPolicy::NextFit);
+        assert_eq!(list.allocate(4).unwrap().shards, vec![loc(0, 4)]);
+        assert_eq!(list.allocate(4).unwrap().shards, vec![loc(4, 4)]);
+        assert_eq!(list.allocate(36).unwrap().shards, vec![loc(8, 2), loc(20, 5), loc(40, 29)]);
+        FreeList::free(&mut list, 4, 0).unwrap();
+        assert_eq!(list.allocate(6).unwrap().shards, vec![loc(69, 1), loc(90, 5)]);
+        // wraps back around to the start after reaching the end
+        assert_eq!(list.allocate(6).unwrap().shards, vec![loc(95, 3), loc(0, 3)]);
+    }
+
+    #[test]
+    fn test_policy_best_fit() {
+        let mut list = fragmented(AllocationPolicy::BestFit);
+        assert_eq!(list.allocate(5).unwrap().shards, vec![loc(20, 5)]);
+        assert_eq!(list.allocate(7).unwrap().shards, vec![loc(90, 7)]);
+        assert_eq!(list.allocate(10).unwrap().shards, vec![loc(0, 10)]);
+        // nothing holds 31 blocks, so fall back to first-fit
+        assert_eq!(list.allocate(31).unwrap().shards, vec![loc(40, 30), loc(97, 1)]);
+    }
+
+    #[test]
+    fn test_policy_contiguous_only() {
+        let mut list = fragmented(AllocationPolicy::ContiguousOnly);
+        assert_eq!(list.allocate(12).unwrap().shards, vec![loc(40, 12)]);
+        assert!(list.allocate(31).is_err());
+        assert_eq!(list.free(), 41);
+    }
+
+    #[test]
+    fn test_stats() {
+        let list = fragmented(AllocationPolicy::FirstFit);
+        let stats = list.stats();
+        assert_eq!(stats.capacity, 100);
+        assert_eq!(stats.free, 53);
+        assert_eq!(stats.extents, 4);
+        assert_eq!(stats.largest_free_run, 30);
+        // runs of 10, 5, 30 and 8 blocks
+        assert_eq!(stats.histogram, vec![0, 0, 1, 2, 1]);
+        assert!((stats.fragmentation() - 23.0 / 53.0).abs() < 1e-9);
+
+        let stats = BitmapFreelist::new(100).stats();
+        assert_eq!(stats.fragmentation(), 0.0);
+    }
+
+    #[test]
+    fn test_double_free() {
+        let size = 1024;
+        let mut list = BitmapFreelist::new(size);
+        let manifest = list.allocate(10).unwrap();
+
+        assert!(list.release(&manifest).is_ok());
+        assert!(list.release(&manifest).is_err());
+        assert!(FreeList::free(&mut list, 10, size as u64).is_err());
+        assert_eq!(list.free(), size);
+    }
+
+    #[test]
+    fn test_take_twice() {
+        let mut list = BitmapFreelist::new(100);
+        list.take(10, 20).unwrap();
+        assert_eq!(error(list.take(10, 20)), FreeListError::NotFree { lba: 20, span: 10 });
+        // overlapping only partly fails too, and takes nothing
+        assert_eq!(error(list.take(10, 25)), FreeListError::NotFree { lba: 25, span: 10 });
+        assert_eq!(list.free(), 90);
+        list.take(5, 30).unwrap();
+        assert_eq!(list.free(), 85);
+    }
 }
 
@@ -1,9 +1,110 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::Bound;
 
-struct TreeNode<T> {
-    left: Option(&TreeNode),
-    right: Option(&TreeNode),
-    data: T
+use super::extents::{ExtentFreeList, ExtentIndex};
+
+/// Free extents in two B-Trees sharing data -- one ordered by location, the other ordered by size.
+#[derive(Debug, Default)]
+pub struct BTreeExtents {
+    /// address -> span
+    by_addr: BTreeMap<u64, u64>,
+    /// (span, address)
+    by_size: BTreeSet<(u64, u64)>,
+}
+
+impl ExtentIndex for BTreeExtents {
+    fn insert(&mut self, address: u64, span: u64) {
+        self.by_addr.insert(address, span);
+        self.by_size.insert((span, address));
+    }
+
+    fn remove(&mut self, address: u64, span: u64) {
+        self.by_addr.remove(&address);
+        self.by_size.remove(&(span, address));
+    }
+
+    fn best_fit(&self, span: u64) -> Option<(u64, u64)> {
+        self.by_size.range((span, 0)..).next().map(|(s, a)| (*a, *s))
+    }
+
+    fn before(&self, lba: u64) -> Option<(u64, u64)> {
+        self.by_addr.range(..=lba).next_back().map(|(a, s)| (*a, *s))
+    }
+
+    fn after(&self, lba: u64) -> Option<(u64, u64)> {
+        self.by_addr.range((Bound::Excluded(lba), Bound::Unbounded)).next().map(|(a, s)| (*a, *s))
+    }
+
+    fn extents(&self) -> Vec<(u64, u64)> {
+        self.by_addr.iter().map(|(a, s)| (*a, *s)).collect()
+    }
+
+    fn len(&self) -> usize {
+        self.by_addr.len()
+    }
 }
 
+/// A best-fit free list of extents in B-Trees.
+///
+/// Allocation is best-fit from the size index; releases consult the location index to merge the
+/// freed area with its neighbors, so free space never fragments into adjacent extents.
+pub type BTreeFreeList = ExtentFreeList<BTreeExtents>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::BS4K;
+    use crate::freelist::{FreeList, FreeListError};
+    use crate::freelist::tests::{check_free_list, error};
+    use crate::object::ManifestLocation;
 
+    #[test]
+    fn test_allocate() {
+        let mut list = BTreeFreeList::new(1000);
 
+        let m = list.allocate(10 * BS4K as u64).unwrap();
+        assert_eq!(m.shards[0], ManifestLocation { blkdevid: None, lba: 0, span: 10 });
+        let m = list.allocate(1).unwrap();
+        assert_eq!(m.shards[0], ManifestLocation { blkdevid: None, lba: 10, span: 1 });
+
+        assert_eq!(list.free_areas(), vec![(11, 989)]);
+        assert!(list.allocate(990 * BS4K as u64).is_err());
+    }
+
+    #[test]
+    fn test_take_splits() {
+        let mut list = BTreeFreeList::new(100);
+        list.take(10, 40).unwrap();
+        assert_eq!(list.free_areas(), vec![(0, 40), (50, 50)]);
+
+        list.take(40, 0).unwrap();
+        list.take(1, 99).unwrap();
+        assert_eq!(list.free_areas(), vec![(50, 49)]);
+
+        // overlaps allocated space
+        assert!(list.take(10, 45).is_err());
+        assert!(list.take(2, 98).is_err());
+    }
+
+    #[test]
+    fn test_free_coalesces() {
+        let mut list = BTreeFreeList::new(100);
+        list.take(100, 0).unwrap();
+
+        list.free(10, 0).unwrap();
+        list.free(10, 20).unwrap();
+        assert_eq!(list.extents(), 2);
+        list.free(10, 10).unwrap();
+        assert_eq!(list.free_areas(), vec![(0, 30)]);
+
+        assert!(list.free(5, 25).is_err());
+        assert!(list.free(1, 100).is_err());
+        assert_eq!(error(list.free(2, u64::MAX)), FreeListError::OutOfRange { lba: u64::MAX, span: 2, capacity: 100 });
+        assert_eq!(error(list.take(2, u64::MAX)), FreeListError::NotFree { lba: u64::MAX, span: 2 });
+    }
+
+    #[test]
+    fn test_free_space_conserved() {
+        check_free_list(BTreeFreeList::new);
+    }
+}
@@ -0,0 +1,365 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::{Mutex, MutexGuard};
+use std::thread;
+
+use super::{FreeList, FreeListError};
+use crate::object::{Manifest, ManifestLocation};
+use crate::{RResult, RustorError};
+
+#[allow(unused_imports)]
+use log::{trace, debug, info, warn, error};
+
+/// A free list that can be shared between threads.
+///
+/// The LBA space is partitioned into allocation groups, each an independent free list behind its
+/// own lock. A thread starts allocating from the group its id hashes to and only moves on to
+/// the others when that group is full, so threads mostly work in separate groups without
+/// contending. An allocation is satisfied from a single group where possible, and split across
+/// groups only when no one group has room for it.
+pub struct ConcurrentFreeList<F> {
+    groups: Vec<Mutex<F>>,
+    group_size: u64,
+    capacity: u64,
+}
+
+impl<F: FreeList> ConcurrentFreeList<F> {
+    /// split `capacity` blocks into `n_groups` groups, creating the free list for each with
+    /// `make(group span)`. the last group absorbs any remainder.
+    ///
+    /// an allocation larger than a group, `capacity / n_groups` blocks, always spans several groups,
+    /// which serializes it against every other allocation while it is made
+    pub fn new<M>(capacity: u64, n_groups: usize, make: M) -> Self
+    where M: Fn(u64) -> F {
+        let n_groups = n_groups.clamp(1, capacity.max(1) as usize);
+        let group_size = capacity / n_groups as u64;
+        let groups = (0..n_groups)
+            .map(|g| {
+                let span = if g == n_groups - 1 { capacity - group_size * g as u64 } else { group_size };
+                Mutex::new(make(span))
+            })
+            .collect();
+        Self { groups, group_size, capacity }
+    }
+
+    pub fn capacity(&self) -> u64 {
+        self.capacity
+    }
+
+    pub fn groups(&self) -> usize {
+        self.groups.len()
+    }
+
+    fn lock(&self, g: usize) -> MutexGuard<'_, F> {
+        // a panic while holding a group lock can't leave the list half-updated in a way later
+        // callers could make worse, so keep going with whatever state it left
+        self.groups[g].lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn base(&self, g: usize) -> u64 {
+        self.group_size * g as u64
+    }
+
+    fn group_of(&self, lba: u64) -> usize {
+        ((lba / self.group_size.max(1)) as usize).min(self.groups.len() - 1)
+    }
+
+    /// the group this thread prefers to allocate from
+    fn home_group(&self) -> usize {
+        let mut hasher = DefaultHasher::new();
+        thread::current().id().hash(&mut hasher);
+        (hasher.finish() % self.groups.len() as u64) as usize
+    }
+
+    /// split `span` blocks at `lba` at group boundaries, as (group, group-relative lba, span)
+    fn split(&self, span: u64, lba: u64) -> Vec<(usize, u64, u64)> {
+        let mut pieces = Vec::new();
+        let mut lba = lba;
+        let end = lba + span;
+        while lba < end {
+            let g = self.group_of(lba);
+            let group_end = if g == self.groups.len() - 1 { end } else { self.base(g + 1) };
+            let n = end.min(group_end) - lba;
+            pieces.push((g, lba - self.base(g), n));
+            lba += n;
+        }
+        pieces
+    }
+
+    /// allocate through a shared reference, trying this thread's home group first
+    pub fn allocate_shared(&self, span: u64) -> RResult<Manifest> {
+        let home = self.home_group();
+        let mut last_err = None;
+        for i in 0..self.groups.len() {
+            let g = (home + i) % self.groups.len();
+            match self.lock(g).allocate(span) {
+                Ok(mut m) => {
+                    let base = self.base(g);
+                    for loc in m.shards.iter_mut() {
+                        loc.lba += base;
+                    }
+                    trace!("allocated {:?} from group {}", &m, g);
+                    return Ok(m);
+                }
+                Err(e) => last_err = Some(e),
+            }
+        }
+        match last_err.unwrap() {
+            // every group reports the allocation's size in blocks
+            RustorError::FreeList(FreeListError::AllocationError { span: blocks }) => self.allocate_across(span, blocks),
+            e => Err(e),
+        }
+    }
+
+    /// allocate `blocks` blocks from the free areas of every group, starting with this thread's
+    /// home group. `span` is the size as requested, for the error
+    fn allocate_across(&self, span: u64, blocks: u64) -> RResult<Manifest> {
+        // hold every group, always locked in order, so the free space can't change underneath
+        let mut groups: Vec<_> = (0..self.groups.len()).map(|g| self.lock(g)).collect();
+        let free: u64 = groups.iter().flat_map(|g| g.free_areas()).map(|(_, n)| n).sum();
+        if free < blocks {
+            return Err(FreeListError::AllocationError { span })?;
+        }
+
+        let home = self.home_group();
+        let mut m = Manifest::new();
+        let mut remaining = blocks;
+        for i in 0..groups.len() {
+            let g = (home + i) % groups.len();
+            for (lba, n) in groups[g].free_areas() {
+                if remaining == 0 { break; }
+                let n = n.min(remaining);
+                groups[g].take(n, lba)?;
+                m.shards.push(ManifestLocation { blkdevid: None, lba: self.base(g) + lba, span: n });
+                remaining -= n;
+            }
+        }
+        trace!("allocated {:?} across groups", &m);
+        Ok(m)
+    }
+
+    /// apply `op` to each group's piece of `span` blocks at `lba`. if one fails, `undo` is applied
+    /// to the pieces already done, so the list is left as it was
+    fn each_piece<O, U>(&self, span: u64, lba: u64, op: O, undo: U) -> RResult<()>
+    where O: Fn(&mut F, u64, u64) -> RResult<()>, U: Fn(&mut F, u64, u64) -> RResult<()> {
+        if lba + span > self.capacity {
+            return Err(FreeListError::OutOfRange { lba, span, capacity: self.capacity })?;
+        }
+        let pieces = self.split(span, lba);
+        for (i, (g, local, n)) in pieces.iter().enumerate() {
+            // only ever hold one group at a time: allocate_across holds them all, in order, and
+            // the undo below goes back to lower groups
+            let result = op(&mut self.lock(*g), *n, *local);
+            if let Err(e) = result {
+                for (g, local, n) in pieces[..i].iter() {
+                    let undone = undo(&mut self.lock(*g), *n, *local);
+                    if let Err(e) = undone {
+                        error!("could not undo {} blocks at {} in group {}: {}", n, local, g, e);
+                    }
+                }
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn release_shared(&self, manifest: &Manifest) -> RResult<()> {
+        for loc in manifest.shards.iter() {
+            self.free_shared(loc.span, loc.lba)?;
+        }
+        Ok(())
+    }
+
+    /// take `span` blocks at `lba`, or none of them if any aren't free
+    pub fn take_shared(&self, span: u64, lba: u64) -> RResult<()> {
+        self.each_piece(span, lba, |list, n, local| list.take(n, local), |list, n, local| list.free(n, local))
+    }
+
+    /// free `span` blocks at `lba`, or none of them if any are already free
+    pub fn free_shared(&self, span: u64, lba: u64) -> RResult<()> {
+        self.each_piece(span, lba, |list, n, local| list.free(n, local), |list, n, local| list.take(n, local))
+    }
+}
+
+impl<F: FreeList> FreeList for ConcurrentFreeList<F> {
+    fn allocate(&mut self, span: u64) -> RResult<Manifest> {
+        self.allocate_shared(span)
+    }
+    fn release(&mut self, manifest: &Manifest) -> RResult<()> {
+        self.release_shared(manifest)
+    }
+    fn take(&mut self, span: u64, lba: u64) -> RResult<()> {
+        self.take_shared(span, lba)
+    }
+    fn free(&mut self, span: u64, lba: u64) -> RResult<()> {
+        self.free_shared(span, lba)
+    }
+
+    fn capacity(&self) -> u64 {
+        self.capacity
+    }
+
+    fn free_areas(&self) -> Vec<(u64, u64)> {
+        let mut areas: Vec<(u64, u64)> = Vec::new();
+        for g in 0..self.groups.len() {
+            let base = self.base(g);
+            for (lba, span) in self.lock(g).free_areas() {
+                // join areas that meet at a group boundary
+                match areas.last_mut() {
+                    Some(last) if last.0 + last.1 == base + lba => last.1 += span,
+                    _ => areas.push((base + lba, span)),
+                }
+            }
+        }
+        areas
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Barrier};
+    use crate::freelist::BitmapFreelist;
+    use crate::freelist::bitmap::{Bitmap, BitOps};
+
+    fn list(capacity: u64, groups: usize) -> ConcurrentFreeList<BitmapFreelist> {
+        ConcurrentFreeList::new(capacity, groups, |span| BitmapFreelist::new(span as usize))
+    }
+
+    #[test]
+    fn test_groups() {
+        let l = list(1000, 3);
+        assert_eq!(l.groups(), 3);
+        assert_eq!(l.split(20, 325), vec![(0, 325, 8), (1, 0, 12)]);
+        // the last group absorbs the remainder
+        assert_eq!(l.split(10, 990), vec![(2, 324, 10)]);
+
+        // spanning group boundaries
+        l.take_shared(400, 300).unwrap();
+        // group 1 is now full and the others have 300 blocks left each, so a larger allocation
+        // is split across them
+        let m = l.allocate_shared(301).unwrap();
+        assert_eq!(m.blocks(), 301);
+        assert!(m.shards.len() > 1);
+        assert!(l.allocate_shared(300).is_err());
+        l.release_shared(&m).unwrap();
+        l.free_shared(400, 300).unwrap();
+        assert!(l.free_shared(1, 400).is_err());
+    }
+
+    #[test]
+    fn test_failed_piece_rolls_back() {
+        let l = list(1000, 3);
+        l.take_shared(10, 340).unwrap();
+
+        // the piece in group 1 isn't free, so the piece in group 0 is given back
+        assert!(l.take_shared(20, 325).is_err());
+        l.take_shared(8, 325).unwrap();
+        l.free_shared(8, 325).unwrap();
+
+        // and the other way round: the piece in group 1 is already free
+        l.take_shared(8, 325).unwrap();
+        assert!(l.free_shared(20, 325).is_err());
+        assert!(l.take_shared(1, 325).is_err());
+        assert_eq!(l.free_areas(), vec![(0, 325), (333, 7), (350, 650)]);
+    }
+
+    #[test]
+    fn test_rollback_alongside_allocations() {
+        let l = Arc::new(list(1000, 3));
+        l.take_shared(10, 340).unwrap();
+
+        // one thread keeps failing takes across the boundary of groups 0 and 1 while the others
+        // make allocations too large for a group, which hold every group at once
+        let start = Arc::new(Barrier::new(3));
+        let failing = {
+            let (l, start) = (Arc::clone(&l), Arc::clone(&start));
+            thread::spawn(move || {
+                start.wait();
+                for _ in 0..20000 {
+                    assert!(l.take_shared(20, 325).is_err());
+                }
+            })
+        };
+        let allocating: Vec<_> = (0..2).map(|_| {
+            let (l, start) = (Arc::clone(&l), Arc::clone(&start));
+            thread::spawn(move || {
+                start.wait();
+                for _ in 0..5000 {
+                    if let Ok(m) = l.allocate_shared(340) {
+                        l.release_shared(&m).unwrap();
+                    }
+                }
+            })
+        }).collect();
+        failing.join().unwrap();
+        for t in allocating {
+            t.join().unwrap();
+        }
+        assert_eq!(l.free_areas(), vec![(0, 340), (350, 650)]);
+    }
+
+    #[test]
+    fn test_falls_back_to_other_groups() {
+        let l = list(100, 4);
+        let mut shards = Vec::new();
+        for _ in 0..4 {
+            shards.push(l.allocate_shared(25).unwrap());
+        }
+        assert!(l.allocate_shared(1).is_err());
+
+        let mut lbas: Vec<u64> = shards.iter().map(|m| m.shards[0].lba).collect();
+        lbas.sort_unstable();
+        assert_eq!(lbas, vec![0, 25, 50, 75]);
+
+        l.release_shared(&shards[2]).unwrap();
+        assert_eq!(l.allocate_shared(25).unwrap().shards, shards[2].shards);
+    }
+
+    #[test]
+    fn test_concurrent_allocate_release() {
+        let capacity = 1 << 14;
+        let l = Arc::new(list(capacity, 8));
+
+        let handles: Vec<_> = (0..8).map(|t| {
+            let l = Arc::clone(&l);
+            thread::spawn(move || {
+                let mut live = Vec::new();
+                let mut x: u64 = 0x9e37_79b9 + t;
+                for _ in 0..2000 {
+                    x ^= x << 13;
+                    x ^= x >> 7;
+                    x ^= x << 17;
+                    if !x.is_multiple_of(3) || live.is_empty() {
+                        if let Ok(m) = l.allocate_shared(1 + x % 16) {
+                            live.push(m);
+                        }
+                    } else {
+                        let m = live.swap_remove((x as usize / 3) % live.len());
+                        l.release_shared(&m).unwrap();
+                    }
+                }
+                live
+            })
+        }).collect();
+
+        let live: Vec<Manifest> = handles.into_iter().flat_map(|h| h.join().unwrap()).collect();
+
+        // no block may have been handed out twice
+        let mut used = Bitmap::new(capacity as usize);
+        for loc in live.iter().flat_map(|m| m.shards.iter()) {
+            for lba in loc.lba .. loc.lba + loc.span {
+                assert!(!used.get(lba as usize), "block {} allocated twice", lba);
+                used.set(lba as usize);
+            }
+        }
+
+        // and once everything is returned, each group is entirely free again
+        for m in live.iter() {
+            l.release_shared(m).unwrap();
+        }
+        for _ in 0..l.groups() {
+            assert!(l.allocate_shared(capacity / 8).is_ok());
+        }
+    }
+}
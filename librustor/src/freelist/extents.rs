@@ -0,0 +1,156 @@
+use std::fmt;
+
+use super::{FreeList, FreeListError};
+use crate::object::{Manifest, ManifestLocation};
+use crate::RResult;
+
+use crate::blockstore::BS4K;
+
+#[allow(unused_imports)]
+use log::{trace, debug, info, warn, error};
+
+/// Free extents indexed twice: by size, for best-fit allocation, and by address, for finding the
+/// neighbors of an area. Extents are (address, span) pairs
+pub trait ExtentIndex: Default + fmt::Debug {
+    fn insert(&mut self, address: u64, span: u64);
+    fn remove(&mut self, address: u64, span: u64);
+
+    /// the smallest extent of at least `span` blocks
+    fn best_fit(&self, span: u64) -> Option<(u64, u64)>;
+    /// the extent starting at or before `lba`
+    fn before(&self, lba: u64) -> Option<(u64, u64)>;
+    /// the first extent starting after `lba`
+    fn after(&self, lba: u64) -> Option<(u64, u64)>;
+
+    /// every extent, in address order
+    fn extents(&self) -> Vec<(u64, u64)>;
+    fn len(&self) -> usize;
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// A best-fit free list of extents kept in an `ExtentIndex`.
+///
+/// Allocation carves from the start of the smallest extent large enough; releases merge the freed
+/// area with its neighbors, so free space never fragments into adjacent extents.
+#[derive(Debug)]
+pub struct ExtentFreeList<I: ExtentIndex> {
+    index: I,
+    capacity: u64,
+}
+
+impl<I: ExtentIndex> ExtentFreeList<I> {
+    pub fn new(span: u64) -> Self {
+        let mut s = Self { index: I::default(), capacity: span };
+        if span > 0 {
+            s.insert_extent(0, span);
+        }
+        s
+    }
+
+    /// total number of free blocks
+    pub fn free_blocks(&self) -> u64 {
+        self.index.extents().iter().map(|(_, span)| span).sum()
+    }
+
+    /// number of discontiguous free extents
+    pub fn extents(&self) -> usize {
+        self.index.len()
+    }
+
+    fn insert_extent(&mut self, address: u64, span: u64) {
+        trace!("insert extent {} at {}", span, address);
+        self.index.insert(address, span);
+    }
+
+    fn remove_extent(&mut self, address: u64, span: u64) {
+        trace!("remove extent {} at {}", span, address);
+        self.index.remove(address, span);
+    }
+}
+
+impl<I: ExtentIndex> FreeList for ExtentFreeList<I> {
+    fn allocate(&mut self, size_bytes: u64) -> RResult<Manifest> {
+        let span = size_bytes.div_ceil(BS4K as u64);
+
+        let (address, extent_span) = match self.index.best_fit(span) {
+            Some(e) => e,
+            None => return Err(FreeListError::AllocationError { span })?,
+        };
+        debug!("allocated {} blocks at {}", span, address);
+
+        self.remove_extent(address, extent_span);
+        if extent_span > span {
+            self.insert_extent(address + span, extent_span - span);
+        }
+
+        Ok(Manifest { shards: vec![ManifestLocation { blkdevid: None, lba: address, span }] })
+    }
+
+    fn release(&mut self, manifest: &Manifest) -> RResult<()> {
+        for loc in manifest.shards.iter() {
+            debug!("Releasing {} blocks at {}", loc.span, loc.lba);
+            self.free(loc.span, loc.lba)?;
+        }
+        Ok(())
+    }
+
+    /// forcibly remove `span` at the specified `lba` from the freelist, splitting the free extent
+    /// around it. returns an error unless the whole range is currently free
+    fn take(&mut self, span: u64, lba: u64) -> RResult<()> {
+        let end = lba.checked_add(span).ok_or(FreeListError::NotFree { lba, span })?;
+        let (address, extent_span) = match self.index.before(lba) {
+            Some(e) if end <= e.0 + e.1 => e,
+            _ => return Err(FreeListError::NotFree { lba, span })?,
+        };
+
+        self.remove_extent(address, extent_span);
+        if lba > address {
+            self.insert_extent(address, lba - address);
+        }
+        let extent_end = address + extent_span;
+        if end < extent_end {
+            self.insert_extent(end, extent_end - end);
+        }
+        Ok(())
+    }
+
+    /// return `span` blocks at `lba` to the freelist, merging with adjacent free extents
+    fn free(&mut self, span: u64, lba: u64) -> RResult<()> {
+        let end = match lba.checked_add(span) {
+            Some(end) if end <= self.capacity => end,
+            _ => return Err(FreeListError::OutOfRange { lba, span, capacity: self.capacity })?,
+        };
+
+        // both neighbors are checked before either is merged, so a failure leaves the list as it was
+        let prev = self.index.before(lba);
+        let next = self.index.after(lba);
+        if prev.is_some_and(|(a, s)| a + s > lba) || next.is_some_and(|(a, _)| a < end) {
+            return Err(FreeListError::ReleaseOfFreeArea { lba, span })?;
+        }
+
+        let mut address = lba;
+        let mut total = span;
+        if let Some((prev_addr, prev_span)) = prev.filter(|(a, s)| a + s == lba) {
+            self.remove_extent(prev_addr, prev_span);
+            address = prev_addr;
+            total += prev_span;
+        }
+        if let Some((next_addr, next_span)) = next.filter(|(a, _)| *a == end) {
+            self.remove_extent(next_addr, next_span);
+            total += next_span;
+        }
+
+        self.insert_extent(address, total);
+        Ok(())
+    }
+
+    fn capacity(&self) -> u64 {
+        self.capacity
+    }
+
+    fn free_areas(&self) -> Vec<(u64, u64)> {
+        self.index.extents()
+    }
+}
@@ -3,6 +3,9 @@ use crate::object::Manifest;
 use crate::RResult;
 use uuid::Uuid;
 
+use std::error::Error;
+use std::fmt;
+
 #[derive(Debug, PartialEq, Eq, Hash)]
 pub struct FreeListNode {
     pub blkdevid: Option<Uuid>,
@@ -11,9 +14,10 @@ pub struct FreeListNode {
 }
 
 impl FreeListNode {
+    /// true if the two areas share at least one block. areas that merely touch do not overlap
     pub fn overlaps(&self, other:&Self) -> bool {
-        self.address >= other.address && self.address <= other.address + other.span ||
-            other.address >= self.address && other.address <= self.address + self.span
+        self.address < other.address + other.span &&
+            other.address < self.address + self.span
     }
 
     pub fn adjacent(&self, other:&Self) -> bool {
@@ -22,6 +26,105 @@ impl FreeListNode {
     }
 }
 
+#[derive(Debug, PartialEq, Eq)]
+pub enum FreeListError {
+    /// no free area is large enough to hold `span` blocks
+    AllocationError { span: u64 },
+    /// some or all of the area being released is already free
+    ReleaseOfFreeArea { lba: u64, span: u64 },
+    /// the area being released or taken extends past the end of the list
+    OutOfRange { lba: u64, span: u64, capacity: u64 },
+    /// some or all of the area being taken is not free
+    NotFree { lba: u64, span: u64 },
+    /// there is enough free space for `span` blocks, but only spread over more than `max_shards` areas
+    TooFragmented { span: u64, max_shards: usize },
+    /// the list's indexes disagree: a free area of `span` blocks at `lba` is missing from one
+    MissingNode { lba: u64, span: u64 },
+}
+
+impl Error for FreeListError {}
+
+impl fmt::Display for FreeListError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FreeListError::AllocationError { span } =>
+                write!(f, "Could not allocate {} blocks", span),
+            FreeListError::ReleaseOfFreeArea { lba, span } =>
+                write!(f, "Tried to release a free area: {} blocks at lba {}", span, lba),
+            FreeListError::OutOfRange { lba, span, capacity } =>
+                write!(f, "Out of bounds: {} blocks at lba {} (max {})", span, lba, capacity),
+            FreeListError::NotFree { lba, span } =>
+                write!(f, "Tried to take an area that is not free: {} blocks at lba {}", span, lba),
+            FreeListError::TooFragmented { span, max_shards } =>
+                write!(f, "Could not allocate {} blocks in {} or fewer shards", span, max_shards),
+            FreeListError::MissingNode { lba, span } =>
+                write!(f, "Free list is inconsistent: no node for {} blocks at lba {}", span, lba),
+        }
+    }
+}
+
+/// How a free list may satisfy an allocation
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AllocationMode {
+    /// the whole allocation must fit in a single free area
+    #[default]
+    Contiguous,
+    /// if no single free area is large enough, split the allocation across as few free areas as
+    /// possible, up to `max_shards`
+    Fragmented { max_shards: usize },
+}
+
+/// Where a free list looks for space to satisfy an allocation
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AllocationPolicy {
+    /// use the lowest free blocks, splitting the allocation across free areas as needed
+    #[default]
+    FirstFit,
+    /// like first-fit, but resume searching where the previous allocation ended, wrapping around
+    /// at the end. suits append-heavy workloads like logs
+    NextFit,
+    /// use the smallest free area that holds the whole allocation, falling back to first-fit if
+    /// no single area is large enough. keeps large areas intact for large objects
+    BestFit,
+    /// use the lowest free area that holds the whole allocation, or fail
+    ContiguousOnly,
+}
+
+/// A snapshot of how free space is laid out
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct FreeListStats {
+    pub capacity: u64,
+    pub free: u64,
+    /// number of discontiguous free areas
+    pub extents: u64,
+    /// size of the largest free area, in blocks
+    pub largest_free_run: u64,
+    /// `histogram[i]` counts the free areas of `2^i` to `2^(i+1) - 1` blocks
+    pub histogram: Vec<u64>,
+}
+
+impl FreeListStats {
+    /// fraction of free space outside the largest free area: 0.0 when all free space is
+    /// contiguous, approaching 1.0 as it is scattered into small pieces
+    pub fn fragmentation(&self) -> f64 {
+        if self.free == 0 { return 0.0; }
+        1.0 - self.largest_free_run as f64 / self.free as f64
+    }
+
+    /// record a free area of `span` blocks
+    pub fn add_extent(&mut self, span: u64) {
+        if span == 0 { return; }
+        let bucket = (63 - span.leading_zeros()) as usize;
+        if self.histogram.len() <= bucket {
+            self.histogram.resize(bucket + 1, 0);
+        }
+        self.histogram[bucket] += 1;
+        self.extents += 1;
+        self.free += span;
+        self.largest_free_run = self.largest_free_run.max(span);
+    }
+}
+
 use std::rc::Rc;
 pub type RCFreeListNode = Rc<FreeListNode>;
 
@@ -32,6 +135,11 @@ pub trait FreeList {
 
     fn take(&mut self, span:u64, lba: u64) -> RResult<()>;
     fn free(&mut self, span:u64, lba: u64) -> RResult<()>;
+
+    /// number of blocks the list manages
+    fn capacity(&self) -> u64;
+    /// the free areas as (lba, span), in lba order
+    fn free_areas(&self) -> Vec<(u64, u64)>;
 }
 
 pub trait FreeListFromKeys {
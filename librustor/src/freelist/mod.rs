@@ -13,9 +13,69 @@ mod bitmap;
 pub mod bitmapfreelist;
 pub use bitmapfreelist::*;
 
+pub mod extents;
+pub use extents::{ExtentFreeList, ExtentIndex};
 
-//pub mod avl;
-//pub use avl::*;
+pub mod avl;
+
+pub mod avlfreelist;
+pub use avlfreelist::*;
+
+pub mod btree;
+pub use btree::BTreeFreeList;
+
+pub mod concurrent;
+pub use concurrent::ConcurrentFreeList;
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use proptest::prelude::*;
+    use crate::{BS4K, RResult, RustorError};
+    use crate::object::{Manifest, ManifestLocation};
+    use super::{FreeList, FreeListError};
+
+    /// a manifest of `span` blocks at `lba`
+    pub fn shard(lba: u64, span: u64) -> Manifest {
+        Manifest { shards: vec![ManifestLocation { blkdevid: None, lba, span }] }
+    }
+
+    /// the free list error `result` failed with
+    pub fn error(result: RResult<()>) -> FreeListError {
+        match result.unwrap_err() {
+            RustorError::FreeList(e) => e,
+            e => panic!("unexpected error {:?}", e),
+        }
+    }
+
+    /// Allocate and release at random from a 256-block list made by `make`, checking that free
+    /// space is conserved, that releasing twice fails, and that the list is one free area again
+    /// once everything is released
+    pub fn check_free_list<F: FreeList>(make: impl Fn(u64) -> F) {
+        let capacity = 256;
+        proptest!(|(ops in prop::collection::vec((any::<bool>(), 1..16u64), 1..100))| {
+            let mut list = make(capacity);
+            let mut live: Vec<Manifest> = Vec::new();
+            for (alloc, n) in ops {
+                if alloc || live.is_empty() {
+                    if let Ok(m) = list.allocate(n * BS4K as u64) {
+                        live.push(m);
+                    }
+                } else {
+                    let m = live.remove(n as usize % live.len());
+                    list.release(&m).unwrap();
+                    prop_assert!(list.release(&m).is_err());
+                }
+                let used: u64 = live.iter().map(|m| m.blocks()).sum();
+                let free: u64 = list.free_areas().iter().map(|(_, span)| span).sum();
+                prop_assert_eq!(free, capacity - used);
+            }
+            for m in live.drain(..) {
+                list.release(&m).unwrap();
+            }
+            prop_assert_eq!(list.free_areas(), vec![(0, capacity)]);
+        });
+    }
+}
 
 //pub mod freetree;
 //pub use freetree::*;
@@ -38,23 +98,3 @@ pub trait FreeList {
     fn release(&mut self, size:u64, address:u64) -> RResult<()>;
 }
 */
-
-/*
-#[derive(Debug)]
-pub enum FreeListError {
-    AllocationError,
-    ReleaseOfFreeArea,
-}
-
-impl Error for FreeListError {}
-
-use std::fmt;
-impl fmt::Display for FreeListError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self {
-            FreeListError::AllocationError => write!(f, "Could not allocate"),
-            FreeListError::ReleaseOfFreeArea => write!(f, "Tried to release a free area"),
-        }
-    }
-}
-*/
@@ -1,5 +1,5 @@
 #![allow(unused_imports)]
-use crate::freelist::{FreeList, FreeListNode};
+use crate::freelist::{FreeList, FreeListNode, FreeListError};
 use crate::object::{Manifest, ManifestLocation};
 use crate::RResult;
 
@@ -9,10 +9,16 @@ use std::cell::RefCell;
 
 use crate::blockstore::BS4K;
 
+fn missing(node: &Rc<RefCell<FreeListNode>>) -> FreeListError {
+    let node = node.borrow();
+    FreeListError::MissingNode { lba: node.address, span: node.span }
+}
+
 #[derive(Debug)]
 pub struct RCVecFreeList {
     pub by_size: Vec<Rc<RefCell<FreeListNode>>>,
-    pub by_addr: Vec<Rc<RefCell<FreeListNode>>>
+    pub by_addr: Vec<Rc<RefCell<FreeListNode>>>,
+    capacity: u64,
 }
 
 impl RCVecFreeList {
@@ -21,9 +27,24 @@ impl RCVecFreeList {
         Self {
             by_size: vec![Rc::clone(&new_node)],
             by_addr: vec![Rc::clone(&new_node)],
+            capacity: span,
         }
     }
 
+    pub fn capacity(&self) -> u64 {
+        self.capacity
+    }
+
+    /// total number of free blocks
+    pub fn free_blocks(&self) -> u64 {
+        self.by_addr.iter().map(|node| node.borrow().span).sum()
+    }
+
+    /// number of discontiguous free areas
+    pub fn extents(&self) -> usize {
+        self.by_addr.len()
+    }
+
     fn insert_node(&mut self, node: Rc<RefCell<FreeListNode>>) {
         trace!("insert {:?}", &node);
         // insert the new node into the by_addr list
@@ -44,7 +65,7 @@ impl RCVecFreeList {
         if let Ok(pos) = self.by_addr.binary_search_by(
             |n| n.borrow().address.cmp(&node.borrow().address)) {
             self.by_addr.remove(pos);
-        } else { return Err(format!("could not find node {:?}", node))?; }
+        } else { return Err(missing(node))?; }
 
         for i in 0..self.by_size.len() {
             if &self.by_size[i] == node {
@@ -57,7 +78,7 @@ impl RCVecFreeList {
         if let Ok(pos) = self.by_size.binary_search_by(
             |n| n.borrow().span.cmp(&node.borrow().span)) {
             self.by_size.remove(pos);
-        } else { return Err(format!("could not find node {:?}", node))?; }
+        } else { return Err(missing(node))?; }
         */
 
         Ok(())
@@ -109,21 +130,25 @@ impl FreeList for RCVecFreeList {
                 // didn't find something exactly the right size
                 // if we're at the end, return an error
                 if idx == self.by_size.len() {
-                    return Err("Could not allocate")?;
+                    return Err(FreeListError::AllocationError { span })?;
                 }
                 idx
             }
         };
 
-        let rcnode = Rc::clone(&mut self.by_size[pos]);
+        let rcnode = Rc::clone(&self.by_size[pos]);
         let mut node = rcnode.borrow_mut();
         let address = node.address;
         debug!("allocated {} blocks at {}", span, address);
         node.span -= span;
         node.address += span;
+        let remaining = node.span;
+        drop(node);
 
-        if node.span == 0 {
+        if remaining == 0 {
             self.remove_node(&rcnode)?;
+        } else {
+            self.sort_size();
         }
 
         return Ok( Manifest { shards: vec![ManifestLocation { 
@@ -131,23 +156,9 @@ impl FreeList for RCVecFreeList {
     }
 
     fn release(&mut self, manifest: &Manifest) -> RResult<()> {
-        // TODO: check if the area being freed is already free
-        // TODO: check if the area being released overlaps a free area
-        // TODO: check if the area being released is outside of max size
-        // TODO: check if we're adjacent to another free area and combine
-
         for loc in manifest.shards.iter() {
-            let span = loc.span;
-            let address = loc.lba;
-                debug!("Releasing {} blocks at {}", span, address);
-                let index = match self.by_addr.binary_search_by(|node| node.borrow().span.cmp(&span)) {
-                    Ok(idx) => idx,
-                    Err(idx) => idx, // this is fine, it just means this will be the largest free block
-                };
-            trace!("index: {}", index);
-
-            self.free(span, address)?;
-
+            debug!("Releasing {} blocks at {}", loc.span, loc.lba);
+            self.free(loc.span, loc.lba)?;
         }
 
         Ok(())
@@ -200,36 +211,102 @@ impl FreeList for RCVecFreeList {
             self.sort_size();
 
         } else {
-            return Err(format!("Could not find node spanning {} blocks at address {}", &span, &lba))?;
+            return Err(FreeListError::NotFree { lba, span })?;
         }
         Ok(())
     }
-    /// forcibly releases `span` blocks at `lba` by adding a node to the freelist
-    /// returns an error if the area defined by `lba` and `span` is already partially freed
+    /// forcibly releases `span` blocks at `lba` by adding a node to the freelist, merging it with
+    /// any adjacent free nodes.
+    /// returns an error if the area defined by `lba` and `span` is already partially freed or
+    /// extends past the end of the list
     fn free(&mut self, span:u64, lba: u64) -> RResult<()> {
-        let node = FreeListNode { blkdevid: None, address: lba, span: span };
+        if lba + span > self.capacity {
+            return Err(FreeListError::OutOfRange { lba, span, capacity: self.capacity })?;
+        }
+
+        let mut node = FreeListNode { blkdevid: None, address: lba, span };
 
         let pos = self.by_addr.binary_search_by(
             |n| n.borrow().address.cmp(&node.address))
             .unwrap_or_else(|e| e);
 
         if pos > 0 && node.overlaps(&self.by_addr[pos-1].borrow()) {
-            return Err(format!("{} blocks at lba {} already free", span, lba))?;
+            return Err(FreeListError::ReleaseOfFreeArea { lba, span })?;
         }
         if pos < self.by_addr.len() && node.overlaps(&self.by_addr[pos].borrow()) {
-            return Err(format!("{} blocks at lba {} already free", span, lba))?;
+            return Err(FreeListError::ReleaseOfFreeArea { lba, span })?;
+        }
+
+        // merge with the following node first so that pos-1 still refers to the preceding one
+        if pos < self.by_addr.len() && node.adjacent(&self.by_addr[pos].borrow()) {
+            let next = Rc::clone(&self.by_addr[pos]);
+            node.span += next.borrow().span;
+            self.remove_node(&next)?;
+        }
+        if pos > 0 && node.adjacent(&self.by_addr[pos-1].borrow()) {
+            let prev = Rc::clone(&self.by_addr[pos-1]);
+            node.address = prev.borrow().address;
+            node.span += prev.borrow().span;
+            self.remove_node(&prev)?;
         }
 
         self.insert_node(Rc::new(RefCell::new(node)));
-        
+
         Ok(())
     }
+
+    fn capacity(&self) -> u64 {
+        self.capacity
+    }
+
+    fn free_areas(&self) -> Vec<(u64, u64)> {
+        self.by_addr.iter().map(|node| (node.borrow().address, node.borrow().span)).collect()
+    }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::freelist::tests::{check_free_list, error};
+
+    #[test]
+    fn test_free_coalesces() {
+        let mut list = RCVecFreeList::new(30);
+        let a = list.allocate(10 * BS4K as u64).unwrap();
+        let b = list.allocate(10 * BS4K as u64).unwrap();
+        let c = list.allocate(10 * BS4K as u64).unwrap();
+        assert_eq!(list.extents(), 0);
+
+        list.release(&c).unwrap();
+        list.release(&a).unwrap();
+        assert_eq!(list.extents(), 2);
+
+        list.release(&b).unwrap();
+        assert_eq!(list.extents(), 1);
+        assert_eq!(*list.by_size[0].borrow(), FreeListNode { blkdevid: None, span: 30, address: 0 });
+    }
+
+    #[test]
+    fn test_free_errors() {
+        let mut list = RCVecFreeList::new(30);
+        list.allocate(10 * BS4K as u64).unwrap();
+
+        assert_eq!(error(list.free(6, 5)), FreeListError::ReleaseOfFreeArea { lba: 5, span: 6 });
+        assert_eq!(error(list.free(10, 25)), FreeListError::OutOfRange { lba: 25, span: 10, capacity: 30 });
+        list.free(10, 0).unwrap();
+        assert_eq!(error(list.free(1, 9)), FreeListError::ReleaseOfFreeArea { lba: 9, span: 1 });
+        assert_eq!(list.free_blocks(), 30);
+    }
+
+    #[test]
+    fn test_free_space_conserved() {
+        check_free_list(RCVecFreeList::new);
+    }
+}
 
 /*
 #[cfg(test)]
-mod tests {
+mod old_tests {
     use super::*;
 
     #[test]
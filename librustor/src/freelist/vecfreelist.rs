@@ -1,5 +1,5 @@
 #![allow(unused_imports)]
-use crate::freelist::{FreeList, FreeListNode};
+use crate::freelist::{FreeList, FreeListNode, FreeListError, AllocationMode};
 use crate::object::{Manifest, ManifestLocation};
 
 use log::{trace, debug, info, warn, error};
@@ -11,22 +11,120 @@ use crate::blockstore::BS4K;
 #[derive(Debug)]
 pub struct VecFreeList {
     free: Vec<FreeListNode>,
+    capacity: u64,
+    mode: AllocationMode,
 }
 
 impl VecFreeList {
     pub fn new(span:u64) -> Self {
+        Self::with_mode(span, AllocationMode::default())
+    }
+
+    pub fn with_mode(span:u64, mode: AllocationMode) -> Self {
         let mut s = Self {
             free: Vec::new(),
+            capacity: span,
+            mode,
         };
 
         let new_node = FreeListNode { blkdevid: None, span, address: 0 };
         s.free.push(new_node);
         s
     }
+
+    pub fn mode(&self) -> AllocationMode {
+        self.mode
+    }
+
+    pub fn set_mode(&mut self, mode: AllocationMode) {
+        self.mode = mode;
+    }
+
+    pub fn capacity(&self) -> u64 {
+        self.capacity
+    }
+
+    /// total number of free blocks
+    pub fn free_blocks(&self) -> u64 {
+        self.free.iter().map(|node| node.span).sum()
+    }
+
+    /// number of discontiguous free areas
+    pub fn extents(&self) -> usize {
+        self.free.len()
+    }
+
+    /// carve `span` blocks from the start of the free area at `index`. returns the address
+    fn allocate_from(&mut self, index: usize, span: u64) -> u64 {
+        // take the node out and put back what's left so the list stays ordered by size
+        let mut node = self.free.remove(index);
+        let address = node.address;
+        debug!("allocated {} blocks at {}", span, address);
+        node.span -= span;
+        node.address += span;
+        if node.span > 0 {
+            self.insert_node(node);
+        }
+        address
+    }
+
+    /// split `span` blocks across the fewest free areas, at most `max_shards` of them.
+    ///
+    /// whole areas are used from the largest down; the remainder is then taken from the smallest
+    /// area that still holds it, so the tail doesn't needlessly break up a large area
+    fn allocate_fragmented(&mut self, span: u64, max_shards: usize) -> RResult<Manifest> {
+        if span == 0 {
+            return Ok(Manifest::new());
+        }
+        let total: u64 = self.free_blocks();
+        if total < span {
+            return Err(FreeListError::AllocationError { span })?;
+        }
+
+        // count how many of the largest areas are needed
+        let mut shards = 0;
+        let mut covered = 0;
+        for node in self.free.iter().rev() {
+            shards += 1;
+            covered += node.span;
+            if covered >= span { break; }
+        }
+        if shards > max_shards {
+            return Err(FreeListError::TooFragmented { span, max_shards })?;
+        }
+
+        // all but the last of them are used whole. the list is ordered by size, so the first of
+        // the rest that is large enough is the best fit for the remainder. it's found before
+        // anything is taken, so that a failure leaves the list as it was
+        let whole = self.free.len() - (shards - 1);
+        let remaining = span - self.free[whole..].iter().map(|n| n.span).sum::<u64>();
+        let index = self.free[..whole].iter().position(|n| n.span >= remaining)
+            .ok_or(FreeListError::AllocationError { span })?;
+
+        let mut m = Manifest::new();
+        for node in self.free.drain(whole..) {
+            debug!("allocated {} blocks at {}", node.span, node.address);
+            m.shards.push(ManifestLocation { blkdevid: None, lba: node.address, span: node.span });
+        }
+        let address = self.allocate_from(index, remaining);
+        m.shards.push(ManifestLocation { blkdevid: None, lba: address, span: remaining });
+
+        m.shards.sort_by_key(|loc| loc.lba);
+        Ok(m)
+    }
+
+    /// insert `node`, keeping the list ordered by size
+    fn insert_node(&mut self, node: FreeListNode) {
+        let index = match self.free.binary_search_by(|n| n.span.cmp(&node.span)) {
+            Ok(idx) => idx,
+            Err(idx) => idx, // this is fine, it just means this will be the largest free block
+        };
+        trace!("index: {}", index);
+        self.free.insert(index, node);
+    }
 }
 
 use crate::RResult;
-use crate::GeneralError;
 
 impl FreeList for VecFreeList {
     fn allocate(&mut self, size_bytes:u64) -> RResult<Manifest> {
@@ -37,21 +135,18 @@ impl FreeList for VecFreeList {
             Ok(idx) => idx,
             Err(idx) => {
                 // didn't find something exactly the right size
-                // if we're at the end, return an error
+                // if we're at the end, nothing is big enough on its own
                 if idx == self.free.len() {
-                    return Err("Could not allocate")?;
+                    return match self.mode {
+                        AllocationMode::Contiguous => Err(FreeListError::AllocationError { span })?,
+                        AllocationMode::Fragmented { max_shards } =>
+                            self.allocate_fragmented(span, max_shards),
+                    };
                 }
                 idx
             }
         };
-        let node = &mut self.free[index];
-        let address = node.address;
-        debug!("allocated {} blocks at {}", span, address);
-        node.span -= span;
-        node.address += span;
-        if node.span == 0 {
-            self.free.remove(index);
-        }
+        let address = self.allocate_from(index, span);
 
         let mut m = Manifest { shards: Vec::new() };
         m.shards.push(ManifestLocation { lba: address as u64, span: span as u64, blkdevid: None });
@@ -59,77 +154,226 @@ impl FreeList for VecFreeList {
     }
 
     fn release(&mut self, manifest: &Manifest) -> RResult<()> {
-        // TODO: check if the area being freed is already free
-        // TODO: check if the area being released overlaps a free area
-        // TODO: check if the area being released is outside of max size
-        // TODO: check if we're adjacent to another free area and combine
-
         for loc in manifest.shards.iter() {
-            let span = loc.span;
-            let lba = loc.lba;
-            debug!("Releasing {} blocks at {}", span, lba);
-            let index = match self.free.binary_search_by(|node| node.span.cmp(&span)) {
-                Ok(idx) => idx,
-                Err(idx) => idx, // this is fine, it just means this will be the largest free block
-            };
-            trace!("index: {}", index);
-
-            self.free.insert(index, FreeListNode { blkdevid: None, span, address: lba });
-
+            self.free(loc.span, loc.lba)?;
         }
 
         Ok(())
     }
 
 
-    #[allow(unused_variables)]
+    /// mark `span` blocks at `lba` in use. fails if any of them aren't free
     fn take(&mut self, span:u64, lba: u64) -> RResult<()> {
-        let index = match self.free.binary_search_by(|node| node.span.cmp(&span)) {
-            Ok(idx) => idx,
-            Err(idx) => {
-                // if there is no block exactly span size, If the value is not found then
-                // Result::Err is returned, containing the index where a matching element could be
-                // inserted while maintaining sorted order.
-                // if we're at the end, then there is no span big enough to allocate
-                if idx == self.free.len() {
-                    return Err("Could not allocate")?;
-                }
-                idx
-            }
-        };
-        let node = &mut self.free[index];
-        // allocate at address, shrink by span
-        node.span -= span;
-        node.address += span;
-        if node.span == 0 {
-            self.free.remove(index);
-        }
+        let index = self.free.iter()
+            .position(|n| n.address <= lba && lba + span <= n.address + n.span)
+            .ok_or(FreeListError::NotFree { lba, span })?;
 
+        // put back what's left on either side of the area taken
+        let node = self.free.remove(index);
+        if lba > node.address {
+            self.insert_node(FreeListNode { span: lba - node.address, ..node });
+        }
+        let end = node.address + node.span;
+        if lba + span < end {
+            self.insert_node(FreeListNode { span: end - lba - span, address: lba + span, ..node });
+        }
         Ok(())
     }
 
-    #[allow(unused_variables)]
+    /// return `span` blocks at `lba` to the list, merging with any free neighbors.
+    /// fails without modifying the list if any of the area is already free or out of range
     fn free(&mut self, span:u64, lba: u64) -> RResult<()> {
-        // 
+        debug!("Releasing {} blocks at {}", span, lba);
+        if lba + span > self.capacity {
+            return Err(FreeListError::OutOfRange { lba, span, capacity: self.capacity })?;
+        }
+
+        let mut node = FreeListNode { blkdevid: None, span, address: lba };
+        if self.free.iter().any(|n| n.overlaps(&node)) {
+            return Err(FreeListError::ReleaseOfFreeArea { lba, span })?;
+        }
+
+        // there are at most two neighbors: one ending at lba and one starting at lba + span
+        while let Some(index) = self.free.iter().position(|n| n.adjacent(&node)) {
+            let neighbor = self.free.remove(index);
+            trace!("merging with {:?}", &neighbor);
+            node.address = node.address.min(neighbor.address);
+            node.span += neighbor.span;
+        }
+
+        self.insert_node(node);
         Ok(())
     }
+
+    fn capacity(&self) -> u64 {
+        self.capacity
+    }
+
+    fn free_areas(&self) -> Vec<(u64, u64)> {
+        // the list is ordered by size
+        let mut areas: Vec<_> = self.free.iter().map(|node| (node.address, node.span)).collect();
+        areas.sort_unstable();
+        areas
+    }
 }
 
 use crate::freelist::FreeListFromKeys;
 use crate::object::ObjKey;
 impl FreeListFromKeys for VecFreeList {
-    #[allow(unused_variables)]
+    /// take the blocks of every key
     fn from_keys<'a, I>(&mut self, keys: I) -> RResult<()> where I: Iterator<Item=&'a ObjKey> {
-
+        for key in keys {
+            for shard in key.manifest.shards.iter() {
+                self.take(shard.span, shard.lba)?;
+            }
+        }
         Ok(())
     }
 }
 
 
-/*
 #[cfg(test)]
 mod tests {
     use super::*;
+    use proptest::prelude::*;
+    use crate::freelist::tests::{check_free_list, error, shard};
+
+    #[test]
+    fn test_release_coalesces() {
+        let mut list = VecFreeList::new(30);
+        let a = list.allocate(10 * BS4K as u64).unwrap();
+        let b = list.allocate(10 * BS4K as u64).unwrap();
+        let c = list.allocate(10 * BS4K as u64).unwrap();
+        assert_eq!(list.extents(), 0);
+
+        list.release(&a).unwrap();
+        list.release(&c).unwrap();
+        assert_eq!(list.extents(), 2);
+        assert!(list.allocate(20 * BS4K as u64).is_err());
+
+        list.release(&b).unwrap();
+        assert_eq!(list.free, vec![FreeListNode { blkdevid: None, span: 30, address: 0 }]);
+        assert!(list.allocate(30 * BS4K as u64).is_ok());
+    }
+
+    #[test]
+    fn test_release_errors() {
+        let mut list = VecFreeList::new(30);
+        let m = list.allocate(10 * BS4K as u64).unwrap();
+
+        assert_eq!(error(list.release(&shard(5, 6))), FreeListError::ReleaseOfFreeArea { lba: 5, span: 6 });
+        assert_eq!(error(list.release(&shard(25, 10))), FreeListError::OutOfRange { lba: 25, span: 10, capacity: 30 });
+
+        list.release(&m).unwrap();
+        assert_eq!(error(list.release(&m)), FreeListError::ReleaseOfFreeArea { lba: 0, span: 10 });
+        assert_eq!(list.free_blocks(), 30);
+    }
+
+    /// free list of 100 blocks with free areas of 10 at 0, 20 at 20, 5 at 50 and 30 at 70
+    fn fragmented(mode: AllocationMode) -> VecFreeList {
+        let mut list = VecFreeList::with_mode(100, mode);
+        list.allocate(100 * BS4K as u64).unwrap();
+        for (lba, span) in [(0, 10), (20, 20), (50, 5), (70, 30)].iter() {
+            list.free(*span, *lba).unwrap();
+        }
+        list
+    }
+
+    #[test]
+    fn test_take() {
+        let mut list = VecFreeList::new(30);
+        list.take(5, 10).unwrap();
+        assert_eq!(list.free_blocks(), 25);
+        assert_eq!(error(list.take(2, 14)), FreeListError::NotFree { lba: 14, span: 2 });
+        list.take(10, 0).unwrap();
+        list.take(15, 15).unwrap();
+        assert_eq!(list.free_blocks(), 0);
+        list.free(5, 10).unwrap();
+        assert_eq!(list.allocate(5 * BS4K as u64).unwrap().shards, shard(10, 5).shards);
+    }
+
+    #[test]
+    fn test_contiguous_mode() {
+        let mut list = fragmented(AllocationMode::Contiguous);
+        assert_eq!(error(list.allocate(40 * BS4K as u64).map(|_| ())), FreeListError::AllocationError { span: 40 });
+        assert_eq!(list.free_blocks(), 65);
+    }
+
+    #[test]
+    fn test_fragmented_fewest_shards() {
+        let mut list = fragmented(AllocationMode::Fragmented { max_shards: 4 });
+
+        // a single area still wins when one is big enough
+        let m = list.allocate(5 * BS4K as u64).unwrap();
+        assert_eq!(m.shards, vec![ManifestLocation { blkdevid: None, lba: 50, span: 5 }]);
+
+        // 30 + best fit for the remaining 8 out of the 10 block area
+        let m = list.allocate(38 * BS4K as u64).unwrap();
+        assert_eq!(m.shards, vec![
+            ManifestLocation { blkdevid: None, lba: 0, span: 8 },
+            ManifestLocation { blkdevid: None, lba: 70, span: 30 },
+        ]);
+        assert_eq!(list.free_blocks(), 22);
+    }
+
+    #[test]
+    fn test_fragmented_shard_limit() {
+        let mut list = fragmented(AllocationMode::Fragmented { max_shards: 2 });
+        assert_eq!(error(list.allocate(60 * BS4K as u64).map(|_| ())),
+            FreeListError::TooFragmented { span: 60, max_shards: 2 });
+        assert_eq!(error(list.allocate(66 * BS4K as u64).map(|_| ())), FreeListError::AllocationError { span: 66 });
+        assert_eq!(list.free_blocks(), 65);
+
+        list.set_mode(AllocationMode::Fragmented { max_shards: 3 });
+        let m = list.allocate(60 * BS4K as u64).unwrap();
+        assert_eq!(m.shards.len(), 3);
+        assert_eq!(m.shards.iter().map(|s| s.span).sum::<u64>(), 60);
+        assert_eq!(list.free_blocks(), 5);
+    }
+
+    #[test]
+    fn test_fragmented_empty() {
+        // nothing is free, so an empty allocation goes through the fragmented path
+        let mut list = VecFreeList::with_mode(8, AllocationMode::Fragmented { max_shards: 2 });
+        list.take(8, 0).unwrap();
+        assert!(list.allocate(0).unwrap().shards.is_empty());
+        assert_eq!(error(list.allocate(1).map(|_| ())), FreeListError::AllocationError { span: 1 });
+        assert_eq!(list.free_blocks(), 0);
+    }
+
+    #[test]
+    fn test_free_space_conserved() {
+        check_free_list(VecFreeList::new);
+    }
+
+    proptest! {
+        #[test]
+        fn test_fragmented_free_space_conserved(ops in prop::collection::vec((any::<bool>(), 1..64u64), 1..100)) {
+            let capacity = 256;
+            let mut list = VecFreeList::with_mode(capacity, AllocationMode::Fragmented { max_shards: 4 });
+            let mut live: Vec<Manifest> = Vec::new();
+            for (alloc, n) in ops {
+                if alloc || live.is_empty() {
+                    if let Ok(m) = list.allocate(n * BS4K as u64) {
+                        prop_assert!(m.shards.len() <= 4);
+                        prop_assert_eq!(m.shards.iter().map(|s| s.span).sum::<u64>(), n);
+                        live.push(m);
+                    }
+                } else {
+                    let m = live.remove(n as usize % live.len());
+                    list.release(&m).unwrap();
+                }
+                let used: u64 = live.iter().flat_map(|m| m.shards.iter()).map(|s| s.span).sum();
+                prop_assert_eq!(list.free_blocks(), capacity - used);
+            }
+        }
+    }
+}
+
+/*
+#[cfg(test)]
+mod old_tests {
+    use super::*;
 
     #[test]
     fn test_allocate() {
@@ -1,4 +1,4 @@
-use uuid::Uuid;
+use uuid::{Builder, Uuid, Variant, Version};
 use std::hash::Hasher;
 use std::collections::hash_map::DefaultHasher;
 
@@ -11,18 +11,81 @@ pub trait GeneratesKeys {
 
 pub struct KeyGen {}
 
+impl KeyGen {
+    /// a hasher for data that arrives in pieces
+    pub fn hasher(&self) -> KeyHasher {
+        KeyHasher::new()
+    }
+}
+
 impl GeneratesKeys for KeyGen {
     fn make_key(&self, data: &[u8]) -> RResult<ObjKey> {
-        let mut hasher = DefaultHasher::new();
-        hasher.write(data);
-        let hash = hasher.finish();
+        let mut hasher = self.hasher();
+        hasher.update(data);
+        Ok(hasher.finish())
+    }
+}
+
+/// Builds the key of an object from its data a piece at a time. The key is the same however the
+/// data is split up
+pub struct KeyHasher {
+    // the v5 uuid of the data, computed as Uuid::new_v5 does
+    sha: sha1::Sha1,
+    hasher: DefaultHasher,
+    size: u64,
+}
+
+impl KeyHasher {
+    fn new() -> Self {
+        let mut sha = sha1::Sha1::new();
+        sha.update(Uuid::NAMESPACE_OID.as_bytes());
+        Self { sha, hasher: DefaultHasher::new(), size: 0 }
+    }
 
-        Ok(ObjKey {
+    pub fn update(&mut self, data: &[u8]) {
+        self.sha.update(data);
+        self.hasher.write(data);
+        self.size += data.len() as u64;
+    }
+
+    /// bytes hashed so far
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn finish(self) -> ObjKey {
+        let mut bytes = [0; 16];
+        bytes.copy_from_slice(&self.sha.digest().bytes()[..16]);
+        let uuid = Builder::from_bytes(bytes)
+            .set_variant(Variant::RFC4122)
+            .set_version(Version::Sha1)
+            .build();
+
+        ObjKey {
             manifest: Manifest::default(),
-            uuid: Uuid::new_v5(&Uuid::NAMESPACE_OID, data),
-            hash: hash,
-            size: data.len() as u64
-        })
+            uuid,
+            hash: self.hasher.finish(),
+            size: self.size,
+            ..Default::default()
+        }
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_incremental_key() {
+        let data: Vec<u8> = (0..10000u32).map(|i| i as u8).collect();
+        let key = KeyGen {}.make_key(&data).unwrap();
+        assert_eq!(key.uuid, Uuid::new_v5(&Uuid::NAMESPACE_OID, &data));
+
+        let mut hasher = KeyGen {}.hasher();
+        for piece in data.chunks(999) {
+            hasher.update(piece);
+        }
+        let streamed = hasher.finish();
+        assert_eq!((streamed.uuid, streamed.hash, streamed.size), (key.uuid, key.hash, key.size));
+    }
+}
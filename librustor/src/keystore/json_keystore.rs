@@ -2,7 +2,8 @@
 use std::fs::{OpenOptions};
 use std::io;
 use std::path::PathBuf;
-use std::collections::HashMap;
+use std::collections::BTreeMap;
+use std::ops::Bound;
 
 use uuid::Uuid;
 use serde::{Serialize};
@@ -11,11 +12,12 @@ use serde::de::DeserializeOwned;
 use log::{trace, debug, info, warn, error};
 
 //use crate::object::ObjKey;
-use crate::keystore::KeyStore;
-use crate::RResult;
+use crate::keystore::{KeyStore, ListQuery, Page};
+use crate::{RResult, RustorError};
 
-// to be re-constructable from JSON, this HashMap must contain objects, not references (T, not &T)
-type Index<T> = HashMap<Uuid, T>;
+// to be re-constructable from JSON, this map must contain objects, not references (T, not &T).
+// it's ordered so that listings can resume from a cursor
+type Index<T> = BTreeMap<Uuid, T>;
 
 #[derive(Debug)]
 pub struct JsonKeystore<T: Serialize + DeserializeOwned + fmt::Debug> {
@@ -58,8 +60,7 @@ impl<'a, T> JsonKeystore<T> where T: Serialize + DeserializeOwned + fmt::Debug {
             .create(true)
             .write(true)
             .truncate(true)
-            .open(self.path.as_path())
-            .unwrap();
+            .open(self.path.as_path())?;
         serde_json::to_writer_pretty(idxfile, &self.keystore)?;
         Ok(())
     }
@@ -81,7 +82,7 @@ impl<T> KeyStore<T> for JsonKeystore<T> where T: Serialize + DeserializeOwned +
     {
         trace!("uuid: {:?}, key: {:?}", &uuid, &key);
         let resp = self.keystore.insert(uuid, key);
-        self.write_index()?;
+        self.write_index().map_err(|e| RustorError::KeyStore { uuid: Some(uuid), reason: e.to_string() })?;
         Ok(resp)
     }
     fn get(&self, uuid: &Uuid) -> RResult<Option<&T>> {
@@ -91,9 +92,17 @@ impl<T> KeyStore<T> for JsonKeystore<T> where T: Serialize + DeserializeOwned +
     }
     fn delete(&mut self, uuid: &Uuid) -> RResult<Option<T>> {
         let key = self.keystore.remove(uuid);
-        self.write_index()?;
+        self.write_index().map_err(|e| RustorError::KeyStore { uuid: Some(*uuid), reason: e.to_string() })?;
         Ok(key)
     }
+    fn list(&self, query: &ListQuery<T>) -> RResult<Page<(Uuid, &T)>> {
+        let start = match query.after {
+            Some(cursor) => Bound::Excluded(cursor),
+            None => Bound::Unbounded,
+        };
+        let keys = self.keystore.range((start, Bound::Unbounded)).map(|(uuid, key)| (*uuid, key));
+        Ok(query.page(keys, |key| *key))
+    }
     /*
     fn mset(&self, objects: &HashMap<Uuid, T>) -> io::Result<HashMap<Uuid, io::Result<()>>> {
         let mut results: HashMap<Uuid, io::Result<()>> = HashMap::new();
@@ -113,3 +122,46 @@ impl<T> KeyStore<T> for JsonKeystore<T> where T: Serialize + DeserializeOwned +
     */
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keystore::KeyFilter;
+    use crate::object::{ObjKey, ObjectMetadata};
+
+    #[test]
+    fn test_list_pages() {
+        let path = std::env::temp_dir().join(format!("rustor-json_keystore-list-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut ks = JsonKeystore::<ObjKey>::new(path.clone());
+        for size in 0..10 {
+            let uuid = Uuid::new_v4();
+            let metadata = ObjectMetadata { created: 100 + size, ..Default::default() };
+            ks.set(uuid, ObjKey { uuid, size, metadata, ..Default::default() }).unwrap();
+        }
+
+        // pages of 4 cover every key once, in uuid order
+        let mut seen = Vec::new();
+        let mut cursor = None;
+        loop {
+            let page = ks.list(&ListQuery::default().after(cursor).limit(4)).unwrap();
+            assert!(page.keys.len() <= 4);
+            seen.extend(page.keys.iter().map(|(uuid, _)| *uuid));
+            cursor = page.next;
+            if cursor.is_none() {
+                break;
+            }
+        }
+        assert_eq!(seen, ks.get_objects().keys().copied().collect::<Vec<_>>());
+        assert!(seen.windows(2).all(|w| w[0] < w[1]));
+
+        let filter = KeyFilter { min_size: Some(2), max_size: Some(8), created_after: Some(105) };
+        let matches = |key: &ObjKey| filter.matches(key);
+        let page = ks.list(&ListQuery::default().filter(&matches)).unwrap();
+        let mut sizes: Vec<u64> = page.keys.iter().map(|(_, k)| k.size).collect();
+        sizes.sort();
+        assert_eq!((sizes, page.next), (vec![6, 7, 8], None));
+
+        let _ = std::fs::remove_file(&path);
+    }
+}
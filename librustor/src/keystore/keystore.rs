@@ -3,13 +3,97 @@ use uuid::Uuid;
 use serde::{Serialize};
 use serde::de::DeserializeOwned;
 
-//use crate::object::ObjKey;
+use crate::object::ObjKey;
 
 use crate::RResult;
+
+/// Which keys `KeyStore::list` returns: those after the cursor `after`, in uuid order, that pass
+/// `filter`, at most `limit` of them
+pub struct ListQuery<'a, T> {
+    /// the `next` cursor of the previous page
+    pub after: Option<Uuid>,
+    /// 0 for no limit
+    pub limit: usize,
+    pub filter: Option<&'a dyn Fn(&T) -> bool>,
+}
+
+impl<T> Default for ListQuery<'_, T> {
+    fn default() -> Self {
+        Self { after: None, limit: 0, filter: None }
+    }
+}
+
+impl<'a, T> ListQuery<'a, T> {
+    pub fn after(mut self, cursor: Option<Uuid>) -> Self {
+        self.after = cursor;
+        self
+    }
+
+    pub fn limit(mut self, limit: usize) -> Self {
+        self.limit = limit;
+        self
+    }
+
+    pub fn filter(mut self, filter: &'a dyn Fn(&T) -> bool) -> Self {
+        self.filter = Some(filter);
+        self
+    }
+
+    pub fn matches(&self, key: &T) -> bool {
+        self.filter.is_none_or(|f| f(key))
+    }
+
+    /// the page of `keys`, which must be in uuid order and start after the cursor
+    pub fn page<K, I: Iterator<Item = (Uuid, K)>>(&self, keys: I, key: impl Fn(&K) -> &T) -> Page<(Uuid, K)> {
+        let mut keys = keys.filter(|(_, k)| self.matches(key(k)));
+        let limit = if self.limit == 0 { usize::MAX } else { self.limit };
+        let page: Vec<_> = keys.by_ref().take(limit).collect();
+        let next = match keys.next() {
+            Some(_) => page.last().map(|(uuid, _)| *uuid),
+            None => None,
+        };
+        Page { keys: page, next }
+    }
+}
+
+/// One page of a listing. `next` is the cursor for the following page, if there is one
+#[derive(Debug)]
+pub struct Page<K> {
+    pub keys: Vec<K>,
+    pub next: Option<Uuid>,
+}
+
+impl<T: Clone> Page<(Uuid, &T)> {
+    /// the page with copies of the keys, e.g. to hold it after releasing a lock on the keystore
+    pub fn cloned(self) -> Page<T> {
+        Page { keys: self.keys.into_iter().map(|(_, key)| key.clone()).collect(), next: self.next }
+    }
+}
+
+/// Filters for listing object keys by size and age
+#[derive(Debug, Clone, Copy, Default)]
+pub struct KeyFilter {
+    /// bytes, inclusive
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+    /// seconds since the unix epoch, exclusive
+    pub created_after: Option<u64>,
+}
+
+impl KeyFilter {
+    pub fn matches(&self, key: &ObjKey) -> bool {
+        self.min_size.is_none_or(|min| key.size >= min)
+            && self.max_size.is_none_or(|max| key.size <= max)
+            && self.created_after.is_none_or(|t| key.metadata.created > t)
+    }
+}
+
 pub trait KeyStore<T: Serialize + DeserializeOwned> {
     fn set(&mut self, uuid: Uuid, object: T) -> RResult<Option<T>>;
     fn get(&self, uuid: &Uuid) -> RResult<Option<&T>>;
     fn delete(&mut self, uuid: &Uuid) -> RResult<Option<T>>;
+    /// a page of the stored keys, in uuid order
+    fn list(&self, query: &ListQuery<T>) -> RResult<Page<(Uuid, &T)>>;
 
     //fn mset(&self, objects: &HashMap<Uuid, T>) -> io::Result<HashMap<Uuid, io::Result<()>>>;
     //fn mget(&self, uuids: Vec<Uuid>) -> io::Result<HashMap<Uuid, io::Result<T>>>;
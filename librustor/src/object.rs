@@ -1,13 +1,15 @@
 use uuid::Uuid;
 use std::hash::{Hash, Hasher};
+use std::collections::BTreeMap;
 use std::collections::hash_map::DefaultHasher;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use serde::{Serialize, Deserialize};
 
 pub type ObjectID = Uuid;
 pub type BlkDevID = Uuid;
 
-#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct ManifestLocation {
     pub blkdevid: Option<BlkDevID>,
     /// starting LBA
@@ -16,7 +18,7 @@ pub struct ManifestLocation {
     pub span: u64,  
 }
 
-#[derive(Serialize, Deserialize, Debug, Default)]
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
 pub struct Manifest {
     pub shards: Vec<ManifestLocation>,
 }
@@ -27,6 +29,61 @@ impl Manifest {
             shards: Vec::new()
         }
     }
+
+    /// point every shard stored on `from` at `to` instead. returns true if any shard moved
+    pub fn replace_device(&mut self, from: BlkDevID, to: BlkDevID) -> bool {
+        let mut moved = false;
+        for shard in self.shards.iter_mut().filter(|s| s.blkdevid == Some(from)) {
+            shard.blkdevid = Some(to);
+            moved = true;
+        }
+        moved
+    }
+
+    /// total blocks across all shards
+    pub fn blocks(&self) -> u64 {
+        self.shards.iter().map(|s| s.span).sum()
+    }
+
+    /// the extents holding `span` blocks of the object starting at its block `start`, following
+    /// the shards in order
+    pub fn extents(&self, start: u64, span: u64) -> Vec<ManifestLocation> {
+        let mut extents = Vec::new();
+        let (mut skip, mut left) = (start, span);
+        for shard in self.shards.iter() {
+            if left == 0 {
+                break;
+            }
+            if skip >= shard.span {
+                skip -= shard.span;
+                continue;
+            }
+            let n = left.min(shard.span - skip);
+            extents.push(ManifestLocation { blkdevid: shard.blkdevid, lba: shard.lba + skip, span: n });
+            skip = 0;
+            left -= n;
+        }
+        extents
+    }
+
+    /// keep the first `blocks` blocks and return the rest
+    pub fn split_off(&mut self, blocks: u64) -> Manifest {
+        let mut tail = Manifest::new();
+        let mut kept = 0;
+        for shard in self.shards.iter_mut() {
+            if kept >= blocks {
+                tail.shards.push(*shard);
+                shard.span = 0;
+            } else if kept + shard.span > blocks {
+                let keep = blocks - kept;
+                tail.shards.push(ManifestLocation { lba: shard.lba + keep, span: shard.span - keep, ..*shard });
+                shard.span = keep;
+            }
+            kept += shard.span;
+        }
+        self.shards.retain(|s| s.span > 0);
+        tail
+    }
 }
 
 impl Clone for Manifest {
@@ -36,18 +93,69 @@ impl Clone for Manifest {
 }
 
 
+/// seconds since the unix epoch
+pub fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// Descriptive data kept with an object's key. Keys stored before metadata was kept load with
+/// the defaults
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct ObjectMetadata {
+    /// seconds since the unix epoch, 0 if unknown
+    pub created: u64,
+    pub modified: u64,
+    pub content_type: Option<String>,
+    /// user-defined key/value pairs
+    pub tags: BTreeMap<String, String>,
+}
+
+impl ObjectMetadata {
+    /// metadata with just a content type
+    pub fn with_content_type(content_type: &str) -> Self {
+        Self { content_type: Some(content_type.to_string()), ..Default::default() }
+    }
+
+    pub fn tag(mut self, key: &str, value: &str) -> Self {
+        self.tags.insert(key.to_string(), value.to_string());
+        self
+    }
+
+    /// the metadata of an object stored at `now`
+    pub fn stamped(mut self, now: u64) -> Self {
+        self.created = now;
+        self.modified = now;
+        self
+    }
+
+    /// take the content type and tags of `update`, keeping the creation time
+    pub fn update(&mut self, update: ObjectMetadata, now: u64) {
+        self.content_type = update.content_type;
+        self.tags = update.tags;
+        self.modified = now;
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Default)]
 pub struct ObjKey {
     pub uuid: Uuid,
     pub hash: u64,
     pub size: u64,
     pub manifest: Manifest,
+    #[serde(default)]
+    pub metadata: ObjectMetadata,
+    /// the data of an object small enough to keep in its key, which then has no shards
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub inline: Option<Vec<u8>>,
 }
 
 impl Clone for ObjKey {
     fn clone(&self) -> ObjKey {
         ObjKey {
             manifest: self.manifest.clone(),
+            metadata: self.metadata.clone(),
+            inline: self.inline.clone(),
             ..*self
         }
     }
@@ -88,3 +196,51 @@ impl IsObject for Object {
     fn get_data(&self) -> &Option<Vec<u8>> { &self.data }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manifest(shards: &[(u64, u64)]) -> Manifest {
+        Manifest { shards: shards.iter().map(|(lba, span)| ManifestLocation { blkdevid: None, lba: *lba, span: *span }).collect() }
+    }
+
+    fn spans(shards: &[ManifestLocation]) -> Vec<(u64, u64)> {
+        shards.iter().map(|s| (s.lba, s.span)).collect()
+    }
+
+    #[test]
+    fn test_extents() {
+        let m = manifest(&[(10, 4), (50, 2), (20, 3)]);
+        assert_eq!(m.blocks(), 9);
+        assert_eq!(spans(&m.extents(0, 2)), vec![(10, 2)]);
+        assert_eq!(spans(&m.extents(3, 4)), vec![(13, 1), (50, 2), (20, 1)]);
+        assert_eq!(spans(&m.extents(8, 5)), vec![(22, 1)]);
+        assert!(m.extents(9, 1).is_empty());
+    }
+
+    #[test]
+    fn test_keys_without_metadata_load() {
+        let old = r#"{"uuid":"4d71d03f-f19b-5d9e-8523-9628ba18063c","hash":1,"size":5,"manifest":{"shards":[]}}"#;
+        let key: ObjKey = serde_json::from_str(old).unwrap();
+        assert_eq!(key.metadata, ObjectMetadata::default());
+
+        let tagged = ObjKey { metadata: ObjectMetadata::with_content_type("text/plain").tag("a", "b"), ..key };
+        let json = serde_json::to_string(&tagged).unwrap();
+        assert_eq!(serde_json::from_str::<ObjKey>(&json).unwrap().metadata, tagged.metadata);
+        assert!(!json.contains("inline"));
+    }
+
+    #[test]
+    fn test_split_off() {
+        let mut m = manifest(&[(10, 4), (50, 2), (20, 3)]);
+        let tail = m.split_off(5);
+        assert_eq!(spans(&m.shards), vec![(10, 4), (50, 1)]);
+        assert_eq!(spans(&tail.shards), vec![(51, 1), (20, 3)]);
+
+        let mut m = manifest(&[(10, 4)]);
+        assert!(m.split_off(4).shards.is_empty());
+        assert_eq!(spans(&m.split_off(0).shards), vec![(10, 4)]);
+        assert!(m.shards.is_empty());
+    }
+}
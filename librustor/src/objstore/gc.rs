@@ -0,0 +1,153 @@
+use crate::RResult;
+use crate::freelist::FreeList;
+use crate::keystore::{KeyStore, ListQuery};
+use crate::object::ObjKey;
+use super::snapshot::{self, Snapshot};
+
+#[allow(unused_imports)]
+use log::{trace, debug, info, warn, error};
+
+/// What a collection found, in blocks
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct GcReport {
+    /// blocks referred to by a stored key or a snapshot
+    pub marked: u64,
+    /// blocks that were neither free nor referred to by anything. they are returned to the free
+    /// list, unless this was a dry run
+    pub reclaimed: u64,
+    /// the unreferenced areas as (lba, span)
+    pub areas: Vec<(u64, u64)>,
+    pub dry_run: bool,
+}
+
+/// merge (lba, span) areas into disjoint (start, end) ranges, in lba order
+fn ranges<I: Iterator<Item = (u64, u64)>>(areas: I) -> Vec<(u64, u64)> {
+    let mut areas: Vec<(u64, u64)> = areas.filter(|(_, span)| *span > 0).map(|(lba, span)| (lba, lba + span)).collect();
+    areas.sort_unstable();
+    let mut merged: Vec<(u64, u64)> = Vec::with_capacity(areas.len());
+    for (start, end) in areas {
+        match merged.last_mut() {
+            Some(last) if start <= last.1 => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    merged
+}
+
+/// the ranges of blocks referred to by any key in `keys` or, if given, any snapshot
+fn mark(keys: &dyn KeyStore<ObjKey>, snapshots: Option<&dyn KeyStore<Snapshot>>) -> RResult<Vec<(u64, u64)>> {
+    let page = keys.list(&ListQuery::default())?;
+    let mut areas: Vec<(u64, u64)> = page.keys.iter()
+        .flat_map(|(_, key)| key.manifest.shards.iter())
+        .map(|shard| (shard.lba, shard.span))
+        .collect();
+    if let Some(snapshots) = snapshots {
+        areas.extend(snapshot::held(snapshots)?.into_iter().map(|shard| (shard.lba, shard.span)));
+    }
+    Ok(ranges(areas.into_iter()))
+}
+
+/// Mark every block a key or snapshot refers to, and sweep the free list: any block that is
+/// neither marked nor free was leaked, e.g. by a put that crashed after allocating, and is
+/// returned to the free list. with `dry_run`, only report what would be freed.
+///
+/// The caller must hold off puts for the duration, so that blocks allocated for data that has no
+/// key yet aren't taken for leaks
+pub fn collect(keys: &dyn KeyStore<ObjKey>, snapshots: Option<&dyn KeyStore<Snapshot>>, freelist: &mut dyn FreeList,
+    dry_run: bool) -> RResult<GcReport>
+{
+    let marked = mark(keys, snapshots)?;
+    let mut report = GcReport { marked: marked.iter().map(|(start, end)| end - start).sum(), dry_run, ..Default::default() };
+
+    // whatever is left between the marked and free ranges is unreferenced
+    let capacity = freelist.capacity();
+    let accounted = ranges(marked.into_iter().map(|(start, end)| (start, end - start)).chain(freelist.free_areas()));
+    let mut lba = 0;
+    for (start, end) in accounted.into_iter().chain(std::iter::once((capacity, capacity))) {
+        let start = start.min(capacity);
+        if start > lba {
+            report.areas.push((lba, start - lba));
+        }
+        lba = lba.max(end);
+    }
+    report.reclaimed = report.areas.iter().map(|(_, span)| span).sum();
+
+    debug!("gc: {} blocks marked, {} unreferenced in {} areas", report.marked, report.reclaimed, report.areas.len());
+    if !dry_run {
+        for (lba, span) in report.areas.iter() {
+            trace!("gc: freeing {} blocks at {}", span, lba);
+            freelist.free(*span, *lba)?;
+        }
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+    use crate::freelist::{BitmapFreelist, VecFreeList};
+    use crate::keystore::JsonKeystore;
+
+    #[test]
+    fn test_ranges() {
+        assert_eq!(ranges(vec![(10, 5), (0, 2), (2, 3), (12, 1), (20, 0)].into_iter()), vec![(0, 5), (10, 15)]);
+    }
+
+    #[test]
+    fn test_collect() {
+        let path = |ext: &str| std::env::temp_dir().join(format!("rustor-gc-collect-{}.{}", std::process::id(), ext));
+        let _ = std::fs::remove_file(path("keys.json"));
+        let _ = std::fs::remove_file(path("snapshots.json"));
+        let mut keys = JsonKeystore::<ObjKey>::new(path("keys.json"));
+        let mut snapshots = JsonKeystore::<Snapshot>::new(path("snapshots.json"));
+        let mut fl = VecFreeList::new(32);
+
+        let mut key = |n: u8, blocks: u64| {
+            let uuid = Uuid::from_bytes([n; 16]);
+            ObjKey { uuid, size: blocks * 4096, manifest: fl.allocate(blocks * 4096).unwrap(), ..Default::default() }
+        };
+        let a = key(1, 2);
+        let held = key(2, 3);
+        let _leaked = key(3, 4);
+        let b = key(4, 1);
+        keys.set(held.uuid, held.clone()).unwrap();
+        snapshot::create(&keys, &mut snapshots, "held").unwrap();
+        keys.delete(&held.uuid).unwrap();
+        keys.set(a.uuid, a.clone()).unwrap();
+        keys.set(b.uuid, b.clone()).unwrap();
+        assert_eq!(fl.free_blocks(), 22);
+
+        // a dry run changes nothing
+        let report = collect(&keys, Some(&snapshots), &mut fl, true).unwrap();
+        assert_eq!(report.marked, 6);
+        assert_eq!(report.reclaimed, 4);
+        assert!(report.dry_run);
+        assert_eq!(fl.free_blocks(), 22);
+
+        // without the snapshots, the held blocks are unreferenced too
+        assert_eq!(collect(&keys, None, &mut fl, true).unwrap().reclaimed, 7);
+
+        let report = collect(&keys, Some(&snapshots), &mut fl, false).unwrap();
+        assert_eq!(report.areas, vec![(5, 4)]);
+        assert_eq!(fl.free_blocks(), 26);
+        assert_eq!(collect(&keys, Some(&snapshots), &mut fl, false).unwrap().reclaimed, 0);
+
+        let _ = std::fs::remove_file(path("keys.json"));
+        let _ = std::fs::remove_file(path("snapshots.json"));
+    }
+
+    #[test]
+    fn test_collect_unreferenced_tail() {
+        let path = std::env::temp_dir().join(format!("rustor-gc-tail-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let keys = JsonKeystore::<ObjKey>::new(path.clone());
+        let mut fl = BitmapFreelist::new(16);
+        fl.take(4, 12).unwrap();
+
+        let report = collect(&keys, None, &mut fl, false).unwrap();
+        assert_eq!(report.areas, vec![(12, 4)]);
+        assert_eq!(fl.free(), 16);
+        let _ = std::fs::remove_file(&path);
+    }
+}
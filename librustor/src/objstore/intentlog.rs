@@ -0,0 +1,259 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use serde::{Serialize, Deserialize};
+use uuid::Uuid;
+
+use crate::{RResult, RustorError};
+use crate::freelist::{FreeList, FreeListError};
+use crate::keystore::KeyStore;
+use crate::object::{ObjKey, Manifest};
+
+#[allow(unused_imports)]
+use log::{trace, debug, info, warn, error};
+
+#[derive(Serialize, Deserialize, Debug)]
+enum Intent {
+    /// blocks were allocated for an object that is about to be written
+    Begin { uuid: Uuid, manifest: Manifest },
+    /// the object's key was stored
+    Commit { uuid: Uuid },
+    /// the put failed and its allocation was released
+    Abort { uuid: Uuid },
+    /// a streamed put, begun under a temporary id, now has its uuid and final allocation
+    Rename { from: Uuid, uuid: Uuid, manifest: Manifest },
+}
+
+/// A write-ahead log of the allocations made by puts in progress.
+///
+/// A put records its allocation before writing any data and marks it committed once the key is
+/// stored. After a crash, any allocation that was begun but neither committed nor aborted belongs
+/// to a put that never finished, and `recover` hands it back so the blocks can be reclaimed.
+///
+/// Records are JSON lines, synced as they are written. The file is truncated whenever no put is
+/// in flight, so it only ever holds the records of unfinished puts.
+pub struct IntentLog {
+    path: PathBuf,
+    file: File,
+    pending: HashMap<Uuid, Manifest>,
+}
+
+impl IntentLog {
+    /// open the log at `path`, creating it if necessary, and load any unfinished puts
+    pub fn open(path: PathBuf) -> RResult<Self> {
+        let file = OpenOptions::new().read(true).append(true).create(true).open(&path)?;
+
+        let mut pending = HashMap::new();
+        for line in BufReader::new(&file).lines() {
+            let line = line?;
+            match serde_json::from_str::<Intent>(&line) {
+                Ok(Intent::Begin { uuid, manifest }) => { pending.insert(uuid, manifest); }
+                Ok(Intent::Commit { uuid }) | Ok(Intent::Abort { uuid }) => { pending.remove(&uuid); }
+                Ok(Intent::Rename { from, uuid, manifest }) => {
+                    pending.remove(&from);
+                    pending.insert(uuid, manifest);
+                }
+                // a record torn by a crash mid-write; its put never got further
+                Err(e) => warn!("ignoring unreadable intent record {:?}: {}", &line, e),
+            }
+        }
+        debug!("intent log {:?} has {} unfinished puts", &path, pending.len());
+
+        // rewrite the log with just the unfinished puts, so nothing is appended after a torn
+        // record. the new log replaces the old one atomically so a crash here loses nothing
+        drop(file);
+        let tmp = path.with_extension("tmp");
+        let mut log = Self {
+            file: OpenOptions::new().write(true).create(true).truncate(true).open(&tmp)?,
+            path: tmp,
+            pending: HashMap::new(),
+        };
+        for (uuid, manifest) in pending.iter() {
+            log.begin(*uuid, manifest)?;
+        }
+        // the new log must be on disk before it replaces the old one, and the rename itself only
+        // once the directory is synced
+        log.file.sync_all()?;
+        std::fs::rename(&log.path, &path)?;
+        sync_dir(&path)?;
+        log.file = OpenOptions::new().append(true).open(&path)?;
+        log.path = path;
+        Ok(log)
+    }
+
+    pub fn path(&self) -> &PathBuf {
+        &self.path
+    }
+
+    /// allocations of puts that have begun but not finished
+    pub fn pending(&self) -> impl Iterator<Item=(&Uuid, &Manifest)> {
+        self.pending.iter()
+    }
+
+    fn append(&mut self, intent: &Intent) -> RResult<()> {
+        let mut line = serde_json::to_vec(intent)?;
+        line.push(b'\n');
+        self.file.write_all(&line)?;
+        self.file.sync_data()?;
+        Ok(())
+    }
+
+    /// forget finished puts once none are in flight
+    fn compact(&mut self) -> RResult<()> {
+        if self.pending.is_empty() {
+            self.file.set_len(0)?;
+        }
+        Ok(())
+    }
+
+    pub fn begin(&mut self, uuid: Uuid, manifest: &Manifest) -> RResult<()> {
+        trace!("begin put of {:?}", &uuid);
+        self.append(&Intent::Begin { uuid, manifest: manifest.clone() })?;
+        self.pending.insert(uuid, manifest.clone());
+        Ok(())
+    }
+
+    /// replace the unfinished put `from` with `uuid` and its allocation with `manifest`, in one
+    /// record so that a crash leaves the put under exactly one of them
+    pub fn rename(&mut self, from: Uuid, uuid: Uuid, manifest: &Manifest) -> RResult<()> {
+        trace!("rename put of {:?} to {:?}", &from, &uuid);
+        self.append(&Intent::Rename { from, uuid, manifest: manifest.clone() })?;
+        self.pending.remove(&from);
+        self.pending.insert(uuid, manifest.clone());
+        Ok(())
+    }
+
+    pub fn commit(&mut self, uuid: Uuid) -> RResult<()> {
+        trace!("commit put of {:?}", &uuid);
+        self.pending.remove(&uuid);
+        self.append(&Intent::Commit { uuid })?;
+        self.compact()
+    }
+
+    pub fn abort(&mut self, uuid: Uuid) -> RResult<()> {
+        trace!("abort put of {:?}", &uuid);
+        self.pending.remove(&uuid);
+        self.append(&Intent::Abort { uuid })?;
+        self.compact()
+    }
+
+    /// Resolve every unfinished put against `keystore` and clear the log.
+    ///
+    /// A put whose key made it into the keystore with the logged manifest only missed its commit
+    /// record. Every other unfinished put was interrupted before its key was stored: its
+    /// allocation is orphaned and is returned for the caller to release.
+    pub fn recover(&mut self, keystore: &dyn KeyStore<ObjKey>) -> RResult<Vec<Manifest>> {
+        let mut orphans = Vec::new();
+        for (uuid, manifest) in self.pending.drain() {
+            let stored = keystore.get(&uuid)?
+                .map(|key| key.manifest.shards == manifest.shards)
+                .unwrap_or(false);
+            if stored {
+                debug!("put of {:?} completed", &uuid);
+            } else {
+                info!("reclaiming orphaned allocation of {:?}: {:?}", &uuid, &manifest);
+                orphans.push(manifest);
+            }
+        }
+        self.compact()?;
+        Ok(orphans)
+    }
+}
+
+/// hand the allocations `IntentLog::recover` returned back to `freelist`
+pub(crate) fn release_orphans(freelist: &mut dyn FreeList, orphans: &[Manifest]) -> RResult<()> {
+    for manifest in orphans.iter() {
+        match freelist.release(manifest) {
+            Ok(()) => (),
+            // a free list rebuilt from the keystore never took the orphaned blocks
+            Err(RustorError::FreeList(FreeListError::ReleaseOfFreeArea { .. })) => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// make the creation or renaming of the file at `path` durable
+fn sync_dir(path: &Path) -> RResult<()> {
+    // directories can only be opened, and so synced, on unix
+    #[cfg(unix)]
+    {
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        File::open(dir)?.sync_all()?;
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::object::ManifestLocation;
+    use crate::keystore::JsonKeystore;
+
+    fn path(test: &str, ext: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("rustor-intents-{}-{}.{}", test, std::process::id(), ext))
+    }
+
+    fn manifest(lba: u64, span: u64) -> Manifest {
+        Manifest { shards: vec![ManifestLocation { blkdevid: None, lba, span }] }
+    }
+
+    #[test]
+    fn test_compacts_when_idle() {
+        let _ = std::fs::remove_file(path("compact", "log"));
+        let mut log = IntentLog::open(path("compact", "log")).unwrap();
+        let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+        log.begin(a, &manifest(0, 1)).unwrap();
+        log.begin(b, &manifest(1, 1)).unwrap();
+        log.commit(a).unwrap();
+        assert!(std::fs::metadata(path("compact", "log")).unwrap().len() > 0);
+
+        log.abort(b).unwrap();
+        assert_eq!(std::fs::metadata(path("compact", "log")).unwrap().len(), 0);
+        let _ = std::fs::remove_file(path("compact", "log"));
+    }
+
+    #[test]
+    fn test_recover_after_crash() {
+        let _ = std::fs::remove_file(path("crash", "log"));
+        let _ = std::fs::remove_file(path("crash", "json"));
+        let mut keys = JsonKeystore::<ObjKey>::new(path("crash", "json"));
+        let (stored, lost, done) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        {
+            let mut log = IntentLog::open(path("crash", "log")).unwrap();
+            log.begin(done, &manifest(0, 2)).unwrap();
+            log.begin(stored, &manifest(2, 2)).unwrap();
+            log.begin(lost, &manifest(4, 3)).unwrap();
+            log.commit(done).unwrap();
+            // a streamed put is renamed once its data is all written, with any unused blocks
+            // trimmed from its allocation
+            let (tmp, streamed) = (Uuid::new_v4(), Uuid::new_v4());
+            log.begin(tmp, &manifest(7, 4)).unwrap();
+            log.rename(tmp, streamed, &manifest(7, 2)).unwrap();
+            // the keys of `stored` and `streamed` reach the keystore but the crash comes before
+            // their commits
+            keys.set(stored, ObjKey { uuid: stored, manifest: manifest(2, 2), ..Default::default() }).unwrap();
+            keys.set(streamed, ObjKey { uuid: streamed, manifest: manifest(7, 2), ..Default::default() }).unwrap();
+        }
+        // and the crash tears the record being written
+        let mut file = OpenOptions::new().append(true).open(path("crash", "log")).unwrap();
+        file.write_all(b"{\"Begin\":{\"uu").unwrap();
+
+        let mut log = IntentLog::open(path("crash", "log")).unwrap();
+        assert_eq!(log.pending().count(), 3);
+        let orphans = log.recover(&keys).unwrap();
+        assert_eq!(orphans.len(), 1);
+        assert_eq!(orphans[0].shards, manifest(4, 3).shards);
+
+        assert_eq!(IntentLog::open(path("crash", "log")).unwrap().pending().count(), 0);
+        let _ = std::fs::remove_file(path("crash", "log"));
+        let _ = std::fs::remove_file(path("crash", "json"));
+    }
+}
@@ -2,3 +2,21 @@ pub mod objstore;
 //pub mod filestore;
 
 pub use objstore::*;
+
+pub mod intentlog;
+pub use intentlog::IntentLog;
+
+pub mod stream;
+pub use stream::ObjectReader;
+
+pub mod namespace;
+pub use namespace::{Namespace, Bucket, BucketEntry, NamespaceRecord};
+
+pub mod snapshot;
+pub use snapshot::Snapshot;
+
+pub mod gc;
+pub use gc::GcReport;
+
+pub mod shared;
+pub use shared::SharedObjectStore;
@@ -0,0 +1,563 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::Bound;
+
+use serde::{Serialize, Deserialize};
+use uuid::Uuid;
+
+use crate::{RResult, RustorError};
+use crate::keygen::{KeyGen, GeneratesKeys};
+use crate::keystore::KeyStore;
+use crate::object::unix_time;
+use super::{ObjectStore, ObjectID};
+
+#[allow(unused_imports)]
+use log::{trace, debug, info, warn, error};
+
+/// the object a name in a bucket refers to
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct BucketEntry {
+    pub uuid: ObjectID,
+    /// bytes, counted against the bucket's quota
+    pub size: u64,
+    /// numbered from 1 within the bucket. 0 for names stored before the bucket kept versions
+    #[serde(default)]
+    pub version: u64,
+}
+
+/// A version of a name in a versioned bucket
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Version {
+    pub version: u64,
+    /// None for a tombstone, left when the name was deleted
+    pub uuid: Option<ObjectID>,
+    pub size: u64,
+    /// seconds since the unix epoch the version was replaced or deleted
+    pub retired: u64,
+}
+
+/// A named group of objects, each under a name chosen by the user
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Bucket {
+    pub name: String,
+    /// bytes the bucket may hold. an object stored under two names counts twice
+    pub quota: Option<u64>,
+    pub entries: BTreeMap<String, BucketEntry>,
+    /// keep replaced and deleted versions of names, until `Namespace::gc` expires them
+    #[serde(default)]
+    pub versioned: bool,
+    /// versions `gc` keeps of each name, counting the current one. None for all of them
+    #[serde(default)]
+    pub max_versions: Option<usize>,
+    /// the prior versions of each name, oldest first. only the current versions in `entries`
+    /// count against the quota
+    #[serde(default)]
+    pub history: BTreeMap<String, Vec<Version>>,
+    #[serde(default)]
+    pub last_version: u64,
+}
+
+impl Bucket {
+    /// bytes held, as counted against the quota
+    pub fn used(&self) -> u64 {
+        self.entries.values().map(|e| e.size).sum()
+    }
+
+    /// true if the current or a prior version of a name refers to `uuid`
+    pub fn refers_to(&self, uuid: ObjectID) -> bool {
+        self.entries.values().any(|e| e.uuid == uuid)
+            || self.history.values().flatten().any(|v| v.uuid == Some(uuid))
+    }
+
+    fn next_version(&mut self) -> u64 {
+        self.last_version += 1;
+        self.last_version
+    }
+
+    /// Retire the current version of `name`, leaving a tombstone if `deleted`. returns the uuid
+    /// to release if the bucket doesn't keep versions
+    fn retire(&mut self, name: &str, entry: BucketEntry, deleted: bool) -> Option<ObjectID> {
+        if !self.versioned {
+            return Some(entry.uuid);
+        }
+        let retired = unix_time();
+        let mut versions = vec![Version { version: entry.version, uuid: Some(entry.uuid), size: entry.size, retired }];
+        if deleted {
+            versions.push(Version { version: self.next_version(), uuid: None, size: 0, retired });
+        }
+        self.history.entry(name.to_string()).or_default().extend(versions);
+        None
+    }
+
+    /// Drop the versions of each name beyond `max_versions`, and the history of deleted names
+    /// that's down to tombstones. returns the expired versions
+    fn expire(&mut self) -> Vec<Version> {
+        let mut expired = Vec::new();
+        for (name, versions) in self.history.iter_mut() {
+            let current = self.entries.contains_key(name) as usize;
+            if let Some(max) = self.max_versions {
+                let keep = max.saturating_sub(current).min(versions.len());
+                expired.extend(versions.drain(..versions.len() - keep));
+            }
+            if current == 0 && versions.iter().all(|v| v.uuid.is_none()) {
+                expired.append(versions);
+            }
+        }
+        self.history.retain(|_, versions| !versions.is_empty());
+        expired
+    }
+
+    /// the entries whose names start with `prefix`, in name order
+    pub fn scan<'a>(&'a self, prefix: &'a str) -> impl Iterator<Item = (&'a String, &'a BucketEntry)> + 'a {
+        self.entries.range::<str, _>((Bound::Included(prefix), Bound::Unbounded))
+            .take_while(move |(name, _)| name.starts_with(prefix))
+    }
+}
+
+/// What a namespace keeps in its keystore: a record per bucket, under `bucket_id` of its name,
+/// a catalog of bucket names under the nil uuid, and the objects its puts stored under
+/// `objects_id`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum NamespaceRecord {
+    Catalog(BTreeSet<String>),
+    Bucket(Bucket),
+    Objects(BTreeSet<ObjectID>),
+}
+
+/// the keystore uuid of the bucket `name`
+pub fn bucket_id(name: &str) -> Uuid {
+    Uuid::new_v5(&Uuid::NAMESPACE_URL, format!("rustor:bucket:{}", name).as_bytes())
+}
+
+/// the keystore uuid of the objects the namespace stored
+pub fn objects_id() -> Uuid {
+    Uuid::new_v5(&Uuid::NAMESPACE_URL, b"rustor:objects")
+}
+
+/// Buckets of human-readable names mapped to objects in an ObjectStore. An object the namespace
+/// stored stays stored as long as any name in any bucket refers to it. Uuids are derived from
+/// the data, so a name may refer to an object that was already in the store; the namespace never
+/// deletes those
+pub struct Namespace<'a> {
+    store: &'a mut dyn ObjectStore,
+    records: &'a mut dyn KeyStore<NamespaceRecord>,
+}
+
+impl<'a> Namespace<'a> {
+    pub fn new(store: &'a mut dyn ObjectStore, records: &'a mut dyn KeyStore<NamespaceRecord>) -> Self {
+        Self { store, records }
+    }
+
+    /// the names of every bucket, in order
+    pub fn buckets(&self) -> RResult<Vec<String>> {
+        Ok(self.catalog()?.into_iter().collect())
+    }
+
+    pub fn bucket(&self, name: &str) -> RResult<Option<&Bucket>> {
+        match self.records.get(&bucket_id(name))? {
+            Some(NamespaceRecord::Bucket(bucket)) => Ok(Some(bucket)),
+            Some(_) => Err(corrupt(name)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn create_bucket(&mut self, name: &str, quota: Option<u64>) -> RResult<()> {
+        if name.is_empty() {
+            Err(RustorError::InvalidRequest("bucket names can't be empty".to_string()))?
+        }
+        if self.bucket(name)?.is_some() {
+            Err(RustorError::InvalidRequest(format!("bucket {} already exists", name)))?
+        }
+        self.save(Bucket { name: name.to_string(), quota, ..Default::default() })?;
+        let mut catalog = self.catalog()?;
+        catalog.insert(name.to_string());
+        self.records.set(Uuid::nil(), NamespaceRecord::Catalog(catalog))?;
+        Ok(())
+    }
+
+    /// remove an empty bucket
+    pub fn delete_bucket(&mut self, name: &str) -> RResult<()> {
+        let bucket = self.load(name)?;
+        if !bucket.entries.is_empty() || !bucket.history.is_empty() {
+            Err(RustorError::InvalidRequest(format!("bucket {} is not empty", name)))?
+        }
+        let mut catalog = self.catalog()?;
+        catalog.remove(name);
+        self.records.set(Uuid::nil(), NamespaceRecord::Catalog(catalog))?;
+        self.records.delete(&bucket_id(name))?;
+        Ok(())
+    }
+
+    /// change the bucket's quota. a bucket already over its new quota keeps its objects but
+    /// can't grow
+    pub fn set_quota(&mut self, name: &str, quota: Option<u64>) -> RResult<()> {
+        let mut bucket = self.load(name)?;
+        bucket.quota = quota;
+        self.save(bucket)
+    }
+
+    /// Keep prior versions of names in the bucket, or stop keeping them. `max_versions` counts the
+    /// current version; older ones are expired by `gc`
+    pub fn set_versioning(&mut self, name: &str, versioned: bool, max_versions: Option<usize>) -> RResult<()> {
+        let mut bucket = self.load(name)?;
+        bucket.versioned = versioned;
+        bucket.max_versions = max_versions;
+        self.save(bucket)
+    }
+
+    /// store `data` under `name`, replacing what was stored under it before
+    pub fn put(&mut self, bucket: &str, name: &str, data: &[u8]) -> RResult<ObjectID> {
+        if name.is_empty() {
+            Err(RustorError::InvalidRequest("object names can't be empty".to_string()))?
+        }
+        let mut record = self.load(bucket)?;
+        let size = data.len() as u64;
+        if let Some(quota) = record.quota {
+            let replaced = record.entries.get(name).map(|e| e.size).unwrap_or(0);
+            let needed = record.used() - replaced + size;
+            if needed > quota {
+                Err(RustorError::QuotaExceeded { bucket: bucket.to_string(), quota, needed })?
+            }
+        }
+
+        // only an object this put stores may be deleted later; one stored by a plain put of the
+        // same data isn't the namespace's to delete
+        let uuid = KeyGen {}.make_key(data)?.uuid;
+        let existed = self.store.head(uuid)?.is_some();
+        self.store.put(data)?;
+        if !existed {
+            if let Err(e) = self.set_owned(uuid, true) {
+                if let Err(delete) = self.store.delete(uuid) {
+                    warn!("could not delete {:?} after a failed put: {}", &uuid, delete);
+                }
+                return Err(e);
+            }
+        }
+        let version = record.next_version();
+        let old = record.entries.insert(name.to_string(), BucketEntry { uuid, size, version })
+            .and_then(|old| record.retire(name, old, false));
+        if let Err(e) = self.save(record) {
+            // best effort: the name was never recorded, so the object may be unreferenced
+            if let Err(release) = self.release(uuid) {
+                warn!("could not release {:?} after a failed put: {}", &uuid, release);
+            }
+            return Err(e);
+        }
+        if let Some(old) = old.filter(|old| *old != uuid) {
+            self.release(old)?;
+        }
+        Ok(uuid)
+    }
+
+    pub fn lookup(&self, bucket: &str, name: &str) -> RResult<Option<BucketEntry>> {
+        match self.bucket(bucket)? {
+            Some(record) => Ok(record.entries.get(name).copied()),
+            None => Err(RustorError::NameNotFound { bucket: bucket.to_string(), name: None }),
+        }
+    }
+
+    pub fn get(&mut self, bucket: &str, name: &str) -> RResult<Option<Vec<u8>>> {
+        match self.lookup(bucket, name)? {
+            Some(entry) => self.store.get(entry.uuid),
+            None => Ok(None),
+        }
+    }
+
+    /// every version of `name` still kept, oldest first, ending with the current one
+    pub fn versions(&self, bucket: &str, name: &str) -> RResult<Vec<Version>> {
+        let record = self.load(bucket)?;
+        let mut versions = record.history.get(name).cloned().unwrap_or_default();
+        if let Some(entry) = record.entries.get(name) {
+            versions.push(Version { version: entry.version, uuid: Some(entry.uuid), size: entry.size, retired: 0 });
+        }
+        Ok(versions)
+    }
+
+    /// the data of a version of `name`. None if the version isn't kept or is a tombstone
+    pub fn get_version(&mut self, bucket: &str, name: &str, version: u64) -> RResult<Option<Vec<u8>>> {
+        let found = self.versions(bucket, name)?.into_iter().find(|v| v.version == version);
+        match found.and_then(|v| v.uuid) {
+            Some(uuid) => self.store.get(uuid),
+            None => Ok(None),
+        }
+    }
+
+    /// Expire the versions each versioned bucket keeps beyond its `max_versions`, and delete the
+    /// objects nothing refers to any more, releasing their blocks. returns the versions expired
+    pub fn gc(&mut self) -> RResult<usize> {
+        let mut expired = 0;
+        for name in self.catalog()? {
+            let mut bucket = self.load(&name)?;
+            let versions = bucket.expire();
+            if versions.is_empty() {
+                continue;
+            }
+            debug!("expiring {} versions in {}", versions.len(), &name);
+            expired += versions.len();
+            self.save(bucket)?;
+            let mut uuids: Vec<ObjectID> = versions.iter().filter_map(|v| v.uuid).collect();
+            uuids.sort();
+            uuids.dedup();
+            for uuid in uuids {
+                self.release(uuid)?;
+            }
+        }
+        Ok(expired)
+    }
+
+    /// the names in the bucket starting with `prefix`, in order
+    pub fn list(&self, bucket: &str, prefix: &str) -> RResult<Vec<(String, BucketEntry)>> {
+        let record = self.bucket(bucket)?
+            .ok_or_else(|| RustorError::NameNotFound { bucket: bucket.to_string(), name: None })?;
+        Ok(record.scan(prefix).map(|(name, entry)| (name.clone(), *entry)).collect())
+    }
+
+    /// move the object under `from` to `to`, replacing what was stored under `to`
+    pub fn rename(&mut self, bucket: &str, from: &str, to: &str) -> RResult<()> {
+        if to.is_empty() {
+            Err(RustorError::InvalidRequest("object names can't be empty".to_string()))?
+        }
+        let mut record = self.load(bucket)?;
+        let entry = record.entries.remove(from)
+            .ok_or_else(|| RustorError::NameNotFound { bucket: bucket.to_string(), name: Some(from.to_string()) })?;
+        // in a versioned bucket, the move deletes one name and makes a new version of the other
+        let moved = if record.versioned {
+            BucketEntry { version: record.next_version(), ..entry }
+        } else {
+            entry
+        };
+        record.retire(from, entry, true);
+        let old = record.entries.insert(to.to_string(), moved)
+            .and_then(|old| record.retire(to, old, false));
+        self.save(record)?;
+        if let Some(old) = old.filter(|old| *old != entry.uuid) {
+            self.release(old)?;
+        }
+        Ok(())
+    }
+
+    /// Remove `name` from the bucket, and its object from the store if nothing else refers to it.
+    /// a versioned bucket keeps the object as a prior version and leaves a tombstone
+    pub fn delete(&mut self, bucket: &str, name: &str) -> RResult<Option<ObjectID>> {
+        let mut record = self.load(bucket)?;
+        let entry = match record.entries.remove(name) {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+        let released = record.retire(name, entry, true);
+        self.save(record)?;
+        if let Some(uuid) = released {
+            self.release(uuid)?;
+        }
+        Ok(Some(entry.uuid))
+    }
+
+    fn catalog(&self) -> RResult<BTreeSet<String>> {
+        match self.records.get(&Uuid::nil())? {
+            Some(NamespaceRecord::Catalog(names)) => Ok(names.clone()),
+            Some(_) => Err(corrupt("catalog")),
+            None => Ok(BTreeSet::new()),
+        }
+    }
+
+    /// the objects the namespace's puts stored, which it may delete once nothing refers to them
+    fn objects(&self) -> RResult<BTreeSet<ObjectID>> {
+        match self.records.get(&objects_id())? {
+            Some(NamespaceRecord::Objects(uuids)) => Ok(uuids.clone()),
+            Some(_) => Err(corrupt("objects")),
+            None => Ok(BTreeSet::new()),
+        }
+    }
+
+    /// record whether the namespace stored `uuid`
+    fn set_owned(&mut self, uuid: ObjectID, owned: bool) -> RResult<()> {
+        let mut objects = self.objects()?;
+        let changed = if owned { objects.insert(uuid) } else { objects.remove(&uuid) };
+        if changed {
+            self.records.set(objects_id(), NamespaceRecord::Objects(objects))?;
+        }
+        Ok(())
+    }
+
+    fn load(&self, name: &str) -> RResult<Bucket> {
+        self.bucket(name)?.cloned()
+            .ok_or_else(|| RustorError::NameNotFound { bucket: name.to_string(), name: None })
+    }
+
+    fn save(&mut self, bucket: Bucket) -> RResult<()> {
+        self.records.set(bucket_id(&bucket.name), NamespaceRecord::Bucket(bucket))?;
+        Ok(())
+    }
+
+    /// delete the object if the namespace stored it and no name in any bucket still refers to it
+    fn release(&mut self, uuid: ObjectID) -> RResult<()> {
+        if !self.objects()?.contains(&uuid) {
+            trace!("{:?} wasn't stored by the namespace", &uuid);
+            return Ok(());
+        }
+        for name in self.catalog()? {
+            if self.load(&name)?.refers_to(uuid) {
+                trace!("{:?} is still named in {}", &uuid, &name);
+                return Ok(());
+            }
+        }
+        self.store.delete(uuid)?;
+        self.set_owned(uuid, false)
+    }
+}
+
+fn corrupt(what: &str) -> RustorError {
+    RustorError::KeyStore { uuid: None, reason: format!("namespace record for {} has the wrong type", what) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use crate::blockstore::SingleDeviceBlockStore;
+    use crate::freelist::VecFreeList;
+    use crate::keystore::JsonKeystore;
+    use crate::object::ObjKey;
+    use crate::objstore::BasicObjectStore;
+
+    fn path(ext: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("rustor-namespace-buckets-{}.{}", std::process::id(), ext))
+    }
+
+    #[test]
+    fn test_buckets() {
+        for ext in ["keys.json", "buckets.json"] {
+            let _ = std::fs::remove_file(path(ext));
+        }
+        let mut bs = SingleDeviceBlockStore::new(path("bin"), 16 * 4096).unwrap();
+        let mut fl = VecFreeList::new(16);
+        let mut ks = JsonKeystore::<ObjKey>::new(path("keys.json"));
+        let mut store = BasicObjectStore::new(&mut bs, &mut fl, KeyGen {}, &mut ks);
+        let mut records = JsonKeystore::<NamespaceRecord>::new(path("buckets.json"));
+        let mut ns = Namespace::new(&mut store, &mut records);
+
+        ns.create_bucket("logs", Some(10)).unwrap();
+        ns.create_bucket("docs", None).unwrap();
+        assert!(ns.create_bucket("logs", None).is_err());
+        assert_eq!(ns.buckets().unwrap(), vec!["docs", "logs"]);
+
+        let a = ns.put("logs", "2024/a", b"hello").unwrap();
+        ns.put("logs", "2024/b", b"world").unwrap();
+        ns.put("logs", "2025/a", b"").unwrap();
+        assert_eq!(ns.get("logs", "2024/a").unwrap().unwrap(), b"hello");
+        let names: Vec<String> = ns.list("logs", "2024/").unwrap().into_iter().map(|(name, _)| name).collect();
+        assert_eq!(names, vec!["2024/a", "2024/b"]);
+
+        // 11 bytes is over the quota, but replacing a name only counts the difference
+        let full = ns.put("logs", "2025/b", b"!").unwrap_err();
+        assert!(matches!(full, RustorError::QuotaExceeded { needed: 11, .. }));
+        assert_eq!(full.http_status(), 507);
+        ns.put("logs", "2024/b", b"earth").unwrap();
+
+        // the same data under two names is stored once, and kept until both are gone
+        ns.put("docs", "greeting", b"hello").unwrap();
+        assert_eq!(ns.delete("logs", "2024/a").unwrap(), Some(a));
+        assert_eq!(ns.get("docs", "greeting").unwrap().unwrap(), b"hello");
+        ns.rename("docs", "greeting", "hi").unwrap();
+        assert_eq!(ns.lookup("docs", "greeting").unwrap(), None);
+        ns.delete("docs", "hi").unwrap();
+        assert!(store.get(a).unwrap().is_none());
+
+        // buckets survive a reload
+        let mut records = JsonKeystore::<NamespaceRecord>::new(path("buckets.json"));
+        let mut ns = Namespace::new(&mut store, &mut records);
+        assert_eq!(ns.get("logs", "2024/b").unwrap().unwrap(), b"earth");
+        assert!(ns.delete_bucket("logs").is_err());
+        ns.delete_bucket("docs").unwrap();
+        assert_eq!(ns.buckets().unwrap(), vec!["logs"]);
+        assert!(matches!(ns.get("docs", "hi"), Err(RustorError::NameNotFound { .. })));
+
+        for ext in ["bin", "keys.json", "buckets.json"] {
+            let _ = std::fs::remove_file(path(ext));
+        }
+    }
+
+    #[test]
+    fn test_keeps_objects_stored_without_a_name() {
+        let path = |ext: &str| std::env::temp_dir().join(format!("rustor-namespace-unowned-{}.{}", std::process::id(), ext));
+        for ext in ["keys.json", "buckets.json"] {
+            let _ = std::fs::remove_file(path(ext));
+        }
+        let mut bs = SingleDeviceBlockStore::new(path("bin"), 16 * 4096).unwrap();
+        let mut fl = VecFreeList::new(16);
+        let mut ks = JsonKeystore::<ObjKey>::new(path("keys.json"));
+        let mut store = BasicObjectStore::new(&mut bs, &mut fl, KeyGen {}, &mut ks);
+        let plain = store.put(b"stored directly").unwrap();
+        let mut records = JsonKeystore::<NamespaceRecord>::new(path("buckets.json"));
+        let mut ns = Namespace::new(&mut store, &mut records);
+
+        // naming data that was already stored, then dropping the name, leaves the object alone
+        ns.create_bucket("b", None).unwrap();
+        assert_eq!(ns.put("b", "f", b"stored directly").unwrap(), plain);
+        ns.put("b", "f", b"replaced").unwrap();
+        assert_eq!(ns.get("b", "f").unwrap().unwrap(), b"replaced");
+        ns.put("b", "g", b"stored directly").unwrap();
+        ns.delete("b", "g").unwrap();
+
+        // while what the namespace stored itself is deleted with its last name
+        let named = ns.lookup("b", "f").unwrap().unwrap().uuid;
+        ns.delete("b", "f").unwrap();
+        assert!(store.get(named).unwrap().is_none());
+        assert_eq!(store.get(plain).unwrap().unwrap(), b"stored directly");
+
+        for ext in ["bin", "keys.json", "buckets.json"] {
+            let _ = std::fs::remove_file(path(ext));
+        }
+    }
+
+    #[test]
+    fn test_versions() {
+        let path = |ext: &str| std::env::temp_dir().join(format!("rustor-namespace-versions-{}.{}", std::process::id(), ext));
+        for ext in ["keys.json", "buckets.json"] {
+            let _ = std::fs::remove_file(path(ext));
+        }
+        let mut bs = SingleDeviceBlockStore::new(path("bin"), 16 * 4096).unwrap();
+        let mut fl = VecFreeList::new(16);
+        let mut ks = JsonKeystore::<ObjKey>::new(path("keys.json"));
+        let mut store = BasicObjectStore::new(&mut bs, &mut fl, KeyGen {}, &mut ks);
+        let mut records = JsonKeystore::<NamespaceRecord>::new(path("buckets.json"));
+        let mut ns = Namespace::new(&mut store, &mut records);
+
+        ns.create_bucket("b", None).unwrap();
+        ns.set_versioning("b", true, Some(2)).unwrap();
+        let v1 = ns.put("b", "f", b"one").unwrap();
+        let v2 = ns.put("b", "f", b"two").unwrap();
+        let v3 = ns.put("b", "f", b"three").unwrap();
+        let versions: Vec<u64> = ns.versions("b", "f").unwrap().iter().map(|v| v.version).collect();
+        assert_eq!(versions, vec![1, 2, 3]);
+        assert_eq!(ns.get("b", "f").unwrap().unwrap(), b"three");
+        assert_eq!(ns.get_version("b", "f", 1).unwrap().unwrap(), b"one");
+
+        // only the newest two are kept, and the object of the oldest is deleted
+        assert_eq!(ns.gc().unwrap(), 1);
+        assert_eq!(ns.get_version("b", "f", 1).unwrap(), None);
+        assert_eq!(ns.get_version("b", "f", 2).unwrap().unwrap(), b"two");
+
+        // deleting leaves a tombstone and keeps the data
+        assert_eq!(ns.delete("b", "f").unwrap(), Some(v3));
+        assert_eq!(ns.get("b", "f").unwrap(), None);
+        let last = ns.versions("b", "f").unwrap();
+        assert_eq!(last.last().map(|v| (v.version, v.uuid)), Some((4, None)));
+        assert_eq!(ns.get_version("b", "f", 3).unwrap().unwrap(), b"three");
+        assert!(ns.delete_bucket("b").is_err());
+
+        // a tombstone and the version before it fit in two, and then nothing is left but tombstones
+        assert_eq!(ns.gc().unwrap(), 1);
+        ns.set_versioning("b", true, Some(1)).unwrap();
+        assert_eq!(ns.gc().unwrap(), 2);
+        assert!(ns.versions("b", "f").unwrap().is_empty());
+        ns.delete_bucket("b").unwrap();
+        for uuid in [v1, v2, v3] {
+            assert!(store.get(uuid).unwrap().is_none());
+        }
+        assert_eq!(fl.free_blocks(), 16);
+
+        for ext in ["bin", "keys.json", "buckets.json"] {
+            let _ = std::fs::remove_file(path(ext));
+        }
+    }
+}
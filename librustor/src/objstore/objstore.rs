@@ -1,11 +1,17 @@
+use std::io::Read;
+
 use uuid::Uuid;
 
-use crate::RResult;
+use crate::{RResult, RustorError};
 use crate::blockstore::blockstore::{ BlockStore };//, BlockDevice };
-use crate::object::ObjKey;
-use crate::keystore::KeyStore;
+use crate::object::{ObjKey, Manifest, ObjectMetadata, unix_time};
+use crate::keystore::{KeyStore, ListQuery, Page};
 use crate::keygen::{KeyGen, GeneratesKeys};
-use crate::freelist::{ FreeList}; //, VecFreeList };
+use crate::freelist::FreeList; //, VecFreeList };
+use super::intentlog::{self, IntentLog};
+use super::snapshot::{self, Snapshot};
+use super::gc::{self, GcReport};
+use super::stream::{Upload, ObjectReader, read_head};
 
 #[allow(unused_imports)]
 use log::{trace, debug, info, warn, error};
@@ -17,6 +23,35 @@ pub trait ObjectStore {
     fn put(&mut self, data: &[u8]) -> RResult<ObjectID>;
     fn get(&mut self, uuid: ObjectID) -> RResult<Option<Vec<u8>>>;
     fn delete(&mut self, uuid: ObjectID) -> RResult<Option<ObjectID>>;
+
+    /// store `data` with the content type and tags of `metadata`. if the same data is already
+    /// stored, its metadata is replaced
+    fn put_with_metadata(&mut self, data: &[u8], metadata: ObjectMetadata) -> RResult<ObjectID>;
+    /// the object's key, with its size and metadata, without reading its data
+    fn head(&self, uuid: ObjectID) -> RResult<Option<ObjKey>>;
+    /// replace the object's content type and tags. returns the updated metadata
+    fn update_metadata(&mut self, uuid: ObjectID, metadata: ObjectMetadata) -> RResult<Option<ObjectMetadata>>;
+    /// a page of the stored objects' keys, in uuid order
+    fn list(&self, query: &ListQuery<ObjKey>) -> RResult<Page<ObjKey>>;
+
+    /// Store everything `data` yields, writing it to the blockstore as it arrives rather than
+    /// holding it all in memory. `size_hint` is the expected size in bytes, which is allocated up
+    /// front; the data may turn out larger or smaller
+    fn put_stream(&mut self, data: &mut dyn Read, size_hint: u64) -> RResult<ObjectID>;
+    /// a reader over the object's data that fetches it from the blockstore as it's read
+    fn get_stream(&mut self, uuid: ObjectID) -> RResult<Option<ObjectReader<'_>>>;
+
+    /// `len` bytes of the object from `offset`, reading only the blocks that hold them. the range
+    /// is cut short at the end of the object
+    fn get_range(&mut self, uuid: ObjectID, offset: u64, len: u64) -> RResult<Option<Vec<u8>>> {
+        match self.get_stream(uuid)? {
+            Some(mut reader) => {
+                reader.set_range(offset, len)?;
+                Ok(Some(reader.read_all()?))
+            }
+            None => Ok(None),
+        }
+    }
 }
 
 pub struct BasicObjectStore<'a> {
@@ -24,6 +59,9 @@ pub struct BasicObjectStore<'a> {
     freelist: &'a mut dyn FreeList,
     keygen: KeyGen,
     keystore: &'a mut dyn KeyStore<ObjKey>,
+    intents: Option<&'a mut IntentLog>,
+    snapshots: Option<&'a mut dyn KeyStore<Snapshot>>,
+    inline_threshold: u64,
 }
 
 
@@ -34,41 +72,539 @@ impl<'a> BasicObjectStore<'a> {
         keygen: KeyGen,
         keystore: &'a mut dyn KeyStore<ObjKey>
         ) -> Self {
-        Self { blockstore, freelist, keygen, keystore }
+        Self { blockstore, freelist, keygen, keystore, intents: None, snapshots: None, inline_threshold: 0 }
+    }
+
+    /// record every put's allocation in `intents`, so `recover` can reclaim the blocks of puts
+    /// interrupted by a crash
+    pub fn with_intent_log(mut self, intents: &'a mut IntentLog) -> Self {
+        self.intents = Some(intents);
+        self
+    }
+
+    /// keep snapshots in `snapshots`, and don't reuse the blocks they hold
+    pub fn with_snapshots(mut self, snapshots: &'a mut dyn KeyStore<Snapshot>) -> Self {
+        self.snapshots = Some(snapshots);
+        self
+    }
+
+    /// keep objects smaller than `bytes` in their keys rather than in blocks. 0, the default,
+    /// stores every object in blocks
+    pub fn with_inline_threshold(mut self, bytes: u64) -> Self {
+        self.inline_threshold = bytes;
+        self
+    }
+
+    /// record the keys of every stored object as the snapshot `name`. returns its id
+    pub fn snapshot(&mut self, name: &str) -> RResult<Uuid> {
+        let keys = &*self.keystore;
+        match self.snapshots.as_deref_mut() {
+            Some(snapshots) => snapshot::create(keys, snapshots, name),
+            None => Err(no_snapshots()),
+        }
+    }
+
+    /// every snapshot, oldest first
+    pub fn snapshots(&self) -> RResult<Vec<Snapshot>> {
+        snapshot::list(self.snapshots.as_deref().ok_or_else(no_snapshots)?)
+    }
+
+    /// put the store back as it was when the snapshot was taken
+    pub fn restore_snapshot(&mut self, id: Uuid) -> RResult<usize> {
+        match self.snapshots.as_deref() {
+            Some(snapshots) => snapshot::restore(self.keystore, self.freelist, snapshots, id),
+            None => Err(no_snapshots()),
+        }
+    }
+
+    /// delete the snapshot, freeing the blocks only it held
+    pub fn delete_snapshot(&mut self, id: Uuid) -> RResult<Snapshot> {
+        let keys = &*self.keystore;
+        match self.snapshots.as_deref_mut() {
+            Some(snapshots) => snapshot::delete(keys, self.freelist, snapshots, id),
+            None => Err(no_snapshots()),
+        }
+    }
+
+    /// free the blocks no key or snapshot refers to. with `dry_run`, only report them
+    pub fn gc(&mut self, dry_run: bool) -> RResult<GcReport> {
+        gc::collect(&*self.keystore, self.snapshots.as_deref(), self.freelist, dry_run)
+    }
+
+    /// Release the allocations of puts that were interrupted before their key was stored.
+    /// returns the number of puts rolled back
+    pub fn recover(&mut self) -> RResult<usize> {
+        let orphans = match self.intents.as_mut() {
+            Some(intents) => intents.recover(&*self.keystore)?,
+            None => return Ok(0),
+        };
+        intentlog::release_orphans(self.freelist, &orphans)?;
+        Ok(orphans.len())
+    }
+
+    /// the steps of a put after allocation, any of which may fail
+    fn store(&mut self, data: &[u8], key: &ObjKey) -> RResult<()> {
+        if let Some(intents) = self.intents.as_mut() {
+            intents.begin(key.uuid, &key.manifest)?;
+        }
+        self.blockstore.write(data, key)?;
+        self.publish(key)
+    }
+
+    /// store the key of data that has been written
+    fn publish(&mut self, key: &ObjKey) -> RResult<()> {
+        // the data must be durable before the key that points at it is
+        self.blockstore.barrier()?;
+        if let Err(e) = self.keystore.set(key.uuid, key.clone()) {
+            // the keystore may have taken the key before failing to persist it
+            let _ = self.keystore.delete(&key.uuid);
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// the steps of a streamed put up to knowing its key. its allocation is logged under `tmp`
+    /// until then
+    fn stream(&mut self, tmp: Uuid, upload: &mut Upload, data: &mut dyn Read) -> RResult<()> {
+        while upload.fill(data)? {
+            let shortfall = upload.shortfall();
+            if shortfall > 0 {
+                upload.allocated(self.freelist.allocate(shortfall)?);
+                if let Some(intents) = self.intents.as_mut() {
+                    intents.begin(tmp, upload.manifest())?;
+                }
+            }
+            let (key, chunk) = upload.chunk();
+            self.blockstore.write(chunk, &key)?;
+        }
+        Ok(())
+    }
+
+    /// hand back blocks a streamed put allocated but didn't fill
+    fn trim(&mut self, uuid: Uuid, unused: &Manifest) {
+        if !unused.shards.is_empty() {
+            if let Err(e) = self.freelist.release(unused) {
+                error!("could not release the unused allocation of {:?}: {}", &uuid, e);
+            }
+        }
+    }
+
+    /// undo a failed put: return its blocks and close its intent
+    fn rollback(&mut self, key: &ObjKey) {
+        if let Err(e) = self.freelist.release(&key.manifest) {
+            error!("could not release the allocation of failed put {:?}: {}", &key.uuid, e);
+        }
+        if let Some(intents) = self.intents.as_mut() {
+            if let Err(e) = intents.abort(key.uuid) {
+                warn!("could not record the abort of {:?}: {}", &key.uuid, e);
+            }
+        }
     }
 }
 
-impl ObjectStore for BasicObjectStore<'_> {
-    fn put(&mut self, data: &[u8]) -> RResult<ObjectID> {
+fn no_snapshots() -> RustorError {
+    RustorError::Config("no keystore for snapshots".to_string())
+}
+
+impl BasicObjectStore<'_> {
+    /// store `data`, with `metadata` if given
+    fn put_key(&mut self, data: &[u8], metadata: Option<ObjectMetadata>) -> RResult<ObjectID> {
         let mut key = self.keygen.make_key(data)?;
+        let uuid = key.uuid;
+        // the uuid is derived from the data, so this exact object is already stored
+        if self.keystore.get(&uuid)?.is_some() {
+            if let Some(metadata) = metadata {
+                self.update_metadata(uuid, metadata)?;
+            }
+            return Ok(uuid);
+        }
+
+        key.metadata = metadata.unwrap_or_default().stamped(unix_time());
+        if key.size < self.inline_threshold {
+            // small enough to keep in the key, with no blocks to allocate or write
+            key.inline = Some(data.to_vec());
+            self.keystore.set(uuid, key)?;
+            return Ok(uuid);
+        }
         key.manifest = self.freelist.allocate(key.size)?;
-        self.blockstore.write(data, &key)?;
-        let uuid = key.uuid.clone();
-        self.keystore.set(key.uuid, key)?;
+        if let Err(e) = self.store(data, &key) {
+            debug!("put of {:?} failed, rolling back: {}", &uuid, e);
+            self.rollback(&key);
+            return Err(e);
+        }
 
+        if let Some(intents) = self.intents.as_mut() {
+            // the key is stored, so recovery would find the put complete without its commit
+            if let Err(e) = intents.commit(uuid) {
+                warn!("could not record the commit of {:?}: {}", &uuid, e);
+            }
+        }
+        Ok(uuid)
+    }
+
+    /// store everything `data` yields in blocks, as it arrives
+    fn upload(&mut self, data: &mut dyn Read, size_hint: u64) -> RResult<ObjectID> {
+        // the uuid is derived from the data, so until it has all been read the put is logged
+        // under a temporary id
+        let tmp = Uuid::new_v4();
+        let mut upload = Upload::new(self.keygen.hasher(), size_hint);
+        if let Err(e) = self.stream(tmp, &mut upload, data) {
+            debug!("streamed put failed, rolling back: {}", e);
+            self.rollback(&ObjKey { uuid: tmp, manifest: upload.manifest().clone(), ..Default::default() });
+            return Err(e);
+        }
+
+        let (mut key, unused) = upload.finish();
+        key.metadata = ObjectMetadata::default().stamped(unix_time());
+        let uuid = key.uuid;
+        if let Some(intents) = self.intents.as_mut() {
+            if let Err(e) = intents.rename(tmp, uuid, &key.manifest) {
+                let mut all = key.manifest.clone();
+                all.shards.extend(unused.shards);
+                self.rollback(&ObjKey { uuid: tmp, manifest: all, ..Default::default() });
+                return Err(e);
+            }
+        }
+        self.trim(uuid, &unused);
+
+        // the same data was already stored, so the copy just written isn't needed
+        let stored = match self.keystore.get(&uuid) {
+            Ok(existing) => existing.is_some(),
+            Err(e) => {
+                self.rollback(&key);
+                return Err(e);
+            }
+        };
+        if stored {
+            trace!("{:?} already stored", &uuid);
+            self.rollback(&key);
+            return Ok(uuid);
+        }
+
+        if let Err(e) = self.publish(&key) {
+            debug!("put of {:?} failed, rolling back: {}", &uuid, e);
+            self.rollback(&key);
+            return Err(e);
+        }
+        if let Some(intents) = self.intents.as_mut() {
+            if let Err(e) = intents.commit(uuid) {
+                warn!("could not record the commit of {:?}: {}", &uuid, e);
+            }
+        }
         Ok(uuid)
     }
+}
+
+impl ObjectStore for BasicObjectStore<'_> {
+    fn put(&mut self, data: &[u8]) -> RResult<ObjectID> {
+        self.put_key(data, None)
+    }
+
+    fn put_with_metadata(&mut self, data: &[u8], metadata: ObjectMetadata) -> RResult<ObjectID> {
+        self.put_key(data, Some(metadata))
+    }
+
+    fn head(&self, uuid: ObjectID) -> RResult<Option<ObjKey>> {
+        Ok(self.keystore.get(&uuid)?.cloned())
+    }
+
+    fn list(&self, query: &ListQuery<ObjKey>) -> RResult<Page<ObjKey>> {
+        Ok(self.keystore.list(query)?.cloned())
+    }
+
+    fn update_metadata(&mut self, uuid: ObjectID, metadata: ObjectMetadata) -> RResult<Option<ObjectMetadata>> {
+        let mut key = match self.keystore.get(&uuid)? {
+            Some(key) => key.clone(),
+            None => return Ok(None),
+        };
+        key.metadata.update(metadata, unix_time());
+        let updated = key.metadata.clone();
+        self.keystore.set(uuid, key)?;
+        Ok(Some(updated))
+    }
 
     fn get(&mut self, uuid: ObjectID) -> RResult<Option<Vec<u8>>> {
         trace!("get {:?}", &uuid);
         if let Some(key) = self.keystore.get(&uuid)? {
             trace!("found key: {:?}", &key);
+            if let Some(inline) = key.inline.as_ref() {
+                return Ok(Some(inline.clone()));
+            }
             let mut data = Vec::with_capacity(key.size as usize);
             self.blockstore.read(&mut data, &key)?;
+            // the last block is padded
+            data.truncate(key.size as usize);
             return Ok(Some(data));
         } else {
             return Ok(None);
         }
     }
 
-    #[allow(unused_variables)]
     fn delete(&mut self, uuid: ObjectID) -> RResult<Option<ObjectID>> {
-        if let Some(key) = self.keystore.get(&uuid)? {
-            self.freelist.release(&key.manifest);
+        if let Some(key) = self.keystore.delete(&uuid)? {
+            snapshot::release(self.freelist, self.snapshots.as_deref(), &key.manifest)?;
             return Ok(Some(uuid));
         } else {
             return Ok(None);
         }
     }
+
+    fn put_stream(&mut self, data: &mut dyn Read, size_hint: u64) -> RResult<ObjectID> {
+        let head = read_head(data, self.inline_threshold)?;
+        if (head.len() as u64) < self.inline_threshold {
+            return self.put_key(&head, None);
+        }
+        self.upload(&mut head.as_slice().chain(data), size_hint)
+    }
+
+    fn get_stream(&mut self, uuid: ObjectID) -> RResult<Option<ObjectReader<'_>>> {
+        let key = match self.keystore.get(&uuid)? {
+            Some(key) => key.clone(),
+            None => return Ok(None),
+        };
+        let blockstore = &mut *self.blockstore;
+        Ok(Some(ObjectReader::new(key, Box::new(move |key, data| blockstore.read(data, key)))))
+    }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::path::PathBuf;
+    use std::rc::Rc;
+    use crate::blockstore::BlockDeviceError;
+    use crate::freelist::VecFreeList;
+    use crate::keygen::GeneratesKeys;
+    use crate::object::{Manifest, ManifestLocation};
+
+    type Log = Rc<RefCell<Vec<&'static str>>>;
+
+    #[derive(Default)]
+    struct LoggingBlockStore {
+        log: Log,
+        fail_write: bool,
+    }
+
+    impl BlockStore for LoggingBlockStore {
+        fn write(&mut self, _data: &[u8], _key: &ObjKey) -> RResult<()> {
+            self.log.borrow_mut().push("write");
+            if self.fail_write { Err(BlockDeviceError::Offline)? } else { Ok(()) }
+        }
+        fn read(&mut self, _data: &mut Vec<u8>, _key: &ObjKey) -> RResult<()> {
+            Ok(())
+        }
+        fn barrier(&mut self) -> RResult<()> {
+            self.log.borrow_mut().push("barrier");
+            Ok(())
+        }
+    }
+
+    /// holds a single key. like JsonKeystore, a failed set has already taken the key
+    #[derive(Default)]
+    struct LoggingKeyStore {
+        log: Log,
+        key: Option<ObjKey>,
+        fail_set: bool,
+    }
+
+    impl KeyStore<ObjKey> for LoggingKeyStore {
+        fn set(&mut self, _uuid: Uuid, key: ObjKey) -> RResult<Option<ObjKey>> {
+            self.log.borrow_mut().push("set");
+            let old = self.key.replace(key);
+            if self.fail_set { Err(RustorError::KeyStore { uuid: None, reason: "disk full".to_string() })? } else { Ok(old) }
+        }
+        fn get(&self, uuid: &Uuid) -> RResult<Option<&ObjKey>> {
+            Ok(self.key.as_ref().filter(|k| k.uuid == *uuid))
+        }
+        fn delete(&mut self, _uuid: &Uuid) -> RResult<Option<ObjKey>> {
+            Ok(self.key.take())
+        }
+        fn list(&self, query: &ListQuery<ObjKey>) -> RResult<Page<(Uuid, &ObjKey)>> {
+            Ok(query.page(self.key.iter().map(|k| (k.uuid, k)), |k| *k))
+        }
+    }
+
+    fn intent_log(test: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("rustor-objstore-{}-{}.log", test, std::process::id()))
+    }
+
+    #[test]
+    fn test_put_orders_data_before_key() {
+        let log = Log::default();
+        let mut bs = LoggingBlockStore { log: Rc::clone(&log), ..Default::default() };
+        let mut fl = VecFreeList::new(16);
+        let mut ks = LoggingKeyStore { log: Rc::clone(&log), ..Default::default() };
+        let mut store = BasicObjectStore::new(&mut bs, &mut fl, KeyGen {}, &mut ks);
+
+        store.put(b"durable").unwrap();
+        assert_eq!(*log.borrow(), vec!["write", "barrier", "set"]);
+
+        // putting the same data again finds it already stored
+        store.put(b"durable").unwrap();
+        assert_eq!(log.borrow().len(), 3);
+    }
+
+    #[test]
+    fn test_failed_write_releases_allocation() {
+        let mut bs = LoggingBlockStore { fail_write: true, ..Default::default() };
+        let mut fl = VecFreeList::new(16);
+        let mut ks = LoggingKeyStore::default();
+        let mut store = BasicObjectStore::new(&mut bs, &mut fl, KeyGen {}, &mut ks);
+
+        assert!(store.put(&[1; 4 * 4096]).is_err());
+        assert_eq!(fl.free_blocks(), 16);
+        assert!(ks.key.is_none());
+    }
+
+    #[test]
+    fn test_failed_key_insert_rolls_back() {
+        let _ = std::fs::remove_file(intent_log("rollback"));
+        let mut intents = IntentLog::open(intent_log("rollback")).unwrap();
+        let mut bs = LoggingBlockStore::default();
+        let mut fl = VecFreeList::new(16);
+        let mut ks = LoggingKeyStore { fail_set: true, ..Default::default() };
+        let mut store = BasicObjectStore::new(&mut bs, &mut fl, KeyGen {}, &mut ks)
+            .with_intent_log(&mut intents);
+
+        assert!(store.put(&[1; 4 * 4096]).is_err());
+        assert_eq!(fl.free_blocks(), 16);
+        // the key the keystore took before failing was removed again
+        assert!(ks.key.is_none());
+        assert_eq!(intents.pending().count(), 0);
+        let _ = std::fs::remove_file(intent_log("rollback"));
+    }
+
+    #[test]
+    fn test_recover_reclaims_interrupted_put() {
+        let _ = std::fs::remove_file(intent_log("recover"));
+        let mut fl = VecFreeList::new(16);
+        {
+            // a put that crashed after writing its data but before storing its key
+            let mut intents = IntentLog::open(intent_log("recover")).unwrap();
+            let manifest = fl.allocate(3 * 4096).unwrap();
+            intents.begin(Uuid::new_v4(), &manifest).unwrap();
+        }
+        assert_eq!(fl.free_blocks(), 13);
+
+        let mut intents = IntentLog::open(intent_log("recover")).unwrap();
+        let mut bs = LoggingBlockStore::default();
+        let mut ks = LoggingKeyStore::default();
+        let mut store = BasicObjectStore::new(&mut bs, &mut fl, KeyGen {}, &mut ks)
+            .with_intent_log(&mut intents);
+        assert_eq!(store.recover().unwrap(), 1);
+        assert_eq!(store.recover().unwrap(), 0);
+        assert_eq!(fl.free_blocks(), 16);
+
+        // a free list rebuilt from the keystore already has the orphaned blocks
+        let mut fl = VecFreeList::new(16);
+        let manifest = Manifest { shards: vec![ManifestLocation { blkdevid: None, lba: 2, span: 3 }] };
+        intents.begin(Uuid::new_v4(), &manifest).unwrap();
+        let mut store = BasicObjectStore::new(&mut bs, &mut fl, KeyGen {}, &mut ks)
+            .with_intent_log(&mut intents);
+        assert_eq!(store.recover().unwrap(), 1);
+        assert_eq!(fl.free_blocks(), 16);
+        let _ = std::fs::remove_file(intent_log("recover"));
+    }
+
+    #[test]
+    fn test_streamed_put_and_get() {
+        let path = |ext: &str| std::env::temp_dir().join(format!("rustor-objstore-stream-{}.{}", std::process::id(), ext));
+        let _ = std::fs::remove_file(path("json"));
+        let _ = std::fs::remove_file(intent_log("stream"));
+        let mut intents = IntentLog::open(intent_log("stream")).unwrap();
+        let mut bs = crate::blockstore::SingleDeviceBlockStore::new(path("bin"), 2048 * 4096).unwrap();
+        let mut fl = VecFreeList::new(2048);
+        let mut ks = crate::keystore::JsonKeystore::<ObjKey>::new(path("json"));
+        let mut store = BasicObjectStore::new(&mut bs, &mut fl, KeyGen {}, &mut ks)
+            .with_intent_log(&mut intents);
+
+        // larger than its hint, and spanning several chunks
+        let data: Vec<u8> = (0..600 * 4096 + 100).map(|i| (i % 251) as u8).collect();
+        let uuid = store.put_stream(&mut &data[..], 4096).unwrap();
+        assert_eq!(uuid, KeyGen {}.make_key(&data).unwrap().uuid);
+        let mut read = Vec::new();
+        store.get_stream(uuid).unwrap().unwrap().read_to_end(&mut read).unwrap();
+        assert_eq!(read, data);
+        assert_eq!(store.get(uuid).unwrap().unwrap(), data);
+        assert_eq!(store.get_range(uuid, 4090, 10).unwrap().unwrap(), &data[4090..4100]);
+        assert_eq!(store.get_range(uuid, 600 * 4096, 1000).unwrap().unwrap(), &data[600 * 4096..]);
+        assert!(store.get_range(uuid, data.len() as u64 + 1, 1).is_err());
+        assert!(store.get_range(Uuid::new_v4(), 0, 1).unwrap().is_none());
+
+        // smaller than its hint: the unused allocation is handed back
+        let small = store.put_stream(&mut &b"small"[..], 100 * 4096).unwrap();
+        assert_eq!(store.get(small).unwrap().unwrap(), b"small");
+        // and storing data that's already stored frees the copy, once its uuid is known
+        assert_eq!(store.put_stream(&mut &data[..], 0).unwrap(), uuid);
+        assert!(store.get_stream(Uuid::new_v4()).unwrap().is_none());
+        assert_eq!(fl.free_blocks(), 2048 - 601 - 1);
+        assert_eq!(intents.pending().count(), 0);
+
+        let mut bs = LoggingBlockStore { fail_write: true, ..Default::default() };
+        let mut store = BasicObjectStore::new(&mut bs, &mut fl, KeyGen {}, &mut ks)
+            .with_intent_log(&mut intents);
+        assert!(store.put_stream(&mut &data[..], 0).is_err());
+        assert_eq!(fl.free_blocks(), 2048 - 601 - 1);
+        assert_eq!(intents.pending().count(), 0);
+
+        let _ = std::fs::remove_file(path("bin"));
+        let _ = std::fs::remove_file(path("json"));
+        let _ = std::fs::remove_file(intent_log("stream"));
+    }
+
+    #[test]
+    fn test_metadata() {
+        let mut bs = LoggingBlockStore::default();
+        let mut fl = VecFreeList::new(16);
+        let mut ks = LoggingKeyStore::default();
+        let mut store = BasicObjectStore::new(&mut bs, &mut fl, KeyGen {}, &mut ks);
+
+        let uuid = store.put_with_metadata(b"hello", ObjectMetadata::with_content_type("text/plain").tag("a", "1")).unwrap();
+        let key = store.head(uuid).unwrap().unwrap();
+        assert_eq!((key.size, key.metadata.content_type.as_deref()), (5, Some("text/plain")));
+        assert_eq!(key.metadata.tags.get("a").map(String::as_str), Some("1"));
+        assert!(key.metadata.created > 0);
+        assert_eq!(key.metadata.created, key.metadata.modified);
+
+        let updated = store.update_metadata(uuid, ObjectMetadata::default().tag("b", "2")).unwrap().unwrap();
+        assert_eq!((updated.created, updated.content_type), (key.metadata.created, None));
+        assert_eq!(store.head(uuid).unwrap().unwrap().metadata.tags.keys().collect::<Vec<_>>(), vec!["b"]);
+
+        // storing the same data again with metadata replaces it
+        assert_eq!(store.put_with_metadata(b"hello", ObjectMetadata::with_content_type("text/x")).unwrap(), uuid);
+        assert_eq!(store.head(uuid).unwrap().unwrap().metadata.content_type.as_deref(), Some("text/x"));
+
+        let missing = Uuid::new_v4();
+        assert!(store.head(missing).unwrap().is_none());
+        assert!(store.update_metadata(missing, ObjectMetadata::default()).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_inline() {
+        let log = Log::default();
+        let mut bs = LoggingBlockStore { log: Rc::clone(&log), ..Default::default() };
+        let mut fl = VecFreeList::new(16);
+        let mut ks = LoggingKeyStore { log: Rc::clone(&log), ..Default::default() };
+        let mut store = BasicObjectStore::new(&mut bs, &mut fl, KeyGen {}, &mut ks).with_inline_threshold(16);
+
+        // small objects live in their keys, with no blocks and no block I/O
+        let uuid = store.put(b"hello").unwrap();
+        let key = store.head(uuid).unwrap().unwrap();
+        assert!(key.manifest.shards.is_empty());
+        assert_eq!(key.inline.as_deref(), Some(&b"hello"[..]));
+        assert_eq!(store.get(uuid).unwrap().unwrap(), b"hello");
+        assert_eq!(store.get_range(uuid, 1, 3).unwrap().unwrap(), b"ell");
+        assert_eq!(*log.borrow(), vec!["set"]);
+
+        let streamed = store.put_stream(&mut &b"small stream"[..], 4096).unwrap();
+        assert_eq!(store.get(streamed).unwrap().unwrap(), b"small stream");
+        assert_eq!(store.delete(streamed).unwrap(), Some(streamed));
+
+        // at the threshold, objects go to blocks as before
+        let large = store.put_stream(&mut &[7u8; 16][..], 0).unwrap();
+        assert!(store.head(large).unwrap().unwrap().inline.is_none());
+        assert_eq!(fl.free_blocks(), 15);
+    }
+}
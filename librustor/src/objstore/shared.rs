@@ -0,0 +1,869 @@
+use std::io::Read;
+use std::sync::{Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use crate::{RResult, RustorError};
+use crate::blockstore::BlockStore;
+use crate::object::{Manifest, ObjKey, ObjectMetadata, unix_time};
+use crate::keystore::{KeyStore, ListQuery, Page};
+use crate::keygen::{KeyGen, GeneratesKeys};
+use crate::freelist::FreeList;
+use super::{ObjectStore, ObjectID};
+use super::intentlog::{self, IntentLog};
+use super::stream::{Upload, ObjectReader, read_head};
+use super::snapshot::{self, Snapshot};
+use super::gc::{self, GcReport};
+use uuid::Uuid;
+
+#[allow(unused_imports)]
+use log::{trace, debug, info, warn, error};
+
+pub type SharedBlockStore = Box<dyn BlockStore + Send + Sync>;
+pub type SharedFreeList = Box<dyn FreeList + Send>;
+pub type SharedKeyStore = Box<dyn KeyStore<ObjKey> + Send + Sync>;
+pub type SharedSnapshots = Box<dyn KeyStore<Snapshot> + Send + Sync>;
+
+/// An ObjectStore that owns its components and can be shared between threads behind an `Arc`.
+///
+/// Each component sits behind its own lock so that different operations only contend where they
+/// have to: lookups take a read lock on the keystore and proceed in parallel, while metadata
+/// updates take the write lock and are serialized. A get keeps its keystore read lock until the
+/// data has been read, so an object can't be deleted and its blocks reused mid-read; a streamed
+/// get takes it again for each chunk instead (see `get_stream`). Block stores
+/// that can read through a shared reference are read under the blockstore's read lock, so gets
+/// read their data in parallel too; writes take its write lock.
+///
+/// Puts hold a read lock on `puts` from allocating their blocks until their key is stored, so that
+/// gc, which takes the write lock, never sees blocks that are allocated but not yet referred to.
+///
+/// Locks are always taken in puts -> keystore -> snapshots -> freelist -> blockstore -> intents
+/// order.
+pub struct SharedObjectStore {
+    puts: RwLock<()>,
+    blockstore: RwLock<SharedBlockStore>,
+    freelist: Mutex<SharedFreeList>,
+    keygen: KeyGen,
+    keystore: RwLock<SharedKeyStore>,
+    snapshots: Option<Mutex<SharedSnapshots>>,
+    intents: Option<Mutex<IntentLog>>,
+    inline_threshold: u64,
+}
+
+/// replace the metadata of `uuid`'s key in `keys`
+fn update_metadata(keys: &mut SharedKeyStore, uuid: ObjectID, metadata: ObjectMetadata) -> RResult<Option<ObjectMetadata>> {
+    let mut key = match keys.get(&uuid)? {
+        Some(key) => key.clone(),
+        None => return Ok(None),
+    };
+    key.metadata.update(metadata, unix_time());
+    let updated = key.metadata.clone();
+    keys.set(uuid, key)?;
+    Ok(Some(updated))
+}
+
+/// store `key` in `keys`. the keystore may have taken the key before failing to persist it, so
+/// on failure it is deleted again, as in `BasicObjectStore::publish`
+fn publish(keys: &mut SharedKeyStore, key: &ObjKey) -> RResult<()> {
+    if let Err(e) = keys.set(key.uuid, key.clone()) {
+        let _ = keys.delete(&key.uuid);
+        return Err(e);
+    }
+    Ok(())
+}
+
+fn poisoned(what: &'static str) -> RustorError {
+    RustorError::Poisoned(what)
+}
+
+impl SharedObjectStore {
+    pub fn new<B, F, K>(blockstore: B, freelist: F, keygen: KeyGen, keystore: K) -> Self
+    where
+        B: BlockStore + Send + Sync + 'static,
+        F: FreeList + Send + 'static,
+        K: KeyStore<ObjKey> + Send + Sync + 'static,
+    {
+        Self {
+            puts: RwLock::new(()),
+            blockstore: RwLock::new(Box::new(blockstore)),
+            freelist: Mutex::new(Box::new(freelist)),
+            keygen,
+            keystore: RwLock::new(Box::new(keystore)),
+            snapshots: None,
+            intents: None,
+            inline_threshold: 0,
+        }
+    }
+
+    /// as `BasicObjectStore::with_intent_log`
+    pub fn with_intent_log(mut self, intents: IntentLog) -> Self {
+        self.intents = Some(Mutex::new(intents));
+        self
+    }
+
+    /// keep snapshots in `snapshots`, and don't reuse the blocks they hold
+    pub fn with_snapshots<S: KeyStore<Snapshot> + Send + Sync + 'static>(mut self, snapshots: S) -> Self {
+        self.snapshots = Some(Mutex::new(Box::new(snapshots)));
+        self
+    }
+
+    /// as `BasicObjectStore::with_inline_threshold`
+    pub fn with_inline_threshold(mut self, bytes: u64) -> Self {
+        self.inline_threshold = bytes;
+        self
+    }
+
+    /// None if the store doesn't keep snapshots
+    fn snapshot_store(&self) -> RResult<Option<MutexGuard<'_, SharedSnapshots>>> {
+        match self.snapshots.as_ref() {
+            Some(snapshots) => Ok(Some(snapshots.lock().map_err(|_| poisoned("snapshots"))?)),
+            None => Ok(None),
+        }
+    }
+
+    /// None if the store doesn't log intents
+    fn intents(&self) -> RResult<Option<MutexGuard<'_, IntentLog>>> {
+        match self.intents.as_ref() {
+            Some(intents) => Ok(Some(intents.lock().map_err(|_| poisoned("intents"))?)),
+            None => Ok(None),
+        }
+    }
+
+    /// as `BasicObjectStore::recover`. puts wait until it's done
+    pub fn recover(&self) -> RResult<usize> {
+        let _puts = self.puts.write().map_err(|_| poisoned("puts"))?;
+        let keys = self.keys()?;
+        let mut freelist = self.freelist()?;
+        let orphans = match self.intents()? {
+            Some(mut intents) => intents.recover(&**keys)?,
+            None => return Ok(0),
+        };
+        intentlog::release_orphans(&mut **freelist, &orphans)?;
+        Ok(orphans.len())
+    }
+
+    /// log the allocation of a put that is about to write its data
+    fn begin(&self, uuid: Uuid, manifest: &Manifest) -> RResult<()> {
+        match self.intents()? {
+            Some(mut intents) => intents.begin(uuid, manifest),
+            None => Ok(()),
+        }
+    }
+
+    /// record that the key of a put with blocks is stored
+    fn commit(&self, key: &ObjKey) {
+        if key.manifest.shards.is_empty() {
+            return;
+        }
+        let committed = self.intents().and_then(|intents| match intents {
+            Some(mut intents) => intents.commit(key.uuid),
+            None => Ok(()),
+        });
+        // the key is stored, so recovery would find the put complete without its commit
+        if let Err(e) = committed {
+            warn!("could not record the commit of {:?}: {}", &key.uuid, e);
+        }
+    }
+
+    /// undo a put that won't be stored: return its blocks and close its intent
+    fn rollback(&self, key: &ObjKey) {
+        if key.manifest.shards.is_empty() {
+            return;
+        }
+        if let Err(e) = self.freelist().and_then(|mut freelist| freelist.release(&key.manifest)) {
+            error!("could not release the allocation of failed put {:?}: {}", &key.uuid, e);
+        }
+        let aborted = self.intents().and_then(|intents| match intents {
+            Some(mut intents) => intents.abort(key.uuid),
+            None => Ok(()),
+        });
+        if let Err(e) = aborted {
+            warn!("could not record the abort of {:?}: {}", &key.uuid, e);
+        }
+    }
+
+    fn required_snapshot_store(&self) -> RResult<MutexGuard<'_, SharedSnapshots>> {
+        self.snapshot_store()?.ok_or_else(|| RustorError::Config("no keystore for snapshots".to_string()))
+    }
+
+    /// as `BasicObjectStore::snapshot`
+    pub fn snapshot(&self, name: &str) -> RResult<Uuid> {
+        let keys = self.keys()?;
+        snapshot::create(&**keys, &mut **self.required_snapshot_store()?, name)
+    }
+
+    pub fn snapshots(&self) -> RResult<Vec<Snapshot>> {
+        snapshot::list(&**self.required_snapshot_store()?)
+    }
+
+    pub fn restore_snapshot(&self, id: Uuid) -> RResult<usize> {
+        let mut keys = self.keys_mut()?;
+        let snapshots = self.required_snapshot_store()?;
+        snapshot::restore(&mut **keys, &mut **self.freelist()?, &**snapshots, id)
+    }
+
+    pub fn delete_snapshot(&self, id: Uuid) -> RResult<Snapshot> {
+        let keys = self.keys()?;
+        let mut snapshots = self.required_snapshot_store()?;
+        snapshot::delete(&**keys, &mut **self.freelist()?, &mut **snapshots, id)
+    }
+
+    /// as `BasicObjectStore::gc`. puts wait until it's done
+    pub fn gc(&self, dry_run: bool) -> RResult<GcReport> {
+        let _puts = self.puts.write().map_err(|_| poisoned("puts"))?;
+        let keys = self.keys()?;
+        let snapshots = self.snapshot_store()?;
+        let snapshots = snapshots.as_deref().map(|s| &**s as &dyn KeyStore<Snapshot>);
+        gc::collect(&**keys, snapshots, &mut **self.freelist()?, dry_run)
+    }
+
+    fn puts(&self) -> RResult<RwLockReadGuard<'_, ()>> {
+        self.puts.read().map_err(|_| poisoned("puts"))
+    }
+
+    fn blockstore(&self) -> RResult<RwLockWriteGuard<'_, SharedBlockStore>> {
+        self.blockstore.write().map_err(|_| poisoned("blockstore"))
+    }
+
+    /// read `key`'s blocks into `data`, alongside other reads if the block store allows it
+    fn read_blocks(&self, data: &mut Vec<u8>, key: &ObjKey) -> RResult<()> {
+        let shared = self.blockstore.read().map_err(|_| poisoned("blockstore"))?.read_shared(data, key);
+        match shared {
+            Some(read) => read,
+            None => self.blockstore()?.read(data, key),
+        }
+    }
+
+    fn freelist(&self) -> RResult<MutexGuard<'_, SharedFreeList>> {
+        self.freelist.lock().map_err(|_| poisoned("freelist"))
+    }
+
+    fn keys(&self) -> RResult<RwLockReadGuard<'_, SharedKeyStore>> {
+        self.keystore.read().map_err(|_| poisoned("keystore"))
+    }
+
+    fn keys_mut(&self) -> RResult<RwLockWriteGuard<'_, SharedKeyStore>> {
+        self.keystore.write().map_err(|_| poisoned("keystore"))
+    }
+
+    pub fn put(&self, data: &[u8]) -> RResult<ObjectID> {
+        self.put_key(data, None)
+    }
+
+    /// store `data` with `metadata`, as `ObjectStore::put_with_metadata`
+    pub fn put_with_metadata(&self, data: &[u8], metadata: ObjectMetadata) -> RResult<ObjectID> {
+        self.put_key(data, Some(metadata))
+    }
+
+    /// the object's key, as `ObjectStore::head`
+    pub fn head(&self, uuid: ObjectID) -> RResult<Option<ObjKey>> {
+        Ok(self.keys()?.get(&uuid)?.cloned())
+    }
+
+    /// a page of the stored keys, as `ObjectStore::list`
+    pub fn list(&self, query: &ListQuery<ObjKey>) -> RResult<Page<ObjKey>> {
+        Ok(self.keys()?.list(query)?.cloned())
+    }
+
+    pub fn update_metadata(&self, uuid: ObjectID, metadata: ObjectMetadata) -> RResult<Option<ObjectMetadata>> {
+        update_metadata(&mut *self.keys_mut()?, uuid, metadata)
+    }
+
+    fn put_key(&self, data: &[u8], metadata: Option<ObjectMetadata>) -> RResult<ObjectID> {
+        let _put = self.puts()?;
+        let mut key = self.keygen.make_key(data)?;
+        let uuid = key.uuid;
+        if self.keys()?.get(&uuid)?.is_some() {
+            trace!("{:?} already stored", &uuid);
+            if let Some(metadata) = metadata {
+                self.update_metadata(uuid, metadata)?;
+            }
+            return Ok(uuid);
+        }
+
+        key.metadata = metadata.clone().unwrap_or_default().stamped(unix_time());
+        if key.size < self.inline_threshold {
+            // small enough to keep in the key, with no blocks to allocate or write
+            key.inline = Some(data.to_vec());
+        } else {
+            key.manifest = self.freelist()?.allocate(key.size)?;
+            let written = self.begin(uuid, &key.manifest).and_then(|_| {
+                let mut blockstore = self.blockstore()?;
+                // the data must be durable before the key that points at it is
+                blockstore.write(data, &key).and_then(|_| blockstore.barrier())
+            });
+            if let Err(e) = written {
+                debug!("put of {:?} failed, rolling back: {}", &uuid, e);
+                self.rollback(&key);
+                return Err(e);
+            }
+        }
+
+        // another thread may have stored the same data while this one was writing it
+        let mut keys = self.keys_mut()?;
+        if keys.get(&uuid)?.is_some() {
+            self.rollback(&key);
+            if let Some(metadata) = metadata {
+                update_metadata(&mut keys, uuid, metadata)?;
+            }
+        } else if let Err(e) = publish(&mut keys, &key) {
+            self.rollback(&key);
+            return Err(e);
+        } else {
+            self.commit(&key);
+        }
+        Ok(uuid)
+    }
+
+    pub fn get(&self, uuid: ObjectID) -> RResult<Option<Vec<u8>>> {
+        trace!("get {:?}", &uuid);
+        let keys = self.keys()?;
+        let key = match keys.get(&uuid)? {
+            Some(key) => key,
+            None => return Ok(None),
+        };
+        trace!("found key: {:?}", &key);
+        if let Some(inline) = key.inline.as_ref() {
+            return Ok(Some(inline.clone()));
+        }
+        let mut data = Vec::with_capacity(key.size as usize);
+        self.read_blocks(&mut data, key)?;
+        // the last block is padded
+        data.truncate(key.size as usize);
+        Ok(Some(data))
+    }
+
+    /// Store everything `data` yields, as `ObjectStore::put_stream`.
+    ///
+    /// The blockstore is locked a chunk at a time, so other operations can proceed in between
+    pub fn put_stream(&self, data: &mut dyn Read, size_hint: u64) -> RResult<ObjectID> {
+        self.put_stream_key(data, size_hint, None)
+    }
+
+    /// store everything `data` yields with `metadata`, as `put_with_metadata`. the key is
+    /// written once, with its metadata
+    pub fn put_stream_with_metadata(&self, data: &mut dyn Read, size_hint: u64, metadata: ObjectMetadata) -> RResult<ObjectID> {
+        self.put_stream_key(data, size_hint, Some(metadata))
+    }
+
+    fn put_stream_key(&self, data: &mut dyn Read, size_hint: u64, metadata: Option<ObjectMetadata>) -> RResult<ObjectID> {
+        let head = read_head(data, self.inline_threshold)?;
+        if (head.len() as u64) < self.inline_threshold {
+            return self.put_key(&head, metadata);
+        }
+        self.upload(&mut head.as_slice().chain(data), size_hint, metadata)
+    }
+
+    /// store everything `data` yields in blocks, as it arrives
+    fn upload(&self, data: &mut dyn Read, size_hint: u64, metadata: Option<ObjectMetadata>) -> RResult<ObjectID> {
+        let _put = self.puts()?;
+        // as in `BasicObjectStore::upload`, the put is logged under a temporary id until the
+        // data has all been read
+        let tmp = Uuid::new_v4();
+        let mut upload = Upload::new(self.keygen.hasher(), size_hint);
+        let streamed = (|| {
+            while upload.fill(data)? {
+                let shortfall = upload.shortfall();
+                if shortfall > 0 {
+                    let manifest = self.freelist()?.allocate(shortfall)?;
+                    upload.allocated(manifest);
+                    self.begin(tmp, upload.manifest())?;
+                }
+                let (key, chunk) = upload.chunk();
+                self.blockstore()?.write(chunk, &key)?;
+            }
+            self.blockstore()?.barrier()
+        })();
+        if let Err(e) = streamed {
+            debug!("streamed put failed, rolling back: {}", e);
+            self.rollback(&ObjKey { uuid: tmp, manifest: upload.manifest().clone(), ..Default::default() });
+            return Err(e);
+        }
+
+        let (mut key, unused) = upload.finish();
+        key.metadata = metadata.clone().unwrap_or_default().stamped(unix_time());
+        let uuid = key.uuid;
+        let renamed = self.intents().and_then(|intents| match intents {
+            Some(mut intents) => intents.rename(tmp, uuid, &key.manifest),
+            None => Ok(()),
+        });
+        if let Err(e) = renamed {
+            let mut all = key.manifest.clone();
+            all.shards.extend(unused.shards);
+            self.rollback(&ObjKey { uuid: tmp, manifest: all, ..Default::default() });
+            return Err(e);
+        }
+        if !unused.shards.is_empty() {
+            if let Err(e) = self.freelist().and_then(|mut freelist| freelist.release(&unused)) {
+                error!("could not release the unused allocation of {:?}: {}", &uuid, e);
+            }
+        }
+
+        // the same data may already be stored, or stored by another thread meanwhile
+        let mut keys = self.keys_mut()?;
+        let stored = match keys.get(&uuid) {
+            Ok(existing) => existing.is_some(),
+            Err(e) => {
+                self.rollback(&key);
+                return Err(e);
+            }
+        };
+        if stored {
+            trace!("{:?} already stored", &uuid);
+            self.rollback(&key);
+            if let Some(metadata) = metadata {
+                update_metadata(&mut keys, uuid, metadata)?;
+            }
+        } else if let Err(e) = publish(&mut keys, &key) {
+            debug!("put of {:?} failed, rolling back: {}", &uuid, e);
+            self.rollback(&key);
+            return Err(e);
+        } else {
+            self.commit(&key);
+        }
+        Ok(uuid)
+    }
+
+    /// A reader over the object's data, as `ObjectStore::get_stream`.
+    ///
+    /// The reader may be drained as slowly as a client reads, so it doesn't hold the keystore
+    /// lock between chunks. Instead each chunk is read under a read lock taken just for it, once
+    /// the key has been checked to still point at the same blocks. If the object was deleted or
+    /// replaced since the reader was made, its blocks may have been reused and the read fails
+    /// with `NotFound`
+    pub fn get_stream(&self, uuid: ObjectID) -> RResult<Option<ObjectReader<'_>>> {
+        let key = match self.head(uuid)? {
+            Some(key) => key,
+            None => return Ok(None),
+        };
+        let manifest = key.manifest.clone();
+        Ok(Some(ObjectReader::new(key, Box::new(move |chunk, data| {
+            let keys = self.keys()?;
+            match keys.get(&uuid)? {
+                Some(key) if key.manifest.shards == manifest.shards => self.read_blocks(data, chunk),
+                _ => Err(RustorError::NotFound { uuid }),
+            }
+        }))))
+    }
+
+    /// `len` bytes of the object from `offset`, as `ObjectStore::get_range`
+    pub fn get_range(&self, uuid: ObjectID, offset: u64, len: u64) -> RResult<Option<Vec<u8>>> {
+        match self.get_stream(uuid)? {
+            Some(mut reader) => {
+                reader.set_range(offset, len)?;
+                Ok(Some(reader.read_all()?))
+            }
+            None => Ok(None),
+        }
+    }
+
+    pub fn delete(&self, uuid: ObjectID) -> RResult<Option<ObjectID>> {
+        let mut keys = self.keys_mut()?;
+        match keys.delete(&uuid)? {
+            Some(key) => {
+                let snapshots = self.snapshot_store()?;
+                let snapshots = snapshots.as_deref().map(|s| &**s as &dyn KeyStore<Snapshot>);
+                snapshot::release(&mut **self.freelist()?, snapshots, &key.manifest)?;
+                Ok(Some(uuid))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+impl ObjectStore for SharedObjectStore {
+    fn put(&mut self, data: &[u8]) -> RResult<ObjectID> {
+        SharedObjectStore::put(self, data)
+    }
+    fn get(&mut self, uuid: ObjectID) -> RResult<Option<Vec<u8>>> {
+        SharedObjectStore::get(self, uuid)
+    }
+    fn delete(&mut self, uuid: ObjectID) -> RResult<Option<ObjectID>> {
+        SharedObjectStore::delete(self, uuid)
+    }
+    fn put_stream(&mut self, data: &mut dyn Read, size_hint: u64) -> RResult<ObjectID> {
+        SharedObjectStore::put_stream(self, data, size_hint)
+    }
+    fn get_stream(&mut self, uuid: ObjectID) -> RResult<Option<ObjectReader<'_>>> {
+        SharedObjectStore::get_stream(self, uuid)
+    }
+    fn get_range(&mut self, uuid: ObjectID, offset: u64, len: u64) -> RResult<Option<Vec<u8>>> {
+        SharedObjectStore::get_range(self, uuid, offset, len)
+    }
+    fn put_with_metadata(&mut self, data: &[u8], metadata: ObjectMetadata) -> RResult<ObjectID> {
+        SharedObjectStore::put_with_metadata(self, data, metadata)
+    }
+    fn head(&self, uuid: ObjectID) -> RResult<Option<ObjKey>> {
+        SharedObjectStore::head(self, uuid)
+    }
+    fn list(&self, query: &ListQuery<ObjKey>) -> RResult<Page<ObjKey>> {
+        SharedObjectStore::list(self, query)
+    }
+    fn update_metadata(&mut self, uuid: ObjectID, metadata: ObjectMetadata) -> RResult<Option<ObjectMetadata>> {
+        SharedObjectStore::update_metadata(self, uuid, metadata)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use std::sync::{Arc, Condvar};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use std::time::Duration;
+
+    use crate::BS4K;
+    use crate::blockstore::SingleDeviceBlockStore;
+    use crate::freelist::VecFreeList;
+    use crate::keystore::JsonKeystore;
+    use crate::objstore::stream::STREAM_CHUNK_BLOCKS;
+
+    fn path(test: &str, ext: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("rustor-shared-{}-{}.{}", test, std::process::id(), ext))
+    }
+
+    fn store(test: &str, blocks: u64) -> SharedObjectStore {
+        let _ = std::fs::remove_file(path(test, "json"));
+        SharedObjectStore::new(
+            SingleDeviceBlockStore::new(path(test, "bin"), blocks * BS4K as u64).unwrap(),
+            VecFreeList::new(blocks),
+            KeyGen {},
+            JsonKeystore::<ObjKey>::new(path(test, "json")),
+        )
+    }
+
+    fn cleanup(test: &str) {
+        let _ = std::fs::remove_file(path(test, "bin"));
+        let _ = std::fs::remove_file(path(test, "json"));
+    }
+
+    #[test]
+    fn test_put_get_delete() {
+        let s = store("basic", 16);
+        let uuid = s.put(b"hello").unwrap();
+        assert_eq!(&s.get(uuid).unwrap().unwrap()[..5], b"hello");
+
+        // storing the same data again doesn't take more space
+        assert_eq!(s.put(b"hello").unwrap(), uuid);
+
+        assert_eq!(s.delete(uuid).unwrap(), Some(uuid));
+        assert_eq!(s.get(uuid).unwrap(), None);
+        assert_eq!(s.delete(uuid).unwrap(), None);
+        cleanup("basic");
+    }
+
+    #[test]
+    fn test_snapshots() {
+        let _ = std::fs::remove_file(path("snapshots", "snap"));
+        let s = store("snapshots", 4).with_snapshots(JsonKeystore::<Snapshot>::new(path("snapshots", "snap")));
+        let kept = s.put(b"kept").unwrap();
+        let id = s.snapshot("first").unwrap();
+
+        // the deleted object's block stays taken, so the store fills up sooner
+        s.delete(kept).unwrap();
+        for i in 0..3u8 {
+            s.put(&[i]).unwrap();
+        }
+        assert!(s.put(b"full").unwrap_err().is_out_of_space());
+
+        assert_eq!(s.restore_snapshot(id).unwrap(), 1);
+        assert_eq!(s.get(kept).unwrap().unwrap(), b"kept");
+        assert!(s.get(KeyGen {}.make_key(&[0]).unwrap().uuid).unwrap().is_none());
+        assert_eq!(s.snapshots().unwrap().len(), 1);
+
+        s.delete_snapshot(id).unwrap();
+        s.delete(kept).unwrap();
+        for i in 0..4u8 {
+            s.put(&[i]).unwrap();
+        }
+        let _ = std::fs::remove_file(path("snapshots", "snap"));
+        cleanup("snapshots");
+    }
+
+    #[test]
+    fn test_gc() {
+        let s = store("gc", 4);
+        let uuid = s.put(b"kept").unwrap();
+        // as if a put crashed between allocating and storing its key
+        s.freelist().unwrap().take(2, 2).unwrap();
+
+        let report = s.gc(true).unwrap();
+        assert_eq!((report.marked, report.reclaimed), (1, 2));
+        assert!(s.put(b"one").is_ok());
+        assert!(s.put(b"two").unwrap_err().is_out_of_space());
+
+        assert_eq!(s.gc(false).unwrap().areas, vec![(2, 2)]);
+        assert!(s.put(b"two").is_ok());
+        assert_eq!(s.gc(false).unwrap().reclaimed, 0);
+        assert_eq!(s.get(uuid).unwrap().unwrap()[..4], b"kept"[..]);
+        cleanup("gc");
+    }
+
+    #[test]
+    fn test_inline() {
+        let s = store("inline", 1).with_inline_threshold(64);
+        let uuids: Vec<_> = (0..8u8).map(|i| s.put(&[i; 10]).unwrap()).collect();
+        let streamed = s.put_stream(&mut &b"streamed"[..], 0).unwrap();
+        // none of them took the only block
+        let large = s.put(&[1; 100]).unwrap();
+        assert_eq!(s.get(uuids[3]).unwrap().unwrap(), [3; 10]);
+        assert_eq!(s.get_range(streamed, 2, 100).unwrap().unwrap(), b"reamed");
+        assert_eq!(s.get(large).unwrap().unwrap()[..100], [1; 100][..]);
+        cleanup("inline");
+    }
+
+    #[test]
+    fn test_metadata() {
+        let s = store("metadata", 16);
+        let uuid = s.put_with_metadata(b"hello", ObjectMetadata::with_content_type("text/plain")).unwrap();
+        assert_eq!(s.head(uuid).unwrap().unwrap().metadata.content_type.as_deref(), Some("text/plain"));
+        let updated = s.update_metadata(uuid, ObjectMetadata::default().tag("k", "v")).unwrap().unwrap();
+        assert_eq!(updated.content_type, None);
+        assert_eq!(s.head(uuid).unwrap().unwrap().metadata, updated);
+
+        // streamed objects are stamped too
+        let streamed = s.put_stream(&mut &b"streamed"[..], 0).unwrap();
+        assert!(s.head(streamed).unwrap().unwrap().metadata.created > 0);
+        cleanup("metadata");
+    }
+
+    #[test]
+    fn test_concurrent_clients() {
+        let s = Arc::new(store("threads", 256));
+
+        let handles: Vec<_> = (0..8u8).map(|t| {
+            let s = Arc::clone(&s);
+            thread::spawn(move || {
+                let mut kept = Vec::new();
+                for i in 0..16u8 {
+                    let data = vec![t.wrapping_mul(31).wrapping_add(i); BS4K + i as usize];
+                    let uuid = s.put(&data).unwrap();
+                    let got = s.get(uuid).unwrap().unwrap();
+                    assert_eq!(&got[..data.len()], &data[..]);
+                    if i % 2 == 0 {
+                        s.delete(uuid).unwrap();
+                        assert_eq!(s.get(uuid).unwrap(), None);
+                    } else {
+                        kept.push((uuid, data));
+                    }
+                }
+                kept
+            })
+        }).collect();
+
+        let kept: Vec<_> = handles.into_iter().flat_map(|h| h.join().unwrap()).collect();
+        assert_eq!(kept.len(), 64);
+        for (uuid, data) in kept {
+            assert_eq!(&s.get(uuid).unwrap().unwrap()[..data.len()], &data[..]);
+        }
+        cleanup("threads");
+    }
+
+    #[test]
+    fn test_concurrent_streams() {
+        let s = Arc::new(store("streams", 2048));
+        let handles: Vec<_> = (0..4u8).map(|t| {
+            let s = Arc::clone(&s);
+            thread::spawn(move || {
+                let data = vec![t; 300 * BS4K + t as usize];
+                let uuid = s.put_stream(&mut &data[..], 0).unwrap();
+                let mut read = Vec::new();
+                s.get_stream(uuid).unwrap().unwrap().read_to_end(&mut read).unwrap();
+                assert_eq!(read, data);
+                uuid
+            })
+        }).collect();
+        let uuids: Vec<ObjectID> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+
+        for uuid in uuids {
+            s.delete(uuid).unwrap();
+        }
+        // every block was released, including those trimmed from the streams
+        assert!(s.freelist().unwrap().allocate(2048 * BS4K as u64).is_ok());
+        cleanup("streams");
+    }
+
+    #[test]
+    fn test_unsaved_keys() {
+        // the keystore takes keys in memory, but can't save them
+        let s = SharedObjectStore::new(
+            SingleDeviceBlockStore::new(path("unsaved", "bin"), 4 * BS4K as u64).unwrap(),
+            VecFreeList::new(4),
+            KeyGen {},
+            JsonKeystore::<ObjKey>::new(path("unsaved", "missing").join("keys.json")),
+        );
+        let data = vec![1; 2 * BS4K];
+        let uuid = KeyGen {}.make_key(&data).unwrap().uuid;
+        assert!(s.put(&data).is_err());
+        assert!(s.put_stream(&mut &data[..], 0).is_err());
+
+        // neither put left its key behind or kept its blocks
+        assert!(s.head(uuid).unwrap().is_none());
+        assert!(s.freelist().unwrap().allocate(4 * BS4K as u64).is_ok());
+        let _ = std::fs::remove_file(path("unsaved", "bin"));
+    }
+
+    #[test]
+    fn test_recover_reclaims_interrupted_put() {
+        let _ = std::fs::remove_file(path("recover", "log"));
+        let mut fl = VecFreeList::new(16);
+        {
+            // a put that crashed after writing its data but before storing its key
+            let mut intents = IntentLog::open(path("recover", "log")).unwrap();
+            let manifest = fl.allocate(3 * BS4K as u64).unwrap();
+            intents.begin(Uuid::new_v4(), &manifest).unwrap();
+        }
+
+        let _ = std::fs::remove_file(path("recover", "json"));
+        let s = SharedObjectStore::new(
+            SingleDeviceBlockStore::new(path("recover", "bin"), 16 * BS4K as u64).unwrap(),
+            fl,
+            KeyGen {},
+            JsonKeystore::<ObjKey>::new(path("recover", "json")),
+        ).with_intent_log(IntentLog::open(path("recover", "log")).unwrap());
+        assert_eq!(s.recover().unwrap(), 1);
+        assert_eq!(s.recover().unwrap(), 0);
+        let all = s.freelist().unwrap().allocate(16 * BS4K as u64).unwrap();
+        s.freelist().unwrap().release(&all).unwrap();
+
+        // completed puts leave nothing to recover
+        let uuid = s.put(&vec![1; 2 * BS4K]).unwrap();
+        s.put_stream(&mut &vec![2; 3 * BS4K][..], 0).unwrap();
+        assert_eq!(s.intents().unwrap().unwrap().pending().count(), 0);
+        assert_eq!(s.recover().unwrap(), 0);
+        assert_eq!(s.get(uuid).unwrap(), Some(vec![1; 2 * BS4K]));
+        cleanup("recover");
+        let _ = std::fs::remove_file(path("recover", "log"));
+    }
+
+    #[test]
+    fn test_stream_does_not_block_writers() {
+        let s = store("unblocked", 1024);
+        let data: Vec<u8> = (0..2 * STREAM_CHUNK_BLOCKS as usize * BS4K).map(|i| (i / BS4K) as u8).collect();
+        let uuid = s.put(&data).unwrap();
+        let other = s.put(b"other").unwrap();
+
+        // puts and deletes go ahead while a reader is part way through an object
+        let mut reader = s.get_stream(uuid).unwrap().unwrap();
+        let mut first = vec![0; 10];
+        reader.read_exact(&mut first).unwrap();
+        assert_eq!(first, &data[..10]);
+        s.put(b"meanwhile").unwrap();
+        s.delete(other).unwrap();
+
+        // the rest of the chunk was read already, but the object is gone before the next one
+        let mut rest = vec![0; STREAM_CHUNK_BLOCKS as usize * BS4K - 10];
+        reader.read_exact(&mut rest).unwrap();
+        s.delete(uuid).unwrap();
+        assert!(reader.read(&mut rest).is_err());
+        cleanup("unblocked");
+    }
+
+    /// counts the keys set in the keystore it wraps
+    struct CountingKeyStore {
+        inner: JsonKeystore<ObjKey>,
+        sets: Arc<AtomicUsize>,
+    }
+
+    impl KeyStore<ObjKey> for CountingKeyStore {
+        fn set(&mut self, uuid: Uuid, key: ObjKey) -> RResult<Option<ObjKey>> {
+            self.sets.fetch_add(1, Ordering::SeqCst);
+            self.inner.set(uuid, key)
+        }
+        fn get(&self, uuid: &Uuid) -> RResult<Option<&ObjKey>> {
+            self.inner.get(uuid)
+        }
+        fn delete(&mut self, uuid: &Uuid) -> RResult<Option<ObjKey>> {
+            self.inner.delete(uuid)
+        }
+        fn list(&self, query: &ListQuery<ObjKey>) -> RResult<Page<(Uuid, &ObjKey)>> {
+            self.inner.list(query)
+        }
+    }
+
+    #[test]
+    fn test_streamed_put_with_metadata() {
+        let _ = std::fs::remove_file(path("streammeta", "json"));
+        let sets = Arc::new(AtomicUsize::new(0));
+        let s = SharedObjectStore::new(
+            SingleDeviceBlockStore::new(path("streammeta", "bin"), 16 * BS4K as u64).unwrap(),
+            VecFreeList::new(16),
+            KeyGen {},
+            CountingKeyStore { inner: JsonKeystore::new(path("streammeta", "json")), sets: Arc::clone(&sets) },
+        );
+
+        // the key is stored once, metadata and all
+        let data = vec![3; 2 * BS4K];
+        let metadata = ObjectMetadata::with_content_type("text/plain").tag("stage", "raw");
+        let uuid = s.put_stream_with_metadata(&mut &data[..], 0, metadata).unwrap();
+        assert_eq!(sets.load(Ordering::SeqCst), 1);
+        let key = s.head(uuid).unwrap().unwrap();
+        assert_eq!(key.metadata.content_type.as_deref(), Some("text/plain"));
+        assert!(key.metadata.created > 0);
+
+        // storing the same data again replaces its metadata, without keeping the new blocks
+        let metadata = ObjectMetadata::default().tag("stage", "done");
+        assert_eq!(s.put_stream_with_metadata(&mut &data[..], 0, metadata).unwrap(), uuid);
+        assert_eq!(s.head(uuid).unwrap().unwrap().metadata.tags["stage"], "done");
+        s.delete(uuid).unwrap();
+        assert!(s.freelist().unwrap().allocate(16 * BS4K as u64).is_ok());
+        cleanup("streammeta");
+    }
+
+    /// lets a shared read finish only once another one has started alongside it
+    struct Rendezvous {
+        inner: SingleDeviceBlockStore,
+        readers: Mutex<usize>,
+        arrived: Condvar,
+    }
+
+    impl BlockStore for Rendezvous {
+        fn write(&mut self, data: &[u8], key: &ObjKey) -> RResult<()> {
+            self.inner.write(data, key)
+        }
+
+        fn read(&mut self, data: &mut Vec<u8>, key: &ObjKey) -> RResult<()> {
+            self.inner.read(data, key)
+        }
+
+        fn read_shared(&self, data: &mut Vec<u8>, key: &ObjKey) -> Option<RResult<()>> {
+            let mut readers = self.readers.lock().unwrap();
+            *readers += 1;
+            self.arrived.notify_all();
+            let (readers, timeout) = self.arrived
+                .wait_timeout_while(readers, Duration::from_secs(10), |n| *n < 2)
+                .unwrap();
+            drop(readers);
+            if timeout.timed_out() {
+                return Some(Err(RustorError::Config("reads were serialized".to_string())));
+            }
+            self.inner.read_shared(data, key)
+        }
+    }
+
+    #[test]
+    fn test_parallel_gets() {
+        let _ = std::fs::remove_file(path("parallel", "json"));
+        let s = Arc::new(SharedObjectStore::new(
+            Rendezvous {
+                inner: SingleDeviceBlockStore::new(path("parallel", "bin"), 16 * BS4K as u64).unwrap(),
+                readers: Mutex::new(0),
+                arrived: Condvar::new(),
+            },
+            VecFreeList::new(16),
+            KeyGen {},
+            JsonKeystore::<ObjKey>::new(path("parallel", "json")),
+        ));
+        let uuids = [s.put(b"first").unwrap(), s.put(b"second").unwrap()];
+
+        // each get waits inside the block store for the other, so they only both finish if they
+        // read at the same time
+        let handles: Vec<_> = uuids.iter().map(|&uuid| {
+            let s = Arc::clone(&s);
+            thread::spawn(move || s.get(uuid).unwrap().unwrap())
+        }).collect();
+        let got: Vec<Vec<u8>> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+        assert_eq!(&got[0][..5], b"first");
+        assert_eq!(&got[1][..6], b"second");
+        cleanup("parallel");
+    }
+}
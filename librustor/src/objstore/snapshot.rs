@@ -0,0 +1,157 @@
+use std::collections::{HashMap, HashSet};
+
+use serde::{Serialize, Deserialize};
+use uuid::Uuid;
+
+use crate::{RResult, RustorError};
+use crate::freelist::FreeList;
+use crate::keystore::{KeyStore, ListQuery};
+use crate::object::{ObjKey, Manifest, ManifestLocation, unix_time};
+
+#[allow(unused_imports)]
+use log::{trace, debug, info, warn, error};
+
+/// The keys of every object stored at a point in time. The blocks the keys refer to are held,
+/// not returned to the free list, until the snapshot is deleted
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Snapshot {
+    pub id: Uuid,
+    pub name: String,
+    /// seconds since the unix epoch
+    pub created: u64,
+    pub keys: Vec<ObjKey>,
+}
+
+// Snapshots are kept in a KeyStore of their own, under their ids. The functions here take the
+// object keystore, free list and snapshot keystore separately so that both BasicObjectStore and
+// SharedObjectStore can call them with whatever they hold, under their own locks.
+
+fn all_keys(keys: &dyn KeyStore<ObjKey>) -> RResult<Vec<ObjKey>> {
+    Ok(keys.list(&ListQuery::default())?.cloned().keys)
+}
+
+fn shards<'a, I: Iterator<Item = &'a ObjKey>>(keys: I) -> HashSet<ManifestLocation> {
+    keys.flat_map(|key| key.manifest.shards.iter().copied()).collect()
+}
+
+/// the extents referred to by any snapshot
+pub fn held(snapshots: &dyn KeyStore<Snapshot>) -> RResult<HashSet<ManifestLocation>> {
+    let page = snapshots.list(&ListQuery::default())?;
+    Ok(shards(page.keys.iter().flat_map(|(_, snapshot)| snapshot.keys.iter())))
+}
+
+/// Every snapshot, oldest first
+pub fn list(snapshots: &dyn KeyStore<Snapshot>) -> RResult<Vec<Snapshot>> {
+    let mut list = snapshots.list(&ListQuery::default())?.cloned().keys;
+    list.sort_by_key(|s| (s.created, s.id));
+    Ok(list)
+}
+
+/// Return the blocks of `manifest` to the free list, except those a snapshot holds
+pub fn release(freelist: &mut dyn FreeList, snapshots: Option<&dyn KeyStore<Snapshot>>, manifest: &Manifest) -> RResult<()> {
+    let held = match snapshots {
+        Some(snapshots) => held(snapshots)?,
+        None => return freelist.release(manifest),
+    };
+    let free = Manifest { shards: manifest.shards.iter().filter(|s| !held.contains(s)).copied().collect() };
+    if free.shards.len() < manifest.shards.len() {
+        trace!("{} extents held by snapshots", manifest.shards.len() - free.shards.len());
+    }
+    freelist.release(&free)
+}
+
+/// Record the keys of every stored object as the snapshot `name`. returns its id
+pub fn create(keys: &dyn KeyStore<ObjKey>, snapshots: &mut dyn KeyStore<Snapshot>, name: &str) -> RResult<Uuid> {
+    let id = Uuid::new_v4();
+    let snapshot = Snapshot { id, name: name.to_string(), created: unix_time(), keys: all_keys(keys)? };
+    info!("snapshot {} of {} objects", name, snapshot.keys.len());
+    snapshots.set(id, snapshot)?;
+    Ok(id)
+}
+
+/// Put the keystore back as it was when the snapshot was taken: objects stored since are
+/// deleted, and objects deleted since come back with the data they had. returns the number of
+/// objects in the snapshot
+pub fn restore(keys: &mut dyn KeyStore<ObjKey>, freelist: &mut dyn FreeList, snapshots: &dyn KeyStore<Snapshot>,
+    id: Uuid) -> RResult<usize>
+{
+    let snapshot = snapshots.get(&id)?.ok_or(RustorError::SnapshotNotFound { id })?;
+    let restored: HashMap<Uuid, &ObjKey> = snapshot.keys.iter().map(|k| (k.uuid, k)).collect();
+    for key in all_keys(keys)? {
+        match restored.get(&key.uuid) {
+            // the same object, though its metadata may have changed since
+            Some(old) if old.manifest == key.manifest => continue,
+            Some(_) => (),
+            None => {
+                keys.delete(&key.uuid)?;
+            }
+        }
+        release(freelist, Some(snapshots), &key.manifest)?;
+    }
+    for key in snapshot.keys.iter() {
+        keys.set(key.uuid, key.clone())?;
+    }
+    Ok(snapshot.keys.len())
+}
+
+/// Delete the snapshot, returning the blocks only it held to the free list
+pub fn delete(keys: &dyn KeyStore<ObjKey>, freelist: &mut dyn FreeList, snapshots: &mut dyn KeyStore<Snapshot>,
+    id: Uuid) -> RResult<Snapshot>
+{
+    let snapshot = snapshots.delete(&id)?.ok_or(RustorError::SnapshotNotFound { id })?;
+    let mut referenced = held(snapshots)?;
+    referenced.extend(shards(all_keys(keys)?.iter()));
+    let free = Manifest {
+        shards: shards(snapshot.keys.iter()).into_iter().filter(|s| !referenced.contains(s)).collect(),
+    };
+    debug!("deleting snapshot {} frees {} extents", &snapshot.name, free.shards.len());
+    freelist.release(&free)?;
+    Ok(snapshot)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::freelist::VecFreeList;
+    use crate::keystore::JsonKeystore;
+
+    #[test]
+    fn test_held_blocks() {
+        let path = |ext: &str| std::env::temp_dir().join(format!("rustor-snapshot-held-{}.{}", std::process::id(), ext));
+        let _ = std::fs::remove_file(path("keys.json"));
+        let _ = std::fs::remove_file(path("snapshots.json"));
+        let mut keys = JsonKeystore::<ObjKey>::new(path("keys.json"));
+        let mut snapshots = JsonKeystore::<Snapshot>::new(path("snapshots.json"));
+        let mut fl = VecFreeList::new(16);
+
+        let key = |fl: &mut VecFreeList, n: u8| {
+            let uuid = Uuid::from_bytes([n; 16]);
+            ObjKey { uuid, size: 4096, manifest: fl.allocate(4096).unwrap(), ..Default::default() }
+        };
+        let a = key(&mut fl, 1);
+        keys.set(a.uuid, a.clone()).unwrap();
+        let id = create(&keys, &mut snapshots, "before").unwrap();
+
+        // deleting after the snapshot keeps the block
+        let b = key(&mut fl, 2);
+        keys.set(b.uuid, b.clone()).unwrap();
+        keys.delete(&a.uuid).unwrap();
+        release(&mut fl, Some(&snapshots), &a.manifest).unwrap();
+        assert_eq!(fl.free_blocks(), 14);
+
+        // restoring brings a back and frees b's block
+        assert_eq!(restore(&mut keys, &mut fl, &snapshots, id).unwrap(), 1);
+        assert!(keys.get(&a.uuid).unwrap().is_some());
+        assert!(keys.get(&b.uuid).unwrap().is_none());
+        assert_eq!(fl.free_blocks(), 15);
+        assert_eq!(list(&snapshots).unwrap()[0].name, "before");
+
+        // a is still stored, so deleting the snapshot frees nothing
+        delete(&keys, &mut fl, &mut snapshots, id).unwrap();
+        assert_eq!(fl.free_blocks(), 15);
+        assert!(matches!(restore(&mut keys, &mut fl, &snapshots, id), Err(RustorError::SnapshotNotFound { .. })));
+
+        let _ = std::fs::remove_file(path("keys.json"));
+        let _ = std::fs::remove_file(path("snapshots.json"));
+    }
+}
@@ -0,0 +1,336 @@
+use std::io::{self, Read, Seek, SeekFrom};
+
+use uuid::Uuid;
+
+use crate::{BS4K, RResult, RustorError};
+use crate::keygen::KeyHasher;
+use crate::object::{ObjKey, Manifest};
+
+#[allow(unused_imports)]
+use log::{trace, debug, info, warn, error};
+
+/// streams are read and written this many blocks at a time
+pub const STREAM_CHUNK_BLOCKS: u64 = 256;
+
+fn blocks(bytes: u64) -> u64 {
+    bytes.div_ceil(BS4K as u64)
+}
+
+/// a key for reading or writing just `manifest`, a piece of the object `uuid`
+fn piece(uuid: Uuid, manifest: Manifest) -> ObjKey {
+    ObjKey { uuid, size: manifest.blocks() * BS4K as u64, manifest, ..Default::default() }
+}
+
+/// Read up to `threshold` bytes of `data`. if fewer come back, that was all of it and the object
+/// can be stored inline; otherwise they must be read again ahead of the rest
+pub(crate) fn read_head(data: &mut dyn Read, threshold: u64) -> RResult<Vec<u8>> {
+    let mut head = Vec::new();
+    data.take(threshold).read_to_end(&mut head)?;
+    Ok(head)
+}
+
+/// The progress of a streamed put: the data hashed so far, the blocks allocated for it and how
+/// many of them have been written.
+///
+/// Space is allocated as the data arrives: all of the size hint up front, and a chunk at a time
+/// once the data outgrows it. Whatever the data doesn't fill is handed back by `finish`.
+pub(crate) struct Upload {
+    hasher: KeyHasher,
+    hint: u64,
+    manifest: Manifest,
+    written: u64,
+    buf: Vec<u8>,
+}
+
+impl Upload {
+    pub fn new(hasher: KeyHasher, size_hint: u64) -> Self {
+        Self {
+            hasher,
+            hint: size_hint,
+            manifest: Manifest::new(),
+            written: 0,
+            buf: Vec::with_capacity(STREAM_CHUNK_BLOCKS as usize * BS4K),
+        }
+    }
+
+    /// everything allocated so far
+    pub fn manifest(&self) -> &Manifest {
+        &self.manifest
+    }
+
+    /// read the next chunk of `data`. returns false once it is exhausted
+    pub fn fill(&mut self, data: &mut dyn Read) -> RResult<bool> {
+        let chunk = STREAM_CHUNK_BLOCKS as usize * BS4K;
+        self.buf.resize(chunk, 0);
+        let mut n = 0;
+        while n < chunk {
+            match data.read(&mut self.buf[n..]) {
+                Ok(0) => break,
+                Ok(read) => n += read,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e)?,
+            }
+        }
+        self.buf.truncate(n);
+        self.hasher.update(&self.buf);
+        Ok(n > 0)
+    }
+
+    /// bytes that must be allocated before the chunk read by `fill` can be written
+    pub fn shortfall(&self) -> u64 {
+        let free = self.manifest.blocks() - self.written;
+        let needed = blocks(self.buf.len() as u64);
+        if needed <= free {
+            return 0;
+        }
+        let bytes = (needed - free) * BS4K as u64;
+        if self.manifest.shards.is_empty() {
+            bytes.max(blocks(self.hint) * BS4K as u64)
+        } else {
+            bytes
+        }
+    }
+
+    pub fn allocated(&mut self, manifest: Manifest) {
+        self.manifest.shards.extend(manifest.shards);
+    }
+
+    /// the chunk read by `fill` and a key for writing it to the blocks that follow those already
+    /// written
+    pub fn chunk(&mut self) -> (ObjKey, &[u8]) {
+        let span = blocks(self.buf.len() as u64);
+        let key = piece(Uuid::nil(), Manifest { shards: self.manifest.extents(self.written, span) });
+        self.written += span;
+        (key, &self.buf)
+    }
+
+    /// the key of the data streamed, and the allocation it didn't need
+    pub fn finish(mut self) -> (ObjKey, Manifest) {
+        let unused = self.manifest.split_off(self.written);
+        let mut key = self.hasher.finish();
+        key.manifest = self.manifest;
+        (key, unused)
+    }
+}
+
+/// reads `key`'s blocks into the end of the buffer
+pub(crate) type ReadBlocks<'a> = Box<dyn FnMut(&ObjKey, &mut Vec<u8>) -> RResult<()> + 'a>;
+
+/// A reader over a stored object that fetches its blocks a chunk at a time.
+///
+/// Seeking only moves the position, so a range of the object can be read by seeking to its start
+/// without reading anything before it. `set_range` also bounds the end, so that only the blocks
+/// holding the range are read.
+pub struct ObjectReader<'a> {
+    read: ReadBlocks<'a>,
+    key: ObjKey,
+    pos: u64,
+    /// reads stop here
+    end: u64,
+    buf: Vec<u8>,
+    buf_start: u64,
+}
+
+impl<'a> ObjectReader<'a> {
+    pub(crate) fn new(key: ObjKey, read: ReadBlocks<'a>) -> Self {
+        Self { read, pos: 0, end: key.size, key, buf: Vec::new(), buf_start: 0 }
+    }
+
+    /// the key of the object being read
+    pub fn key(&self) -> &ObjKey {
+        &self.key
+    }
+
+    /// the object's size in bytes
+    pub fn size(&self) -> u64 {
+        self.key.size
+    }
+
+    /// Limit reads to `len` bytes from `offset`. A range running past the end of the object is
+    /// cut short, but one starting past the end is an error
+    pub fn set_range(&mut self, offset: u64, len: u64) -> RResult<()> {
+        if offset > self.key.size || (offset == self.key.size && len > 0 && self.key.size > 0) {
+            return Err(RustorError::RangeNotSatisfiable { uuid: self.key.uuid, offset, size: self.key.size });
+        }
+        self.pos = offset;
+        self.end = offset.saturating_add(len).min(self.key.size);
+        Ok(())
+    }
+
+    /// bytes left to read
+    pub fn remaining(&self) -> u64 {
+        self.end.saturating_sub(self.pos)
+    }
+
+    /// read everything left
+    pub fn read_all(mut self) -> RResult<Vec<u8>> {
+        let mut data = Vec::with_capacity(self.remaining() as usize);
+        self.read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    /// read the chunk containing `pos` into the buffer
+    fn load(&mut self) -> RResult<()> {
+        if let Some(inline) = self.key.inline.as_ref() {
+            // the whole object is in its key
+            self.buf = inline.clone();
+            self.buf_start = 0;
+            return Ok(());
+        }
+        let block = self.pos / BS4K as u64;
+        let span = STREAM_CHUNK_BLOCKS.min(blocks(self.end) - block);
+        trace!("reading {} blocks of {:?} from block {}", span, &self.key.uuid, block);
+        let chunk = piece(self.key.uuid, Manifest { shards: self.key.manifest.extents(block, span) });
+        self.buf.clear();
+        (self.read)(&chunk, &mut self.buf)?;
+        self.buf.truncate(span as usize * BS4K);
+        self.buf_start = block * BS4K as u64;
+        Ok(())
+    }
+}
+
+impl Read for ObjectReader<'_> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.end || out.is_empty() {
+            return Ok(0);
+        }
+        let buffered = self.buf_start..self.buf_start + self.buf.len() as u64;
+        if !buffered.contains(&self.pos) {
+            self.load()?;
+        }
+        let start = (self.pos - self.buf_start) as usize;
+        let end = self.buf.len().min((self.end - self.buf_start) as usize);
+        let n = out.len().min(end - start);
+        out[..n].copy_from_slice(&self.buf[start..start + n]);
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for ObjectReader<'_> {
+    fn seek(&mut self, to: SeekFrom) -> io::Result<u64> {
+        let pos = match to {
+            SeekFrom::Start(n) => Some(n),
+            SeekFrom::End(d) => self.key.size.checked_add_signed(d),
+            SeekFrom::Current(d) => self.pos.checked_add_signed(d),
+        };
+        match pos {
+            Some(pos) => {
+                self.pos = pos;
+                Ok(pos)
+            }
+            None => Err(io::Error::new(io::ErrorKind::InvalidInput, "seek before the start of the object")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keygen::{KeyGen, GeneratesKeys};
+    use crate::object::ManifestLocation;
+
+    /// a reader over `data` as if it were stored in `shards`, recording the blocks it reads
+    fn reader<'a>(data: Vec<u8>, shards: &[(u64, u64)], reads: &'a mut Vec<(u64, u64)>) -> ObjectReader<'a> {
+        let manifest = Manifest { shards: shards.iter().map(|(lba, span)| ManifestLocation { blkdevid: None, lba: *lba, span: *span }).collect() };
+        // map each lba back to its block of the object
+        let lbas: Vec<u64> = manifest.shards.iter().flat_map(|s| s.lba..s.lba + s.span).collect();
+        let key = ObjKey { size: data.len() as u64, manifest, ..Default::default() };
+        ObjectReader::new(key, Box::new(move |key: &ObjKey, buf: &mut Vec<u8>| {
+            for shard in key.manifest.shards.iter() {
+                reads.push((shard.lba, shard.span));
+                for lba in shard.lba..shard.lba + shard.span {
+                    let block = lbas.iter().position(|l| *l == lba).unwrap() * BS4K;
+                    let mut b = data[block.min(data.len())..(block + BS4K).min(data.len())].to_vec();
+                    b.resize(BS4K, 0);
+                    buf.extend_from_slice(&b);
+                }
+            }
+            Ok(())
+        }))
+    }
+
+    #[test]
+    fn test_read_and_seek() {
+        let n = 300 * BS4K + 5;
+        let data: Vec<u8> = (0..n).map(|i| (i / BS4K) as u8 ^ i as u8).collect();
+        let mut reads = Vec::new();
+        {
+            let mut r = reader(data.clone(), &[(1000, 100), (0, 201)], &mut reads);
+            let mut all = Vec::new();
+            r.read_to_end(&mut all).unwrap();
+            assert_eq!(all, data);
+
+            // a range read starts from the block holding it
+            r.seek(SeekFrom::Start(150 * BS4K as u64 + 7)).unwrap();
+            let mut range = [0; 10];
+            r.read_exact(&mut range).unwrap();
+            assert_eq!(&range[..], &data[150 * BS4K + 7..150 * BS4K + 17]);
+
+            assert_eq!(r.seek(SeekFrom::End(-2)).unwrap(), n as u64 - 2);
+            let mut tail = Vec::new();
+            r.read_to_end(&mut tail).unwrap();
+            assert_eq!(tail, &data[n - 2..]);
+            assert!(r.seek(SeekFrom::Current(-(n as i64) - 1)).is_err());
+        }
+        // the tail was still buffered from the range read
+        assert_eq!(reads, vec![(1000, 100), (0, 156), (156, 45), (50, 151)]);
+    }
+
+    #[test]
+    fn test_range_reads_only_its_blocks() {
+        let n = 300 * BS4K + 5;
+        let data: Vec<u8> = (0..n).map(|i| (i / BS4K) as u8 ^ i as u8).collect();
+        let mut reads = Vec::new();
+        {
+            let mut r = reader(data.clone(), &[(1000, 100), (0, 201)], &mut reads);
+            r.set_range(150 * BS4K as u64 + 7, 10).unwrap();
+            assert_eq!(r.remaining(), 10);
+            assert_eq!(r.read_all().unwrap(), &data[150 * BS4K + 7..150 * BS4K + 17]);
+
+            // across a shard boundary, and cut short at the end of the object
+            let mut r = reader(data.clone(), &[(1000, 100), (0, 201)], &mut reads);
+            r.set_range(99 * BS4K as u64, 2 * BS4K as u64).unwrap();
+            assert_eq!(r.read_all().unwrap(), &data[99 * BS4K..101 * BS4K]);
+            let mut r = reader(data.clone(), &[(1000, 100), (0, 201)], &mut reads);
+            r.set_range(n as u64 - 3, 100).unwrap();
+            assert_eq!(r.read_all().unwrap(), &data[n - 3..]);
+
+            let mut r = reader(data.clone(), &[(1000, 100), (0, 201)], &mut reads);
+            assert!(r.set_range(n as u64, 0).is_ok());
+            assert!(matches!(r.set_range(n as u64, 1), Err(RustorError::RangeNotSatisfiable { .. })));
+        }
+        assert_eq!(reads, vec![(50, 1), (1099, 1), (0, 1), (200, 1)]);
+    }
+
+    #[test]
+    fn test_upload() {
+        let data = vec![7u8; STREAM_CHUNK_BLOCKS as usize * BS4K + 10];
+        let mut upload = Upload::new(KeyGen {}.hasher(), 2 * BS4K as u64);
+        let mut input = &data[..];
+
+        // the first chunk is allocated all at once, rather than just the hint
+        assert!(upload.fill(&mut input).unwrap());
+        assert_eq!(upload.shortfall(), STREAM_CHUNK_BLOCKS * BS4K as u64);
+        upload.allocated(Manifest { shards: vec![ManifestLocation { blkdevid: None, lba: 0, span: 200 }] });
+        upload.allocated(Manifest { shards: vec![ManifestLocation { blkdevid: None, lba: 500, span: 56 }] });
+        let (key, chunk) = upload.chunk();
+        assert_eq!(chunk.len(), STREAM_CHUNK_BLOCKS as usize * BS4K);
+        assert_eq!(key.manifest.blocks(), STREAM_CHUNK_BLOCKS);
+
+        // the last block is allocated, then an over-allocation is trimmed
+        assert!(upload.fill(&mut input).unwrap());
+        assert_eq!(upload.shortfall(), BS4K as u64);
+        upload.allocated(Manifest { shards: vec![ManifestLocation { blkdevid: None, lba: 900, span: 3 }] });
+        let (key, chunk) = upload.chunk();
+        assert_eq!(chunk.len(), 10);
+        assert_eq!((key.manifest.shards[0].lba, key.manifest.blocks()), (900, 1));
+        assert!(!upload.fill(&mut input).unwrap());
+
+        let (key, unused) = upload.finish();
+        assert_eq!(key.uuid, KeyGen {}.make_key(&data).unwrap().uuid);
+        assert_eq!(key.size, data.len() as u64);
+        assert_eq!(key.manifest.blocks(), STREAM_CHUNK_BLOCKS + 1);
+        assert_eq!((unused.shards[0].lba, unused.blocks()), (901, 2));
+    }
+}
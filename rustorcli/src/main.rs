@@ -1,9 +1,10 @@
 use librustor::*;
-use librustor::object::ObjKey;
+use librustor::object::{ObjKey, ObjectMetadata};
 use librustor::RResult;
-use librustor::objstore::BasicObjectStore;
+use librustor::objstore::{BasicObjectStore, IntentLog, Namespace, NamespaceRecord, Snapshot};
+use librustor::objstore::snapshot;
 use librustor::blockstore::SingleDeviceBlockStore;
-use librustor::keystore::JsonKeystore;
+use librustor::keystore::{JsonKeystore, ListQuery, KeyFilter};
 use librustor::freelist::BitmapFreelist;
 
 use std::path::PathBuf;
@@ -23,10 +24,18 @@ use log;
 use log::{trace, debug, info, warn, error};
 use env_logger;
 
-fn main() -> RResult<()> {
-
+fn main() {
     env_logger::init();
 
+    // failures exit with a sysexits.h status, e.g. 66 for an object that doesn't exist
+    if let Err(e) = run() {
+        eprintln!("rustorcli: {}", e);
+        std::process::exit(e.exit_code());
+    }
+}
+
+fn run() -> RResult<()> {
+
     // PUT an object:
     //  get object size
     //  hash object data
@@ -53,25 +62,43 @@ fn main() -> RResult<()> {
 
     let keystore_file = matches.value_of("KEYSTORE").unwrap_or("keys.json");
     let objstore_file = matches.value_of("OBJSTORE").unwrap_or("data.bin");
+    let intents_file = matches.value_of("intents").unwrap_or("intents.log");
+    let buckets_file = matches.value_of("buckets").unwrap_or("buckets.json");
+    let snapshots_file = matches.value_of("snapshots").unwrap_or("snapshots.json");
+    let inline_threshold = bytes_arg(matches.value_of("inline_threshold").unwrap_or("256"))?;
     
     let interactive: bool = matches.is_present("interactive");
     debug!("{:#?}", matches);
 
     let size = 1024*1024;
-    let mut bs = SingleDeviceBlockStore::new(PathBuf::from(objstore_file), size);
+    let mut bs = SingleDeviceBlockStore::new(PathBuf::from(objstore_file), size)?;
     let mut fl = BitmapFreelist::new(size as usize);
     let kg = keygen::KeyGen {};
     let mut ks: JsonKeystore<ObjKey> = keystore::JsonKeystore::new(PathBuf::from(keystore_file));
+    let mut snapshots: JsonKeystore<Snapshot> = JsonKeystore::new(PathBuf::from(snapshots_file));
 
-    // reconstruct free list from keystore
+    // reconstruct free list from keystore, and the blocks snapshots still hold
+    let mut held = snapshot::held(&snapshots)?;
     for obj in ks.get_objects().values() {
         debug!("taking blocks for {:?}", &obj.uuid);
         for block in obj.manifest.shards.iter() {
             fl.take(block.span, block.lba)?;
+            held.remove(block);
         }
     }
+    for block in held.iter() {
+        fl.take(block.span, block.lba)?;
+    }
 
-    let mut fs = BasicObjectStore::new(&mut bs, &mut fl, kg, &mut ks);
+    let mut il = IntentLog::open(PathBuf::from(intents_file))?;
+    let mut fs = BasicObjectStore::new(&mut bs, &mut fl, kg, &mut ks)
+        .with_intent_log(&mut il)
+        .with_snapshots(&mut snapshots)
+        .with_inline_threshold(inline_threshold);
+    let recovered = fs.recover()?;
+    if recovered > 0 {
+        info!("rolled back {} interrupted puts", recovered);
+    }
 
     if interactive { return interactive_loop(&mut fs); }
 
@@ -81,36 +108,179 @@ fn main() -> RResult<()> {
             debug!("subcommand matches: {:#?}", &matches);
             match subcommand {
                 "put" => {
-                    let uuid = fs.put(matches.value_of("data").unwrap().as_bytes())?;
+                    let data = matches.value_of("data").unwrap().as_bytes();
+                    let uuid = if matches.is_present("content_type") || matches.is_present("tag") {
+                        let mut metadata = ObjectMetadata {
+                            content_type: matches.value_of("content_type").map(String::from),
+                            ..Default::default()
+                        };
+                        for tag in matches.values_of("tag").into_iter().flatten() {
+                            let (k, v) = tag.split_once('=')
+                                .ok_or_else(|| RustorError::InvalidRequest(format!("tag is not KEY=VALUE: {:?}", tag)))?;
+                            metadata = metadata.tag(k, v);
+                        }
+                        fs.put_with_metadata(data, metadata)?
+                    } else {
+                        fs.put(data)?
+                    };
                     info!("uuid: {:?}", &uuid);
                 }
                 "get" => {
                     let id = Uuid::parse_str(matches.value_of("uuid").unwrap())?;
                     trace!("got id: {:?}", &id);
 
-                    if let Some(data) = fs.get(id)? {
-                        info!("retrieved data: {:?}", &data);
+                    let data = if matches.is_present("offset") || matches.is_present("len") {
+                        let offset = bytes_arg(matches.value_of("offset").unwrap_or("0"))?;
+                        let len = matches.value_of("len").map(bytes_arg).transpose()?.unwrap_or(u64::MAX);
+                        fs.get_range(id, offset, len)?
                     } else {
-                        warn!("Could not retrieve data for {:?}", &id);
-                    }
+                        fs.get(id)?
+                    };
+                    let data = data.ok_or(RustorError::NotFound { uuid: id })?;
+                    info!("retrieved data: {:?}", &data);
+                }
+                "bucket" => {
+                    let mut records: JsonKeystore<NamespaceRecord> = JsonKeystore::new(PathBuf::from(buckets_file));
+                    bucket_command(&mut Namespace::new(&mut fs, &mut records), matches)?;
+                }
+                "snapshot" => snapshot_command(&mut fs, matches)?,
+                "gc" => {
+                    let report = fs.gc(matches.is_present("dry_run"))?;
+                    let verb = if report.dry_run { "would reclaim" } else { "reclaimed" };
+                    info!("{} blocks referenced, {} {} blocks in {} areas", report.marked, verb, report.reclaimed, report.areas.len());
+                }
+                "head" => {
+                    let id = Uuid::parse_str(matches.value_of("uuid").unwrap())?;
+                    let key = fs.head(id)?.ok_or(RustorError::NotFound { uuid: id })?;
+                    info!("{} bytes, {:?}", key.size, &key.metadata);
                 }
                 "delete" => {
-
+                    let id = Uuid::parse_str(matches.value_of("uuid").unwrap())?;
+                    fs.delete(id)?.ok_or(RustorError::NotFound { uuid: id })?;
+                    info!("deleted {:?}", &id);
                 }
-                "keys" => { 
-
+                "keys" | "objs" => {
+                    let filter = KeyFilter {
+                        min_size: matches.value_of("min_size").map(bytes_arg).transpose()?,
+                        max_size: matches.value_of("max_size").map(bytes_arg).transpose()?,
+                        created_after: matches.value_of("created_after").map(bytes_arg).transpose()?,
+                    };
+                    let matching = |key: &ObjKey| filter.matches(key);
+                    let after = matches.value_of("after").map(Uuid::parse_str).transpose()?;
+                    let limit = matches.value_of("limit").map(bytes_arg).transpose()?.unwrap_or(0);
+                    let query = ListQuery::default().after(after).limit(limit as usize).filter(&matching);
+                    list(&mut fs, &query, subcommand == "objs")?;
                 }
-                "objs" => {
+                _ => Err(RustorError::InvalidRequest(format!("Unknown subcommand: {}", subcommand)))?
+            }
+        }
+    }
 
-                }
-                _ => Err(format!("Unknown subcommand: {}", subcommand))?
+    Ok(())
+}
+
+
+/// log a page of keys, and their data if `data`, and the cursor for the next page
+fn list(fs: &mut impl ObjectStore, query: &ListQuery<ObjKey>, data: bool) -> RResult<()> {
+    let page = fs.list(query)?;
+    for key in page.keys.iter() {
+        info!("{:?} {} bytes, created {}", &key.uuid, key.size, key.metadata.created);
+        if data {
+            let data = fs.get(key.uuid)?.ok_or(RustorError::NotFound { uuid: key.uuid })?;
+            info!("  {:?}", &data);
+        }
+    }
+    if let Some(next) = page.next {
+        info!("next: {:?}", &next);
+    }
+    Ok(())
+}
+
+fn snapshot_command(fs: &mut BasicObjectStore, matches: &clap::ArgMatches) -> RResult<()> {
+    let (subcommand, args) = matches.subcommand();
+    let arg = |name| args.and_then(|args| args.value_of(name)).unwrap_or("");
+    match subcommand {
+        "create" => {
+            let id = fs.snapshot(arg("name"))?;
+            info!("snapshot: {:?}", &id);
+        }
+        "list" => {
+            for snapshot in fs.snapshots()? {
+                info!("{:?} {} created {}, {} objects", &snapshot.id, &snapshot.name, snapshot.created, snapshot.keys.len());
             }
         }
+        "restore" => info!("restored {} objects", fs.restore_snapshot(Uuid::parse_str(arg("id"))?)?),
+        "delete" => {
+            fs.delete_snapshot(Uuid::parse_str(arg("id"))?)?;
+        }
+        _ => Err(RustorError::InvalidRequest(format!("Unknown snapshot subcommand: {:?}", subcommand)))?
     }
+    Ok(())
+}
 
+fn bucket_command(ns: &mut Namespace, matches: &clap::ArgMatches) -> RResult<()> {
+    let (subcommand, matches) = match matches.subcommand() {
+        (name, Some(matches)) => (name, matches),
+        _ => Err(RustorError::InvalidRequest("bucket needs a subcommand".to_string()))?,
+    };
+    let bucket = matches.value_of("bucket").unwrap_or("");
+    match subcommand {
+        "create" => {
+            let quota = matches.value_of("quota").map(bytes_arg).transpose()?;
+            ns.create_bucket(bucket, quota)?;
+        }
+        "delete" => ns.delete_bucket(bucket)?,
+        "list" if bucket.is_empty() => {
+            for name in ns.buckets()? {
+                info!("{}", name);
+            }
+        }
+        "list" => {
+            for (name, entry) in ns.list(bucket, matches.value_of("prefix").unwrap_or(""))? {
+                info!("{} {:?} {}", name, entry.uuid, entry.size);
+            }
+        }
+        "put" => {
+            let uuid = ns.put(bucket, matches.value_of("name").unwrap(), matches.value_of("data").unwrap().as_bytes())?;
+            info!("uuid: {:?}", &uuid);
+        }
+        "get" => {
+            let name = matches.value_of("name").unwrap();
+            let data = match matches.value_of("version") {
+                Some(version) => ns.get_version(bucket, name, bytes_arg(version)?)?,
+                None => ns.get(bucket, name)?,
+            };
+            let data = data
+                .ok_or_else(|| RustorError::NameNotFound { bucket: bucket.to_string(), name: Some(name.to_string()) })?;
+            info!("retrieved data: {:?}", &data);
+        }
+        "rm" => {
+            let name = matches.value_of("name").unwrap();
+            ns.delete(bucket, name)?
+                .ok_or_else(|| RustorError::NameNotFound { bucket: bucket.to_string(), name: Some(name.to_string()) })?;
+        }
+        "versioning" => {
+            let max = matches.value_of("max").map(bytes_arg).transpose()?.map(|n| n as usize);
+            ns.set_versioning(bucket, !matches.is_present("off"), max)?;
+        }
+        "versions" => {
+            for v in ns.versions(bucket, matches.value_of("name").unwrap())? {
+                match v.uuid {
+                    Some(uuid) => info!("{} {:?} {}", v.version, uuid, v.size),
+                    None => info!("{} deleted", v.version),
+                }
+            }
+        }
+        "gc" => info!("expired {} versions", ns.gc()?),
+        "mv" => ns.rename(bucket, matches.value_of("from").unwrap(), matches.value_of("to").unwrap())?,
+        _ => Err(RustorError::InvalidRequest(format!("Unknown bucket subcommand: {}", subcommand)))?
+    }
     Ok(())
 }
 
+fn bytes_arg(value: &str) -> RResult<u64> {
+    value.parse().map_err(|_| RustorError::InvalidRequest(format!("not a number of bytes: {:?}", value)))
+}
 
 fn interactive_loop(fs: &mut impl ObjectStore) -> RResult<()> {
     loop {
@@ -150,12 +320,8 @@ fn interactive_loop(fs: &mut impl ObjectStore) -> RResult<()> {
                 let result = fs.delete(uuid);
                 debug!("{:?}", result);
             },
-            "keys" => {
-
-            },
-            "objs" => {
-
-            }
+            "keys" => list(fs, &ListQuery::default(), false)?,
+            "objs" => list(fs, &ListQuery::default(), true)?,
             /*
                "objects" => {
                let objects = fs.get_objects();
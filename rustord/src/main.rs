@@ -1,3 +1,494 @@
+use librustor::{RResult, RustorError, BS4K};
+use librustor::object::{ObjKey, ObjectMetadata};
+use librustor::objstore::{IntentLog, ObjectReader, SharedObjectStore, Snapshot};
+use librustor::objstore::snapshot;
+use librustor::blockstore::SingleDeviceBlockStore;
+use librustor::keystore::JsonKeystore;
+use librustor::keygen::KeyGen;
+use librustor::freelist::{FreeList, VecFreeList, FreeListFromKeys};
+
+use std::fmt;
+use std::io::{Cursor, Read};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread;
+use uuid::Uuid;
+
+#[macro_use]
+extern crate clap;
+use clap::App;
+use tiny_http::{Header, Method, Request, Response, Server, StatusCode};
+
+#[allow(unused_imports)]
+use log::{trace, debug, info, warn, error};
+
+// PUT    /objects                  store the request body as it arrives, 201 with its uuid
+// GET    /objects/{uuid}           200 with the object's data, or 206 with the part asked for by
+//                                  a single `Range: bytes=...` header
+// HEAD   /objects/{uuid}           200 with the object's metadata headers and no body
+// PUT    /objects/{uuid}/metadata  replace the object's metadata, 204
+// DELETE /objects/{uuid}           204
+//
+// metadata travels as headers: Content-Type, and an `X-Rustor-Meta-{key}` header per tag. GET
+// and HEAD also send the object's size and unix timestamps as X-Rustor-Size, X-Rustor-Created
+// and X-Rustor-Modified
+//
+// object data is streamed from the store as the response is sent rather than buffered. as with
+// SharedObjectStore::get_stream, puts and deletes wait until the response is finished
+//
+// failures are answered with the status of the error, e.g. 404 for an object that doesn't exist
+// or 507 when the store is full, and the error message as the body
+
+const META_PREFIX: &str = "x-rustor-meta-";
+
+/// what a reply sends after its headers
+enum Body<'a> {
+    Data(Vec<u8>),
+    /// the rest of an object, read from the store as the response is sent rather than buffered
+    Object(ObjectReader<'a>),
+}
+
+impl fmt::Debug for Body<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Body::Data(data) => write!(f, "Data({} bytes)", data.len()),
+            Body::Object(reader) => write!(f, "Object({} bytes of {})", reader.remaining(), reader.key().uuid),
+        }
+    }
+}
+
+/// a response to a request
+#[derive(Debug)]
+struct Reply<'a> {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Body<'a>,
+}
+
+impl<'a> Reply<'a> {
+    fn new(status: u16, body: Vec<u8>) -> Self {
+        Self { status, headers: Vec::new(), body: Body::Data(body) }
+    }
+
+    /// stream what's left of `reader`
+    fn object(status: u16, reader: ObjectReader<'a>) -> Self {
+        Self { status, headers: Vec::new(), body: Body::Object(reader) }
+    }
+
+    fn header(mut self, name: impl Into<String>, value: String) -> Self {
+        self.headers.push((name.into(), value));
+        self
+    }
+
+    /// the metadata headers of `key`
+    fn metadata(mut self, key: &ObjKey) -> Self {
+        let metadata = &key.metadata;
+        if let Some(content_type) = &metadata.content_type {
+            self = self.header("Content-Type", content_type.clone());
+        }
+        self = self.header("X-Rustor-Size", key.size.to_string())
+            .header("X-Rustor-Created", metadata.created.to_string())
+            .header("X-Rustor-Modified", metadata.modified.to_string());
+        for (k, v) in metadata.tags.iter() {
+            self = self.header(format!("X-Rustor-Meta-{}", k), v.clone());
+        }
+        self
+    }
+}
+
 fn main() {
-    println!("Hello, world!");
+    env_logger::init();
+
+    if let Err(e) = run() {
+        eprintln!("rustord: {}", e);
+        std::process::exit(e.exit_code());
+    }
+}
+
+fn run() -> RResult<()> {
+    let yaml = load_yaml!("cli.yml");
+    let matches = App::from_yaml(yaml).get_matches();
+
+    let keystore_file = matches.value_of("keystore").unwrap_or("keys.json");
+    let objstore_file = matches.value_of("objstore").unwrap_or("data.bin");
+    let intents_file = matches.value_of("intents").unwrap_or("intents.log");
+    let listen = matches.value_of("listen").unwrap_or("127.0.0.1:8080");
+    let size: u64 = parse(matches.value_of("size").unwrap_or("1048576"), "size")?;
+    let threads: usize = parse(matches.value_of("threads").unwrap_or("4"), "threads")?;
+    let inline_threshold: u64 = parse(matches.value_of("inline_threshold").unwrap_or("256"), "inline threshold")?;
+
+    let snapshots = matches.value_of("snapshots").map(PathBuf::from);
+    let store = open(PathBuf::from(objstore_file), size, PathBuf::from(keystore_file), PathBuf::from(intents_file), snapshots)?;
+    let store = Arc::new(store.with_inline_threshold(inline_threshold));
+    let server = Arc::new(Server::http(listen)
+        .map_err(|e| RustorError::Config(format!("could not listen on {}: {}", listen, e)))?);
+    info!("serving {} on {} with {} threads", objstore_file, listen, threads);
+
+    let workers: Vec<_> = (0..threads.max(1)).map(|_| {
+        let store = Arc::clone(&store);
+        let server = Arc::clone(&server);
+        thread::spawn(move || {
+            for request in server.incoming_requests() {
+                serve(&store, request);
+            }
+        })
+    }).collect();
+    for worker in workers {
+        let _ = worker.join();
+    }
+    Ok(())
+}
+
+fn parse<T: std::str::FromStr>(value: &str, what: &str) -> RResult<T> {
+    value.parse().map_err(|_| RustorError::Config(format!("invalid {}: {:?}", what, value)))
+}
+
+/// open the store, rebuilding its free list from the keystore and any snapshots, and roll back the
+/// puts its intent log shows were interrupted
+fn open(objstore: PathBuf, size: u64, keystore: PathBuf, intents: PathBuf, snapshots: Option<PathBuf>) -> RResult<SharedObjectStore> {
+    let bs = SingleDeviceBlockStore::new(objstore, size)?;
+    let ks: JsonKeystore<ObjKey> = JsonKeystore::new(keystore);
+    let mut fl = VecFreeList::new(size / BS4K as u64);
+    fl.from_keys(ks.get_objects().values())?;
+    let snapshots = snapshots.map(JsonKeystore::<Snapshot>::new);
+    if let Some(snapshots) = snapshots.as_ref() {
+        let mut held = snapshot::held(snapshots)?;
+        for key in ks.get_objects().values() {
+            for shard in key.manifest.shards.iter() {
+                held.remove(shard);
+            }
+        }
+        for shard in held.iter() {
+            fl.take(shard.span, shard.lba)?;
+        }
+    }
+
+    let mut store = SharedObjectStore::new(bs, fl, KeyGen {}, ks)
+        .with_intent_log(IntentLog::open(intents)?);
+    if let Some(snapshots) = snapshots {
+        store = store.with_snapshots(snapshots);
+    }
+    let recovered = store.recover()?;
+    if recovered > 0 {
+        info!("rolled back {} interrupted puts", recovered);
+    }
+    Ok(store)
+}
+
+fn serve(store: &SharedObjectStore, mut request: Request) {
+    let method = request.method().clone();
+    let url = request.url().to_string();
+    let size_hint = request.body_length().unwrap_or(0) as u64;
+    let headers: Vec<(String, String)> = request.headers().iter()
+        .map(|h| (h.field.as_str().as_str().to_string(), h.value.as_str().to_string()))
+        .collect();
+    let reply = route(store, &method, &url, &headers, request.as_reader(), size_hint)
+        .unwrap_or_else(|e| {
+            warn!("{} {}: {}", &method, &url, e);
+            error_reply(&e)
+        });
+
+    debug!("{} {} -> {}", &method, &url, reply.status);
+    let status = StatusCode(reply.status);
+    let headers = reply.headers.iter()
+        .filter_map(|(name, value)| Header::from_bytes(name.as_bytes(), value.as_bytes()).ok())
+        .collect();
+    let sent = match reply.body {
+        Body::Data(data) => {
+            let len = data.len();
+            request.respond(Response::new(status, headers, Cursor::new(data), Some(len), None))
+        }
+        Body::Object(reader) => {
+            // the length is known up front, so send it rather than chunking large objects
+            let len = reader.remaining() as usize;
+            request.respond(Response::new(status, headers, reader, Some(len), None)
+                .with_chunked_threshold(usize::MAX))
+        }
+    };
+    if let Err(e) = sent {
+        warn!("could not respond to {} {}: {}", &method, &url, e);
+    }
+}
+
+/// the reply to a request that failed with `e`: its status, with the message as the body
+fn error_reply(e: &RustorError) -> Reply<'static> {
+    let reply = Reply::new(e.http_status(), e.to_string().into_bytes())
+        .header("Content-Type", "text/plain; charset=UTF-8".to_string());
+    match e {
+        // the size of the object, so the client can ask again, as RFC 7233 has it
+        RustorError::RangeNotSatisfiable { size, .. } => reply.header("Content-Range", format!("bytes */{}", size)),
+        _ => reply,
+    }
+}
+
+/// the part of an object a Range header asks for
+#[derive(Debug, PartialEq)]
+enum ByteRange {
+    /// `len` bytes from `offset`, all within the object
+    Bytes { offset: u64, len: u64 },
+    /// not one byte of the object, e.g. a range starting past its end, or any range of an empty
+    /// object
+    Unsatisfiable { offset: u64 },
+}
+
+/// The part asked for by a Range header on an object of `size` bytes: `bytes=a-b`, `bytes=a-`
+/// or `bytes=-n` for the last n bytes, cut short at the end of the object. Anything else,
+/// including multiple ranges, is ignored and the whole object served, as RFC 7233 allows
+fn parse_range(range: &str, size: u64) -> Option<ByteRange> {
+    let spec = range.trim().strip_prefix("bytes=")?;
+    if spec.contains(',') {
+        return None;
+    }
+    let (start, end) = spec.split_once('-')?;
+    let (offset, last) = match (start.trim(), end.trim()) {
+        ("", suffix) => {
+            let n: u64 = suffix.parse().ok()?;
+            if n == 0 {
+                return Some(ByteRange::Unsatisfiable { offset: size });
+            }
+            (size.saturating_sub(n), u64::MAX)
+        }
+        (start, "") => (start.parse().ok()?, u64::MAX),
+        (start, end) => {
+            let (start, end): (u64, u64) = (start.parse().ok()?, end.parse().ok()?);
+            if end < start {
+                return None;
+            }
+            (start, end)
+        }
+    };
+    if offset >= size {
+        return Some(ByteRange::Unsatisfiable { offset });
+    }
+    Some(ByteRange::Bytes { offset, len: last.min(size - 1) - offset + 1 })
+}
+
+/// the value of the request header `name`
+fn header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers.iter().find(|(k, _)| k.eq_ignore_ascii_case(name)).map(|(_, v)| v.as_str())
+}
+
+/// the metadata sent with a request, or None if it sent none
+fn request_metadata(headers: &[(String, String)]) -> Option<ObjectMetadata> {
+    let mut metadata = ObjectMetadata {
+        content_type: header(headers, "Content-Type").map(String::from),
+        ..Default::default()
+    };
+    for (k, v) in headers.iter() {
+        let k = k.to_ascii_lowercase();
+        if let Some(tag) = k.strip_prefix(META_PREFIX) {
+            metadata = metadata.tag(tag, v);
+        }
+    }
+    if metadata == ObjectMetadata::default() { None } else { Some(metadata) }
+}
+
+/// handle one request
+fn route<'a>(store: &'a SharedObjectStore, method: &Method, url: &str, headers: &[(String, String)],
+    body: &mut dyn Read, size_hint: u64) -> RResult<Reply<'a>>
+{
+    let path: Vec<&str> = url.trim_matches('/').split('/').collect();
+    match (method, &path[..]) {
+        (Method::Put, ["objects"]) | (Method::Post, ["objects"]) => {
+            let uuid = match request_metadata(headers) {
+                Some(metadata) => store.put_stream_with_metadata(body, size_hint, metadata)?,
+                None => store.put_stream(body, size_hint)?,
+            };
+            Ok(Reply::new(201, uuid.to_string().into_bytes()))
+        }
+        (Method::Head, ["objects", id]) => {
+            let uuid = Uuid::parse_str(id)?;
+            let key = store.head(uuid)?.ok_or(RustorError::NotFound { uuid })?;
+            Ok(Reply::new(200, Vec::new()).metadata(&key))
+        }
+        (Method::Put, ["objects", id, "metadata"]) => {
+            let uuid = Uuid::parse_str(id)?;
+            let metadata = request_metadata(headers).unwrap_or_default();
+            store.update_metadata(uuid, metadata)?.ok_or(RustorError::NotFound { uuid })?;
+            Ok(Reply::new(204, Vec::new()))
+        }
+        (Method::Get, ["objects", id]) => {
+            let uuid = Uuid::parse_str(id)?;
+            let mut reader = store.get_stream(uuid)?.ok_or(RustorError::NotFound { uuid })?;
+            let key = reader.key().clone();
+            let size = reader.size();
+            match header(headers, "Range").and_then(|r| parse_range(r, size)) {
+                Some(ByteRange::Bytes { offset, len }) => {
+                    reader.set_range(offset, len)?;
+                    Ok(Reply::object(206, reader).metadata(&key)
+                        .header("Content-Range", format!("bytes {}-{}/{}", offset, offset + len - 1, size))
+                        .header("Accept-Ranges", "bytes".to_string()))
+                }
+                Some(ByteRange::Unsatisfiable { offset }) => Err(RustorError::RangeNotSatisfiable { uuid, offset, size }),
+                None => Ok(Reply::object(200, reader).metadata(&key)
+                    .header("Accept-Ranges", "bytes".to_string())),
+            }
+        }
+        (Method::Delete, ["objects", id]) => {
+            let uuid = Uuid::parse_str(id)?;
+            store.delete(uuid)?.ok_or(RustorError::NotFound { uuid })?;
+            Ok(Reply::new(204, Vec::new()))
+        }
+        // GET reports what a collection would free, POST runs one
+        (Method::Get, ["gc"]) | (Method::Post, ["gc"]) => {
+            let report = store.gc(*method == Method::Get)?;
+            let body = format!("marked {}\nreclaimed {}\nareas {}\n", report.marked, report.reclaimed, report.areas.len());
+            Ok(Reply::new(200, body.into_bytes()))
+        }
+        _ => Err(RustorError::InvalidRequest(format!("no route for {} {}", method, url))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+
+    fn path(ext: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("rustor-rustord-routes-{}.{}", std::process::id(), ext))
+    }
+
+    /// the body of `reply`, reading it from the store if it is streamed
+    fn body(reply: Reply) -> Vec<u8> {
+        match reply.body {
+            Body::Data(data) => data,
+            Body::Object(reader) => reader.read_all().unwrap(),
+        }
+    }
+
+    fn get<'a>(store: &'a SharedObjectStore, url: &str, range: Option<&str>) -> RResult<Reply<'a>> {
+        let headers: Vec<(String, String)> = range.map(|r| ("Range".to_string(), r.to_string())).into_iter().collect();
+        route(store, &Method::Get, url, &headers, &mut io::empty(), 0)
+    }
+
+    #[test]
+    fn test_routes() {
+        let _ = std::fs::remove_file(path("json"));
+        let _ = std::fs::remove_file(path("log"));
+        let store = open(path("bin"), 16 * BS4K as u64, path("json"), path("log"), None).unwrap();
+
+        let reply = route(&store, &Method::Put, "/objects", &[], &mut &b"hello"[..], 0).unwrap();
+        assert_eq!(reply.status, 201);
+        let url = format!("/objects/{}", String::from_utf8(body(reply)).unwrap());
+        // object data is streamed rather than read into the reply
+        assert!(matches!(get(&store, &url, None).unwrap().body, Body::Object(_)));
+        assert_eq!(body(get(&store, &url, None).unwrap()), b"hello");
+        assert_eq!(route(&store, &Method::Delete, &url, &[], &mut io::empty(), 0).unwrap().status, 204);
+
+        let status = |result: RResult<Reply>| result.unwrap_err().http_status();
+        assert_eq!(status(get(&store, &url, None)), 404);
+        assert_eq!(status(route(&store, &Method::Delete, &url, &[], &mut io::empty(), 0)), 404);
+        assert_eq!(status(get(&store, "/objects/nope", None)), 400);
+        assert_eq!(status(route(&store, &Method::Patch, "/objects", &[], &mut io::empty(), 0)), 400);
+
+        // a full store
+        let big = vec![1; 17 * BS4K];
+        assert_eq!(status(route(&store, &Method::Put, "/objects", &[], &mut &big[..], 0)), 507);
+
+        let _ = std::fs::remove_file(path("bin"));
+        let _ = std::fs::remove_file(path("json"));
+        let _ = std::fs::remove_file(path("log"));
+    }
+
+    #[test]
+    fn test_range_requests() {
+        let _ = std::fs::remove_file(path("range.json"));
+        let _ = std::fs::remove_file(path("range.log"));
+        let store = open(path("range.bin"), 16 * BS4K as u64, path("range.json"), path("range.log"), None).unwrap();
+        let data: Vec<u8> = (0..3 * BS4K).map(|i| i as u8).collect();
+        let reply = route(&store, &Method::Put, "/objects", &[], &mut &data[..], 0).unwrap();
+        let url = format!("/objects/{}", String::from_utf8(body(reply)).unwrap());
+
+        let reply = get(&store, &url, Some("bytes=4090-4105")).unwrap();
+        assert!(reply.headers.contains(&("Content-Range".to_string(), format!("bytes 4090-4105/{}", data.len()))));
+        assert_eq!((reply.status, body(reply)), (206, data[4090..4106].to_vec()));
+        assert_eq!(body(get(&store, &url, Some("bytes=-5")).unwrap()), &data[data.len() - 5..]);
+        assert_eq!(body(get(&store, &url, Some("bytes=12000-")).unwrap()), &data[12000..]);
+
+        // unsupported or malformed ranges get the whole object
+        assert_eq!(get(&store, &url, Some("bytes=0-1,5-6")).unwrap().status, 200);
+        assert_eq!(body(get(&store, &url, Some("lines=1-2")).unwrap()), data);
+        // ranges holding none of the object are refused, with its size
+        let unsatisfiable = |url: &str, range: &str, size: usize| {
+            let reply = error_reply(&get(&store, url, Some(range)).unwrap_err());
+            assert_eq!(reply.status, 416, "{}", range);
+            assert!(reply.headers.contains(&("Content-Range".to_string(), format!("bytes */{}", size))), "{}", range);
+        };
+        unsatisfiable(&url, &format!("bytes={}-", data.len()), data.len());
+        unsatisfiable(&url, "bytes=-0", data.len());
+        assert_eq!(body(get(&store, &url, Some("bytes=12000-99999")).unwrap()), &data[12000..]);
+
+        let reply = route(&store, &Method::Put, "/objects", &[], &mut io::empty(), 0).unwrap();
+        let empty = format!("/objects/{}", String::from_utf8(body(reply)).unwrap());
+        assert_eq!(body(get(&store, &empty, None).unwrap()), b"");
+        for range in ["bytes=0-", "bytes=0-0", "bytes=-1", "bytes=-0"] {
+            unsatisfiable(&empty, range, 0);
+        }
+
+        let _ = std::fs::remove_file(path("range.bin"));
+        let _ = std::fs::remove_file(path("range.json"));
+        let _ = std::fs::remove_file(path("range.log"));
+    }
+
+    #[test]
+    fn test_metadata_headers() {
+        let _ = std::fs::remove_file(path("meta.json"));
+        let _ = std::fs::remove_file(path("meta.log"));
+        let store = open(path("meta.bin"), 16 * BS4K as u64, path("meta.json"), path("meta.log"), None).unwrap();
+        let headers = vec![
+            ("Content-Type".to_string(), "text/plain".to_string()),
+            ("X-Rustor-Meta-Owner".to_string(), "ops".to_string()),
+        ];
+        let reply = route(&store, &Method::Put, "/objects", &headers, &mut &b"hello"[..], 0).unwrap();
+        let url = format!("/objects/{}", String::from_utf8(body(reply)).unwrap());
+
+        let has = |reply: &Reply, name: &str, value: &str| reply.headers.contains(&(name.to_string(), value.to_string()));
+        let head = route(&store, &Method::Head, &url, &[], &mut io::empty(), 0).unwrap();
+        assert!(matches!(&head.body, Body::Data(data) if data.is_empty()));
+        assert!(has(&head, "Content-Type", "text/plain"));
+        assert!(has(&head, "X-Rustor-Meta-owner", "ops"));
+        assert!(has(&head, "X-Rustor-Size", "5"));
+        assert!(has(&get(&store, &url, None).unwrap(), "Content-Type", "text/plain"));
+
+        let update = vec![("X-Rustor-Meta-Stage".to_string(), "done".to_string())];
+        let meta = format!("{}/metadata", url);
+        assert_eq!(route(&store, &Method::Put, &meta, &update, &mut io::empty(), 0).unwrap().status, 204);
+        let head = route(&store, &Method::Head, &url, &[], &mut io::empty(), 0).unwrap();
+        assert!(has(&head, "X-Rustor-Meta-stage", "done"));
+        assert!(!head.headers.iter().any(|(k, _)| k == "Content-Type"));
+
+        let _ = std::fs::remove_file(path("meta.bin"));
+        let _ = std::fs::remove_file(path("meta.json"));
+        let _ = std::fs::remove_file(path("meta.log"));
+    }
+
+    #[test]
+    fn test_reopen_keeps_blocks() {
+        let _ = std::fs::remove_file(path("reopen.json"));
+        let _ = std::fs::remove_file(path("reopen.log"));
+        let size = 16 * BS4K as u64;
+        let store = open(path("reopen.bin"), size, path("reopen.json"), path("reopen.log"), None).unwrap();
+        let first = store.put(b"first").unwrap();
+        drop(store);
+
+        // the reopened free list has the first object's block taken
+        let store = open(path("reopen.bin"), size, path("reopen.json"), path("reopen.log"), None).unwrap();
+        store.put(b"second").unwrap();
+        assert_eq!(store.get(first).unwrap().unwrap(), b"first");
+        drop(store);
+
+        // a put interrupted before storing its key is rolled back when the store is reopened
+        {
+            let mut intents = IntentLog::open(path("reopen.log")).unwrap();
+            let mut fl = VecFreeList::new(size / BS4K as u64);
+            intents.begin(Uuid::new_v4(), &fl.allocate(BS4K as u64).unwrap()).unwrap();
+        }
+        let store = open(path("reopen.bin"), size, path("reopen.json"), path("reopen.log"), None).unwrap();
+        drop(store);
+        assert_eq!(IntentLog::open(path("reopen.log")).unwrap().pending().count(), 0);
+
+        let _ = std::fs::remove_file(path("reopen.bin"));
+        let _ = std::fs::remove_file(path("reopen.json"));
+        let _ = std::fs::remove_file(path("reopen.log"));
+    }
 }